@@ -1,36 +1,121 @@
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter};
 use std::str;
+use std::time::Duration;
 
-use serde::Serialize;
-
+use reqwest::redirect::Policy;
 use reqwest::Client;
 
 const USER_AGENT_KEY: &str = "User-Agent";
 const UA: &str = "PDFiller";
+const CONTENT_TYPE_KEY: &str = "Content-Type";
+/// Remote files must be fully downloaded within this delay
+const GET_TIMEOUT: Duration = Duration::from_secs(30);
+/// Callback endpoints must answer within this delay, otherwise the attempt fails
+const POST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Timestamp authorities must answer within this delay, the compile waits for them
+const POST_BYTES_TIMEOUT: Duration = Duration::from_secs(30);
 
-pub async fn get<S: AsRef<str>>(uri: S) -> Option<Vec<u8>> {
-    let client_request = Client::default().get(uri.as_ref());
-    let response = client_request.header(USER_AGENT_KEY, UA).send().await;
+#[derive(Debug)]
+pub enum GetError {
+    Request(reqwest::Error),
+    TooLarge { max: usize },
+}
 
-    match response {
-        Ok(response) => match response.bytes().await {
-            Ok(body) => Some(body.to_vec()),
-            _ => None,
-        },
-        _ => None,
+impl Display for GetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GetError::Request(e) => write!(f, "Request error: {}", e),
+            GetError::TooLarge { max } => {
+                write!(f, "The response is larger than the {} bytes accepted", max)
+            }
+        }
+    }
+}
+
+impl StdError for GetError {}
+
+/// Downloads the body chunk by chunk, giving up as soon as it grows past `max_size`.
+pub async fn get<S: AsRef<str>>(uri: S, max_size: Option<usize>) -> Result<Vec<u8>, GetError> {
+    let client = Client::builder()
+        .timeout(GET_TIMEOUT)
+        .build()
+        .map_err(GetError::Request)?;
+    let mut response = client
+        .get(uri.as_ref())
+        .header(USER_AGENT_KEY, UA)
+        .send()
+        .await
+        .map_err(GetError::Request)?;
+
+    if let (Some(max), Some(length)) = (max_size, response.content_length()) {
+        if length > max as u64 {
+            return Err(GetError::TooLarge { max });
+        }
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(GetError::Request)? {
+        if let Some(max) = max_size {
+            if body.len() + chunk.len() > max {
+                return Err(GetError::TooLarge { max });
+            }
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Posts the body as is, the response body is returned only for successful responses.
+pub async fn post<S: AsRef<str>>(
+    uri: S,
+    content_type: &str,
+    body: Vec<u8>,
+    headers: &[(&str, String)],
+) -> Result<Vec<u8>, String> {
+    // Redirects could lead the callbacks to internal hosts
+    let client = Client::builder()
+        .timeout(POST_TIMEOUT)
+        .redirect(Policy::none())
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut client_request = client
+        .post(uri.as_ref())
+        .header(USER_AGENT_KEY, UA)
+        .header(CONTENT_TYPE_KEY, content_type)
+        .body(body);
+    for (key, value) in headers {
+        client_request = client_request.header(*key, value.as_str());
+    }
+
+    match client_request.send().await {
+        Ok(response) if response.status().is_success() => response
+            .bytes()
+            .await
+            .map(|body| body.to_vec())
+            .map_err(|e| e.to_string()),
+        Ok(response) => Err(format!("the response status is {}", response.status())),
+        Err(e) => Err(e.to_string()),
     }
 }
 
-#[allow(dead_code)]
-pub async fn post<S: AsRef<str>, D: Serialize>(uri: S, request: D) -> Option<Vec<u8>> {
-    let client_request = Client::default().post(uri.as_ref());
-    let response = client_request
+pub async fn post_bytes<S: AsRef<str>>(
+    uri: S,
+    content_type: &str,
+    body: Vec<u8>,
+) -> Option<Vec<u8>> {
+    let client = Client::builder().timeout(POST_BYTES_TIMEOUT).build().ok()?;
+    let response = client
+        .post(uri.as_ref())
         .header(USER_AGENT_KEY, UA)
-        .json(&request)
+        .header(CONTENT_TYPE_KEY, content_type)
+        .body(body)
         .send()
         .await;
 
     match response {
-        Ok(response) => match response.bytes().await {
+        Ok(response) if response.status().is_success() => match response.bytes().await {
             Ok(body) => Some(body.to_vec()),
             _ => None,
         },
@@ -9,6 +9,12 @@ pub struct Config {
     pub server: ServerConfig,
     pub mongo: MongoConfig,
     pub sentry: Option<SentryConfig>,
+    pub signature: Option<SignatureConfig>,
+    pub render: Option<RenderConfig>,
+    pub jobs: Option<JobsConfig>,
+    pub webhooks: Option<WebhooksConfig>,
+    pub batch: Option<BatchConfig>,
+    pub upload: Option<UploadConfig>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -42,6 +48,49 @@ pub struct SentryConfig {
     pub dsn: String,
 }
 
+#[derive(Clone, Deserialize)]
+pub struct SignatureConfig {
+    pub certificate: Option<String>,
+    pub password: Option<String>,
+    pub tsa_url: Option<String>,
+    pub trust_store: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RenderConfig {
+    pub command: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct JobsConfig {
+    pub workers: Option<usize>,
+    pub queue_size: Option<usize>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct WebhooksConfig {
+    pub secret: Option<String>,
+    pub max_attempts: Option<u32>,
+    pub retry_delay: Option<u64>,
+    pub allow_private_hosts: Option<bool>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct BatchConfig {
+    pub concurrency: Option<usize>,
+    pub max_rows: Option<usize>,
+    pub max_body_size: Option<usize>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct UploadConfig {
+    pub max_size: Option<usize>,
+    pub max_pages: Option<usize>,
+    pub reject_javascript: Option<bool>,
+    pub reject_launch_actions: Option<bool>,
+    pub reject_embedded_files: Option<bool>,
+}
+
 impl Config {
     pub fn new<S: AsRef<str>>(path: S) -> Self {
         match crystalsoft_utils::read_file_string(path.as_ref()) {
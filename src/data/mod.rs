@@ -1,56 +1,333 @@
+use std::collections::HashMap;
+
 use async_std::sync::Arc;
+use bson::{doc, oid::ObjectId};
+use chrono::Utc;
+use mongodb::bson::Document as MongoDocument;
 
+use crate::config::{BatchConfig, RenderConfig, SignatureConfig, UploadConfig, WebhooksConfig};
 use crate::file::FileProvider;
+use crate::mongo::models::blob::Blob;
+use crate::mongo::models::certificate::Certificate;
+use crate::mongo::models::compilation::Compilation;
+use crate::mongo::models::dead_letter::DeadLetter;
 use crate::mongo::models::document::Document;
+use crate::mongo::models::job::{Job, JobStatus};
+use crate::mongo::models::mapping::MappingProfile;
+use crate::mongo::models::webhook::Webhook;
+use crate::mongo::models::Model;
 use crate::mongo::wrapper::MongoWrapper;
-use crate::mongo::Error;
+use crate::mongo::{Error, FindQuery};
 
 pub type DataResult<T> = Result<T, Error>;
 
+/// Uploads racing for the same template version before giving up
+const VERSION_ATTEMPTS: usize = 5;
+
 #[derive(Clone)]
 pub struct Data {
     pub file: Arc<Box<dyn FileProvider>>,
+    pub signature: Option<SignatureConfig>,
+    pub render: Option<RenderConfig>,
+    pub webhooks: Option<WebhooksConfig>,
+    pub batch: Option<BatchConfig>,
+    pub upload: Option<UploadConfig>,
     mongo: MongoWrapper,
 }
 
 impl Data {
-    pub fn new(file: Box<dyn FileProvider>, mongo: MongoWrapper) -> Self {
+    pub fn new(
+        file: Box<dyn FileProvider>,
+        mongo: MongoWrapper,
+        signature: Option<SignatureConfig>,
+        render: Option<RenderConfig>,
+        webhooks: Option<WebhooksConfig>,
+        batch: Option<BatchConfig>,
+        upload: Option<UploadConfig>,
+    ) -> Self {
         Data {
             file: Arc::new(file),
+            signature,
+            render,
+            webhooks,
+            batch,
+            upload,
             mongo,
         }
     }
 
-    pub async fn get_all_documents(&self) -> Option<Vec<Document>> {
-        if let Some(documents) = self.mongo.get_all::<Document, _>("date").await {
-            if !documents.is_empty() {
-                Some(documents)
-            } else {
-                None
-            }
+    /// A page of the documents matching the query.
+    pub async fn find_documents(&self, query: FindQuery) -> Option<Vec<Document>> {
+        self.mongo.find::<Document>(query).await
+    }
+
+    pub async fn create_indexes(&self) -> DataResult<()> {
+        self.mongo.create_indexes::<Document>().await?;
+        self.mongo.create_indexes::<Blob>().await
+    }
+
+    /// The latest version of every template of the token, or the version pinned for its name. The
+    /// templates keep the order of their first upload.
+    pub async fn get_templates_by_token<S: AsRef<str>>(
+        &self,
+        token: S,
+        versions: &HashMap<String, u32>,
+    ) -> Option<Vec<Document>> {
+        let branches = versions
+            .iter()
+            .map(|(name, version)| {
+                doc! {
+                    "case": { "$eq": ["$template", name] },
+                    "then": { "$eq": [{ "$ifNull": ["$version", 1] }, *version as i32] },
+                }
+            })
+            .collect::<Vec<_>>();
+        let selected = if branches.is_empty() {
+            doc! { "$literal": true }
         } else {
-            None
-        }
+            doc! { "$switch": { "branches": branches, "default": true } }
+        };
+
+        let pipeline = vec![
+            doc! { "$match": { "token": token.as_ref() } },
+            // Documents uploaded before templates were named are their own template
+            doc! { "$addFields": { "template": { "$ifNull": ["$name", "$file"] } } },
+            doc! { "$addFields": { "selected": selected } },
+            doc! { "$sort": { "selected": -1, "version": -1 } },
+            doc! {
+                "$group": {
+                    "_id": "$template",
+                    "document": { "$first": "$$ROOT" },
+                    "date": { "$min": "$date" },
+                }
+            },
+            doc! { "$match": { "document.selected": true } },
+            doc! { "$sort": { "date": 1, "_id": 1 } },
+            doc! { "$replaceRoot": { "newRoot": "$document" } },
+            doc! { "$project": { "template": 0, "selected": 0 } },
+        ];
+
+        self.mongo
+            .aggregate::<Document>(pipeline)
+            .await
+            .filter(|documents| !documents.is_empty())
     }
 
-    pub async fn get_documents_by_token<S: AsRef<str>>(&self, value: S) -> Option<Vec<Document>> {
-        if let Some(documents) = self
-            .mongo
-            .get_all_by::<Document, _>("token", value.as_ref(), "date")
+    /// Every version of the template, the oldest first.
+    pub async fn get_template_versions<S: AsRef<str>>(
+        &self,
+        token: S,
+        name: S,
+    ) -> Option<Vec<Document>> {
+        self.mongo
+            .find::<Document>(FindQuery {
+                filter: Some(Self::template_filter(token.as_ref(), name.as_ref())),
+                sort: Some(doc! { "version": 1 }),
+                ..Default::default()
+            })
             .await
-        {
-            if !documents.is_empty() {
-                Some(documents)
-            } else {
-                None
+            .filter(|versions| !versions.is_empty())
+    }
+
+    async fn get_latest_template_version(&self, token: &str, name: &str) -> Option<u32> {
+        self.mongo
+            .find::<Document>(FindQuery {
+                filter: Some(Self::template_filter(token, name)),
+                sort: Some(doc! { "version": -1 }),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .await
+            .and_then(|mut versions| versions.pop())
+            .map(|document| document.template_version())
+    }
+
+    /// Documents uploaded before templates were named are named after their file.
+    fn template_filter(token: &str, name: &str) -> MongoDocument {
+        doc! {
+            "token": token,
+            "$or": [
+                { "name": name },
+                { "name": { "$exists": false }, "file": name },
+            ],
+        }
+    }
+
+    /// Saves the document as the next version of its template. Concurrent uploads may pick the
+    /// same version: the unique index refuses all but one, the others pick the following one.
+    pub async fn create_template_version(&self, mut document: Document) -> DataResult<Document> {
+        let name = document.template_name();
+        let mut attempts = 1;
+        loop {
+            let version = self
+                .get_latest_template_version(&document.token, &name)
+                .await
+                .map_or(1, |version| version + 1);
+            document.version = Some(version);
+
+            match self.mongo.create::<Document>(document.clone()).await {
+                Ok(_) => return Ok(document),
+                Err(e) if e.is_duplicate_key() && attempts < VERSION_ATTEMPTS => attempts += 1,
+                Err(e) => return Err(e),
             }
-        } else {
-            None
         }
     }
 
-    pub async fn create_document(&self, document: Document) -> DataResult<()> {
-        self.mongo.create::<Document>(document).await?;
+    pub async fn get_document<S: AsRef<str>>(&self, id: S) -> Option<Document> {
+        self.mongo.get_by_id::<Document, _>(id).await
+    }
+
+    pub async fn delete_document<S: AsRef<str>>(&self, id: S) -> DataResult<()> {
+        self.mongo.delete::<Document, _>(id).await
+    }
+
+    pub async fn get_blob_by_file<S: AsRef<str>>(&self, file: S) -> Option<Blob> {
+        self.mongo
+            .get_all_by::<Blob, _>("file", file.as_ref(), "date")
+            .await
+            .and_then(|mut blobs| blobs.pop())
+    }
+
+    /// Adds a reference to the blob with the content, created with `file` when there's none.
+    pub async fn reference_blob(&self, sha256: &str, file: &str) -> DataResult<Option<Blob>> {
+        let mut blob = Blob::new(sha256.to_string(), file.to_string()).to_document();
+        // New blobs are counted by the increment too
+        blob.remove("references");
+
+        self.mongo
+            .upsert_where::<Blob>(
+                doc! { "sha256": sha256 },
+                doc! { "$inc": { "references": 1 }, "$setOnInsert": blob },
+            )
+            .await
+    }
+
+    pub async fn release_blob<S: AsRef<str>>(&self, id: S) -> DataResult<Option<Blob>> {
+        let id = ObjectId::with_string(id.as_ref()).map_err(Error::InvalidId)?;
+
+        self.mongo
+            .update_where::<Blob>(
+                doc! { "_id": id, "references": { "$gt": 0 } },
+                doc! { "$inc": { "references": -1 } },
+            )
+            .await
+    }
+
+    /// Deletes the blob when it has no references left, returning whether it was.
+    pub async fn delete_unreferenced_blob<S: AsRef<str>>(&self, id: S) -> DataResult<bool> {
+        let id = ObjectId::with_string(id.as_ref()).map_err(Error::InvalidId)?;
+
+        self.mongo
+            .delete_where::<Blob>(doc! { "_id": id, "references": { "$lte": 0 } })
+            .await
+    }
+
+    pub async fn get_certificate_by_token<S: AsRef<str>>(&self, value: S) -> Option<Certificate> {
+        self.mongo
+            .get_all_by::<Certificate, _>("token", value.as_ref(), "date")
+            .await
+            .and_then(|mut certificates| certificates.pop())
+    }
+
+    pub async fn create_certificate(&self, certificate: Certificate) -> DataResult<()> {
+        self.mongo.create::<Certificate>(certificate).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_job<S: AsRef<str>>(&self, id: S) -> Option<Job> {
+        self.mongo.get_by_id::<Job, _>(id).await
+    }
+
+    pub async fn create_job(&self, job: Job) -> DataResult<()> {
+        self.mongo.create::<Job>(job).await?;
+
+        Ok(())
+    }
+
+    pub async fn update_job(&self, job: Job) -> DataResult<()> {
+        self.mongo
+            .update::<Job, _>(job.id().to_owned(), job)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Fails the jobs left queued or running by a previous run, the queue only lives in memory.
+    pub async fn fail_stale_jobs(&self) -> DataResult<i64> {
+        let count = self
+            .mongo
+            .update_many::<Job>(
+                doc! {
+                    "status": { "$in": [JobStatus::Queued.as_str(), JobStatus::Running.as_str()] },
+                },
+                doc! {
+                    "$set": {
+                        "status": JobStatus::Failed.as_str(),
+                        "error": "The server restarted before the job ended",
+                        "updated": Utc::now(),
+                    },
+                },
+            )
+            .await?;
+
+        Ok(count)
+    }
+
+    pub async fn get_compilation<S: AsRef<str>>(&self, id: S) -> Option<Compilation> {
+        self.mongo.get_by_id::<Compilation, _>(id).await
+    }
+
+    pub async fn create_compilation(&self, compilation: Compilation) -> DataResult<()> {
+        self.mongo.create::<Compilation>(compilation).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_webhook_by_token<S: AsRef<str>>(&self, value: S) -> Option<Webhook> {
+        self.mongo
+            .get_all_by::<Webhook, _>("token", value.as_ref(), "date")
+            .await
+            .and_then(|mut webhooks| webhooks.pop())
+    }
+
+    pub async fn create_webhook(&self, webhook: Webhook) -> DataResult<()> {
+        self.mongo.create::<Webhook>(webhook).await?;
+
+        Ok(())
+    }
+
+    pub async fn create_dead_letter(&self, dead_letter: DeadLetter) -> DataResult<()> {
+        self.mongo.create::<DeadLetter>(dead_letter).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_mapping_profiles<S: AsRef<str>>(
+        &self,
+        token: S,
+    ) -> Option<Vec<MappingProfile>> {
+        self.mongo
+            .get_all_by::<MappingProfile, _>("token", token.as_ref(), "date")
+            .await
+    }
+
+    /// The latest profile of the token with the name.
+    pub async fn get_mapping_profile<S: AsRef<str>>(
+        &self,
+        token: S,
+        name: S,
+    ) -> Option<MappingProfile> {
+        self.get_mapping_profiles(token).await.and_then(|profiles| {
+            profiles
+                .into_iter()
+                .rev()
+                .find(|profile| profile.name == name.as_ref())
+        })
+    }
+
+    pub async fn create_mapping_profile(&self, profile: MappingProfile) -> DataResult<()> {
+        self.mongo.create::<MappingProfile>(profile).await?;
 
         Ok(())
     }
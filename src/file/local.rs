@@ -45,6 +45,15 @@ impl FileProvider for Local {
         }
     }
 
+    async fn delete(&self, file_path: &str) -> FileResult<()> {
+        let file_path: String = file_path.into();
+        match web::block(|| fs::remove_file(file_path)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(FileError::IoError(e)),
+            Err(e) => Err(FileError::BlockingError(e)),
+        }
+    }
+
     fn base_path(&self) -> &str {
         self.config.path.as_str()
     }
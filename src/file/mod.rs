@@ -10,9 +10,8 @@ use async_trait::async_trait;
 use serde::de::StdError;
 use uuid::Uuid;
 
-use crate::client;
-
 pub const PATH_COMPILED: &str = "compiled/";
+pub const PATH_BLOBS: &str = "blobs/";
 
 pub type FileResult<T> = Result<T, FileError>;
 
@@ -51,43 +50,56 @@ pub trait FileProvider: Send + Sync {
         format!(
             "{}{}{}",
             self.base_path(),
-            Uuid::new_v4().to_string(),
+            Uuid::new_v4(),
             sanitize_filename::sanitize(file_name)
         )
     }
 
-    fn generate_compiled_filepath(&self, file_path: &str) -> Option<String> {
-        crystalsoft_utils::get_filename(file_path)
-            .map(|file_name| format!("{}{}{}", self.base_path(), PATH_COMPILED, file_name))
+    /// File shared by the uploads with the same content, named after its SHA-256 behind a random
+    /// prefix: a new copy never overwrites one being saved or deleted.
+    fn generate_blob_filepath(&self, sha256: &str) -> String {
+        format!(
+            "{}{}{}{}.pdf",
+            self.base_path(),
+            PATH_BLOBS,
+            Uuid::new_v4(),
+            sanitize_filename::sanitize(sha256)
+        )
     }
 
-    async fn download_and_save(&self, uri: &str) -> Option<String> {
-        let mut filepath = None;
-        if let Some(pdf) = client::get(uri).await {
-            let remote_file_path = self.generate_filepath("file.pdf");
-            match self.save(remote_file_path.as_str(), pdf).await {
-                Ok(_) => {
-                    filepath = Some(remote_file_path.clone());
-                }
-                Err(FileError::IoError(e)) => {
-                    sentry::capture_error(&e);
-                }
-                Err(FileError::S3Error(e)) => {
-                    sentry::capture_error(&e);
-                }
-                Err(FileError::BlockingError(e)) => {
-                    sentry::capture_error(&e);
-                }
-                _ => {}
-            }
-        }
+    /// Directory of the files compiled by a compilation, every compilation has its own.
+    fn generate_compilation_path(&self, compilation: &str) -> String {
+        format!(
+            "{}{}{}/",
+            self.base_path(),
+            PATH_COMPILED,
+            sanitize_filename::sanitize(compilation)
+        )
+    }
 
-        filepath
+    fn generate_compiled_filepath(&self, file_path: &str, compilation: &str) -> Option<String> {
+        crystalsoft_utils::get_filename(file_path).map(|file_name| {
+            format!(
+                "{}{}",
+                self.generate_compilation_path(compilation),
+                file_name
+            )
+        })
+    }
+
+    /// The template itself, or the file compiled from it by the compilation.
+    fn get_document_filepath(&self, file_path: &str, compilation: Option<&str>) -> Option<String> {
+        match compilation {
+            Some(compilation) => self.generate_compiled_filepath(file_path, compilation),
+            None => Some(file_path.to_owned()),
+        }
     }
 
     async fn load(&self, file_path: &str) -> FileResult<Vec<u8>>;
 
     async fn save(&self, file_path: &str, data: Vec<u8>) -> FileResult<()>;
 
+    async fn delete(&self, file_path: &str) -> FileResult<()>;
+
     fn base_path(&self) -> &str;
 }
@@ -49,6 +49,13 @@ impl FileProvider for S3 {
         }
     }
 
+    async fn delete(&self, file_path: &str) -> FileResult<()> {
+        match self.bucket.delete_object(file_path).await {
+            Ok((_data, _code)) => Ok(()),
+            Err(e) => Err(FileError::S3Error(e)),
+        }
+    }
+
     fn base_path(&self) -> &str {
         self.config.path.as_str()
     }
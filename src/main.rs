@@ -15,7 +15,7 @@ use actix_web::{
 use chrono::Local as ChronoLocal;
 use clap::{App as ClapApp, Arg};
 use env_logger::Env;
-use log::info;
+use log::{info, warn};
 
 use crate::config::Config;
 use crate::data::Data;
@@ -23,6 +23,7 @@ use crate::file::local::Local;
 use crate::file::s3::S3;
 use crate::mongo::wrapper::MongoWrapper;
 use crate::mongo::MongoDB;
+use crate::services::jobs::JobQueue;
 
 const API_VERSION: &str = "v1";
 
@@ -67,10 +68,7 @@ async fn main() -> std::io::Result<()> {
     info!("{}", env!("CARGO_PKG_DESCRIPTION"));
     info!("");
 
-    let config = Config::new(&format!(
-        "{}/config.toml",
-        matches.value_of("path").unwrap()
-    ));
+    let config = Config::new(format!("{}/config.toml", matches.value_of("path").unwrap()));
 
     if let Some(sentry) = config.sentry {
         let _guard = sentry::init(sentry.dsn);
@@ -83,19 +81,48 @@ async fn main() -> std::io::Result<()> {
             Box::new(S3::new(config.service.clone()))
         },
         MongoWrapper::new(MongoDB::new(&config.mongo).await),
+        config.signature.clone(),
+        config.render.clone(),
+        config.webhooks.clone(),
+        config.batch.clone(),
+        config.upload.clone(),
     );
 
+    // Listings work without the indexes, only slower
+    if let Err(e) = data.create_indexes().await {
+        warn!("Error creating the MongoDB indexes: {:#?}", e);
+    }
+
+    // Only image exports need it, PDFs are still served
+    if let Err(e) = services::check_renderer(config.render.as_ref()) {
+        warn!("PNG and JPEG exports are unavailable: {}", e);
+    }
+
+    // Queued jobs are lost on restarts, pollers get a failure instead of waiting forever
+    match data.fail_stale_jobs().await {
+        Ok(count) if count > 0 => warn!("Failed {} compile jobs left by the last run", count),
+        Ok(_) => {}
+        Err(e) => warn!("Error failing the stale compile jobs: {:#?}", e),
+    }
+
+    let jobs = JobQueue::start(data.clone(), config.jobs.as_ref());
+
     info!(
         "Starting PDFIller API server at http://{}:{}...",
         config.server.bind_address, config.server.bind_port
     );
 
+    let batch = config.batch.clone();
     HttpServer::new(move || {
         App::new()
             .data(data.clone())
+            .data(jobs.clone())
             .wrap(NormalizePath::new(TrailingSlash::Trim))
             .wrap(Logger::default())
-            .service(web::scope(&format!("/api/{}", API_VERSION)).configure(services::config))
+            .service(
+                web::scope(&format!("/api/{}", API_VERSION))
+                    .configure(|cfg| services::config(cfg, batch.as_ref())),
+            )
     })
     .bind(format!(
         "{}:{}",
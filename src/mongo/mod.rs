@@ -1,11 +1,14 @@
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 
 use async_std::sync::RwLock;
 use bson::{doc, oid::ObjectId};
 use futures_lite::StreamExt;
 use log::error;
-use mongodb::error::Error as MongoDBError;
-use mongodb::options::{ClientOptions, FindOptions};
+use mongodb::bson::Document as MongoDocument;
+use mongodb::error::{Error as MongoDBError, ErrorKind, WriteFailure};
+use mongodb::options::{ClientOptions, FindOneAndUpdateOptions, FindOptions, ReturnDocument};
 use mongodb::{Client, Collection, Database};
 use simple_cache::{Cache, CacheError};
 
@@ -17,10 +20,50 @@ pub mod wrapper;
 
 type MongoResult<T> = Result<T, Error>;
 
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
 #[derive(Debug)]
 pub enum Error {
     MongoDBError(MongoDBError),
     CacheError(CacheError),
+    InvalidId(bson::oid::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MongoDBError(e) => write!(f, "MongoDB error: {}", e),
+            Error::CacheError(e) => write!(f, "Cache error: {:?}", e),
+            Error::InvalidId(e) => write!(f, "Not a valid id: {}", e),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl Error {
+    /// Whether a unique index refused the write.
+    pub fn is_duplicate_key(&self) -> bool {
+        match self {
+            Error::MongoDBError(e) => matches!(
+                e.kind.as_ref(),
+                ErrorKind::WriteError(WriteFailure::WriteError(error))
+                    if error.code == DUPLICATE_KEY_CODE
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Filter, order and page of the models read, `projection` only suits models reading the
+/// projected fields.
+#[derive(Debug, Clone, Default)]
+pub struct FindQuery {
+    pub filter: Option<MongoDocument>,
+    pub sort: Option<MongoDocument>,
+    pub skip: Option<i64>,
+    pub limit: Option<i64>,
+    pub projection: Option<MongoDocument>,
 }
 
 #[derive(Clone)]
@@ -116,15 +159,20 @@ impl MongoDB {
         }
     }
 
-    #[allow(dead_code)]
     pub async fn update_one<T: 'static + Model>(&self, id: ObjectId, model: T) -> MongoResult<()> {
+        // The id can't be changed, models setting it are updated with the other fields
+        let mut document = model.to_document();
+        document.remove("_id");
+
         self.get_collection(T::name())
             .await
             .update_one(
                 doc! {
                     "_id": id.clone(),
                 },
-                model.to_document(),
+                doc! {
+                    "$set": document,
+                },
                 None,
             )
             .await
@@ -148,21 +196,30 @@ impl MongoDB {
         key_value: Option<(S, S)>,
         sort_by: S,
     ) -> Option<Vec<T>> {
-        let filter = key_value.as_ref().map(|key_value| {
-            doc! {
-                key_value.0.as_ref(): key_value.1.as_ref(),
-            }
-        });
+        self.find::<T>(FindQuery {
+            filter: key_value.map(|key_value| {
+                doc! {
+                    key_value.0.as_ref(): key_value.1.as_ref(),
+                }
+            }),
+            sort: Some(doc! { sort_by.as_ref(): 1 }),
+            ..FindQuery::default()
+        })
+        .await
+    }
+
+    pub async fn find<T: Model>(&self, query: FindQuery) -> Option<Vec<T>> {
+        let options = FindOptions::builder()
+            .sort(query.sort)
+            .skip(query.skip)
+            .limit(query.limit)
+            .projection(query.projection)
+            .build();
 
         match self
             .get_collection(T::name())
             .await
-            .find(
-                filter,
-                FindOptions::builder()
-                    .sort(doc! { sort_by.as_ref(): 1 })
-                    .build(),
-            )
+            .find(query.filter.clone(), options)
             .await
         {
             Ok(mut cursor) => {
@@ -177,12 +234,9 @@ impl MongoDB {
             }
             Err(e) => {
                 error!(
-                    "Error getting keys with pattern {:#?}: {:#?}",
-                    if let Some(ref key_value) = key_value {
-                        format!("{}, {}", key_value.0.as_ref(), key_value.1.as_ref())
-                    } else {
-                        "[empty]".into()
-                    },
+                    "Error getting {} with filter {:#?}: {:#?}",
+                    T::name(),
+                    query.filter.unwrap_or_default(),
                     e
                 );
 
@@ -193,53 +247,231 @@ impl MongoDB {
         }
     }
 
-    #[allow(dead_code)]
-    pub async fn get_one<T: 'static + Model, S: AsRef<str>>(&self, id: ObjectId) -> Option<Arc<T>> {
-        match self.cache.get::<T, _>(&id) {
-            Ok(Some(value)) => value,
-            Ok(None) => {
-                match self
-                    .get_collection(T::name())
-                    .await
-                    .find_one(
-                        doc! {
-                            "_id": id.clone(),
-                        },
-                        None,
-                    )
-                    .await
-                {
-                    Ok(result) => match result {
-                        Some(document) => {
-                            let _ = self.cache.insert::<T>(
-                                id.clone(),
-                                Some(T::from_document(document).unwrap_or_else(|_| T::default())),
-                            );
-                        }
-                        None => {
-                            let _ = self.cache.insert::<T>(id.clone(), None);
-                        }
-                    },
-                    Err(e) => {
-                        error!("Error getting {} with key {}: {:#?}", T::name(), &id, e);
-
-                        sentry::capture_error(&e);
-
-                        let _ = self.cache.insert::<T>(id.clone(), None);
+    pub async fn aggregate<T: Model>(&self, pipeline: Vec<MongoDocument>) -> Option<Vec<T>> {
+        match self
+            .get_collection(T::name())
+            .await
+            .aggregate(pipeline.clone(), None)
+            .await
+        {
+            Ok(mut cursor) => {
+                let mut results = Vec::new();
+                while let Some(document) = cursor.next().await {
+                    if let Ok(document) = document {
+                        results.push(T::from_document(document).unwrap_or_else(|_| T::default()));
                     }
                 }
 
-                if let Ok(Some(value)) = self.cache.get::<T, _>(&id) {
-                    value
-                } else {
-                    None
+                Some(results)
+            }
+            Err(e) => {
+                error!(
+                    "Error aggregating {} with pipeline {:#?}: {:#?}",
+                    T::name(),
+                    pipeline,
+                    e
+                );
+
+                sentry::capture_error(&e);
+
+                None
+            }
+        }
+    }
+
+    /// Creates the indexes of the model, existing ones are left as they are.
+    pub async fn create_indexes<T: Model>(&self) -> MongoResult<()> {
+        let indexes = T::indexes()
+            .into_iter()
+            .map(|keys| (keys, false))
+            .chain(T::unique_indexes().into_iter().map(|keys| (keys, true)))
+            .map(|(keys, unique)| {
+                let name = keys
+                    .iter()
+                    .map(|(key, value)| format!("{}_{}", key, value))
+                    .collect::<Vec<_>>()
+                    .join("_");
+
+                let mut index = doc! {
+                    "name": name,
+                    "unique": unique,
+                };
+                // Documents missing one of the keys aren't constrained
+                if unique {
+                    let partial = keys
+                        .keys()
+                        .map(|key| (key.clone(), doc! { "$exists": true }.into()))
+                        .collect::<MongoDocument>();
+                    index.insert("partialFilterExpression", partial);
                 }
+                index.insert("key", keys);
+
+                index
+            })
+            .collect::<Vec<_>>();
+        if indexes.is_empty() {
+            return Ok(());
+        }
+
+        self.database
+            .read()
+            .await
+            .run_command(
+                doc! {
+                    "createIndexes": T::name(),
+                    "indexes": indexes,
+                },
+                None,
+            )
+            .await
+            .map_err(|e| {
+                error!("Error creating the indexes of {}: {:#?}", T::name(), e);
+
+                sentry::capture_error(&e);
+
+                Error::MongoDBError(e)
+            })?;
+
+        Ok(())
+    }
+
+    /// Reads the model skipping the cache, for models updated by other instances.
+    pub async fn find_one<T: Model>(&self, id: ObjectId) -> Option<T> {
+        match self
+            .get_collection(T::name())
+            .await
+            .find_one(
+                doc! {
+                    "_id": id.clone(),
+                },
+                None,
+            )
+            .await
+        {
+            Ok(result) => {
+                result.map(|document| T::from_document(document).unwrap_or_else(|_| T::default()))
+            }
+            Err(e) => {
+                error!("Error getting {} with key {}: {:#?}", T::name(), &id, e);
+
+                sentry::capture_error(&e);
+
+                None
+            }
+        }
+    }
+
+    /// Applies the update operators to the model matching the filter in a single step, returning
+    /// it updated or `None` when nothing matches.
+    pub async fn update_where<T: Model>(
+        &self,
+        filter: MongoDocument,
+        update: MongoDocument,
+    ) -> MongoResult<Option<T>> {
+        self.find_and_update::<T>(filter, update, false).await
+    }
+
+    /// Updates the model matching the filter, inserting it when none does.
+    pub async fn upsert_where<T: Model>(
+        &self,
+        filter: MongoDocument,
+        update: MongoDocument,
+    ) -> MongoResult<Option<T>> {
+        self.find_and_update::<T>(filter, update, true).await
+    }
+
+    async fn find_and_update<T: Model>(
+        &self,
+        filter: MongoDocument,
+        update: MongoDocument,
+        upsert: bool,
+    ) -> MongoResult<Option<T>> {
+        match self
+            .get_collection(T::name())
+            .await
+            .find_one_and_update(
+                filter.clone(),
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .upsert(upsert)
+                    .build(),
+            )
+            .await
+        {
+            Ok(result) => Ok(result.map(|document| {
+                if let Ok(id) = document.get_object_id("_id") {
+                    let _ = self.cache.remove(id);
+                }
+
+                T::from_document(document).unwrap_or_else(|_| T::default())
+            })),
+            Err(e) => {
+                error!(
+                    "Error updating {} with filter {:#?}: {:#?}",
+                    T::name(),
+                    filter,
+                    e
+                );
+
+                sentry::capture_error(&e);
+
+                Err(Error::MongoDBError(e))
             }
-            Err(_) => None,
         }
     }
 
-    #[allow(dead_code)]
+    /// Updates every model matching the filter, returning how many were. Cached models aren't
+    /// refreshed, only use it before they are read.
+    pub async fn update_many<T: Model>(
+        &self,
+        filter: MongoDocument,
+        update: MongoDocument,
+    ) -> MongoResult<i64> {
+        let result = self
+            .get_collection(T::name())
+            .await
+            .update_many(filter.clone(), update, None)
+            .await
+            .map_err(|e| {
+                error!(
+                    "Error updating {} with filter {:#?}: {:#?}",
+                    T::name(),
+                    filter,
+                    e
+                );
+
+                sentry::capture_error(&e);
+
+                Error::MongoDBError(e)
+            })?;
+
+        Ok(result.modified_count)
+    }
+
+    /// Deletes the model matching the filter, returning whether one was.
+    pub async fn delete_where<T: Model>(&self, filter: MongoDocument) -> MongoResult<bool> {
+        let result = self
+            .get_collection(T::name())
+            .await
+            .delete_one(filter.clone(), None)
+            .await
+            .map_err(|e| {
+                error!(
+                    "Error deleting {} with filter {:#?}: {:#?}",
+                    T::name(),
+                    filter,
+                    e
+                );
+
+                sentry::capture_error(&e);
+
+                Error::MongoDBError(e)
+            })?;
+
+        Ok(result.deleted_count > 0)
+    }
+
     pub async fn delete_one<T: Model>(&self, id: ObjectId) -> MongoResult<()> {
         self.get_collection(T::name())
             .await
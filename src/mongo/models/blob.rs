@@ -0,0 +1,87 @@
+use bson::doc;
+use bson::document::ValueAccessError;
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document as MongoDocument;
+use serde::{Deserialize, Serialize};
+use simple_cache::CacheItem;
+
+use crate::mongo::models::Model;
+
+/// File stored once for every upload with the same content, removed when no document references
+/// it anymore.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Blob {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    pub sha256: String,
+    pub file: String,
+    /// Documents pointing at the file
+    pub references: i32,
+    pub date: DateTime<Utc>,
+}
+
+impl Blob {
+    pub fn new(sha256: String, file: String) -> Self {
+        Self {
+            id: None,
+            sha256,
+            file,
+            references: 1,
+            date: Utc::now(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or_default()
+    }
+}
+
+impl CacheItem for Blob {}
+
+impl Model for Blob {
+    fn name() -> &'static str {
+        "blob"
+    }
+
+    fn default() -> Self {
+        Self {
+            id: None,
+            sha256: "".into(),
+            file: "".into(),
+            references: 0,
+            date: Utc::now(),
+        }
+    }
+
+    fn indexes() -> Vec<MongoDocument> {
+        vec![doc! { "file": 1 }]
+    }
+
+    /// A single blob per content, uploads add references to it
+    fn unique_indexes() -> Vec<MongoDocument> {
+        vec![doc! { "sha256": 1 }]
+    }
+
+    fn debug(&self) -> String {
+        format!("{:#?}", self)
+    }
+
+    fn to_document(&self) -> MongoDocument {
+        doc! {
+            "sha256": self.sha256.clone(),
+            "file": self.file.clone(),
+            "references": self.references,
+            "date": self.date,
+        }
+    }
+
+    fn from_document(document: MongoDocument) -> Result<Self, ValueAccessError> {
+        Ok(Self {
+            id: Some(document.get_object_id("_id")?.to_hex()),
+            sha256: document.get_str("sha256")?.to_owned(),
+            file: document.get_str("file")?.to_owned(),
+            references: document.get_i32("references")?,
+            date: document.get_datetime("date")?.to_owned(),
+        })
+    }
+}
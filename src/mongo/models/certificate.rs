@@ -0,0 +1,72 @@
+use bson::doc;
+use bson::document::ValueAccessError;
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document as MongoDocument;
+use serde::{Deserialize, Serialize};
+use simple_cache::CacheItem;
+
+use crate::mongo::models::Model;
+
+/// PKCS#12 certificate used to sign the documents of a token, the password isn't stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Certificate {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    pub token: String,
+    pub file: String,
+    pub subject: String,
+    pub date: DateTime<Utc>,
+}
+
+impl Certificate {
+    pub fn new(token: String, file: String, subject: String) -> Self {
+        Self {
+            id: None,
+            token,
+            file,
+            subject,
+            date: Utc::now(),
+        }
+    }
+}
+
+impl CacheItem for Certificate {}
+
+impl Model for Certificate {
+    fn name() -> &'static str {
+        "certificate"
+    }
+
+    fn default() -> Self {
+        Self {
+            id: None,
+            token: "".into(),
+            file: "".into(),
+            subject: "".into(),
+            date: Utc::now(),
+        }
+    }
+
+    fn debug(&self) -> String {
+        format!("{:#?}", self)
+    }
+
+    fn to_document(&self) -> MongoDocument {
+        doc! {
+            "token": self.token.clone(),
+            "file": self.file.clone(),
+            "subject": self.subject.clone(),
+            "date": self.date,
+        }
+    }
+
+    fn from_document(document: MongoDocument) -> Result<Self, ValueAccessError> {
+        Ok(Self {
+            id: Some(document.get_object_id("_id")?.to_hex()),
+            token: document.get_str("token")?.to_owned(),
+            file: document.get_str("file")?.to_owned(),
+            subject: document.get_str("subject")?.to_owned(),
+            date: document.get_datetime("date")?.to_owned(),
+        })
+    }
+}
@@ -0,0 +1,110 @@
+use bson::document::ValueAccessError;
+use bson::{doc, oid::ObjectId, Bson};
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document as MongoDocument;
+use serde::{Deserialize, Serialize};
+use simple_cache::CacheItem;
+
+use crate::mongo::models::document::Document;
+use crate::mongo::models::Model;
+
+/// Result of a compile, the id is generated before compiling so the files are written to a path
+/// of their own and can be exported again later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Compilation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    pub token: String,
+    /// SHA-256 of the data filling the documents
+    pub hash: String,
+    /// Directory of the compiled files
+    #[serde(skip)]
+    pub path: String,
+    /// Template documents as they were compiled
+    #[serde(skip)]
+    pub documents: Vec<Document>,
+    pub date: DateTime<Utc>,
+}
+
+impl Compilation {
+    pub fn new(token: String, hash: String) -> Self {
+        Self {
+            id: Some(ObjectId::new().to_hex()),
+            token,
+            hash,
+            path: "".into(),
+            documents: Vec::new(),
+            date: Utc::now(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or_default()
+    }
+}
+
+impl CacheItem for Compilation {}
+
+impl Model for Compilation {
+    fn name() -> &'static str {
+        "compilation"
+    }
+
+    fn default() -> Self {
+        Self {
+            id: None,
+            token: "".into(),
+            hash: "".into(),
+            path: "".into(),
+            documents: Vec::new(),
+            date: Utc::now(),
+        }
+    }
+
+    fn debug(&self) -> String {
+        format!("{:#?}", self)
+    }
+
+    fn to_document(&self) -> MongoDocument {
+        let documents = self
+            .documents
+            .iter()
+            .map(|document| Bson::Document(document.to_document()))
+            .collect::<Vec<_>>();
+
+        let mut document = doc! {
+            "token": self.token.clone(),
+            "hash": self.hash.clone(),
+            "path": self.path.clone(),
+            "documents": documents,
+            "date": self.date,
+        };
+        if let Some(id) = self
+            .id
+            .as_deref()
+            .and_then(|id| ObjectId::with_string(id).ok())
+        {
+            document.insert("_id", id);
+        }
+
+        document
+    }
+
+    fn from_document(document: MongoDocument) -> Result<Self, ValueAccessError> {
+        let mut documents = Vec::new();
+        for compiled in document.get_array("documents")? {
+            if let Bson::Document(compiled) = compiled {
+                documents.push(Document::from_document(compiled.clone())?);
+            }
+        }
+
+        Ok(Self {
+            id: Some(document.get_object_id("_id")?.to_hex()),
+            token: document.get_str("token")?.to_owned(),
+            hash: document.get_str("hash")?.to_owned(),
+            path: document.get_str("path")?.to_owned(),
+            documents,
+            date: document.get_datetime("date")?.to_owned(),
+        })
+    }
+}
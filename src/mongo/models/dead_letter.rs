@@ -0,0 +1,82 @@
+use bson::doc;
+use bson::document::ValueAccessError;
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document as MongoDocument;
+use serde::{Deserialize, Serialize};
+use simple_cache::CacheItem;
+
+use crate::mongo::models::Model;
+
+/// Callback which couldn't be delivered after all the attempts, kept to be replayed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    pub job: String,
+    pub url: String,
+    pub payload: String,
+    pub attempts: u32,
+    pub error: String,
+    pub date: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    pub fn new(job: String, url: String, payload: String, attempts: u32, error: String) -> Self {
+        Self {
+            id: None,
+            job,
+            url,
+            payload,
+            attempts,
+            error,
+            date: Utc::now(),
+        }
+    }
+}
+
+impl CacheItem for DeadLetter {}
+
+impl Model for DeadLetter {
+    fn name() -> &'static str {
+        "dead_letter"
+    }
+
+    fn default() -> Self {
+        Self {
+            id: None,
+            job: "".into(),
+            url: "".into(),
+            payload: "".into(),
+            attempts: 0,
+            error: "".into(),
+            date: Utc::now(),
+        }
+    }
+
+    fn debug(&self) -> String {
+        format!("{:#?}", self)
+    }
+
+    fn to_document(&self) -> MongoDocument {
+        doc! {
+            "job": self.job.clone(),
+            "url": self.url.clone(),
+            "payload": self.payload.clone(),
+            "attempts": self.attempts as i32,
+            "error": self.error.clone(),
+            "date": self.date,
+        }
+    }
+
+    fn from_document(document: MongoDocument) -> Result<Self, ValueAccessError> {
+        Ok(Self {
+            id: Some(document.get_object_id("_id")?.to_hex()),
+            job: document.get_str("job")?.to_owned(),
+            url: document.get_str("url")?.to_owned(),
+            payload: document.get_str("payload")?.to_owned(),
+            attempts: document.get_i32("attempts")?.max(0) as u32,
+            error: document.get_str("error")?.to_owned(),
+            date: document.get_datetime("date")?.to_owned(),
+        })
+    }
+}
@@ -1,5 +1,7 @@
-use bson::doc;
+use std::collections::HashMap;
+
 use bson::document::ValueAccessError;
+use bson::{doc, Bson};
 use chrono::{DateTime, Utc};
 use mongodb::bson::Document as MongoDocument;
 use serde::{Deserialize, Serialize};
@@ -7,24 +9,169 @@ use simple_cache::CacheItem;
 
 use crate::mongo::models::Model;
 
+/// Description given at upload and values read from the PDF at upload time.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DocumentMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub content_type: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pages: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<u32>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+impl DocumentMetadata {
+    fn to_document(&self) -> MongoDocument {
+        // Label keys may contain dots, which aren't safe as key names
+        let labels = self
+            .labels
+            .iter()
+            .map(|(key, value)| {
+                Bson::Document(doc! {
+                    "key": key.clone(),
+                    "value": value.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut document = doc! {
+            "content_type": self.content_type.clone(),
+            "size": self.size as i64,
+            "sha256": self.sha256.clone(),
+            "tags": self.tags.clone(),
+            "labels": labels,
+        };
+        if let Some(ref title) = self.title {
+            document.insert("title", title.clone());
+        }
+        if let Some(ref description) = self.description {
+            document.insert("description", description.clone());
+        }
+        if let Some(pages) = self.pages {
+            document.insert("pages", pages as i32);
+        }
+        if let Some(fields) = self.fields {
+            document.insert("fields", fields as i32);
+        }
+
+        document
+    }
+
+    fn from_document(document: &MongoDocument) -> Result<Self, ValueAccessError> {
+        let mut labels = HashMap::new();
+        for label in document.get_array("labels")? {
+            if let Bson::Document(label) = label {
+                labels.insert(
+                    label.get_str("key")?.to_owned(),
+                    label.get_str("value")?.to_owned(),
+                );
+            }
+        }
+
+        Ok(Self {
+            title: document.get_str("title").ok().map(|title| title.to_owned()),
+            description: document
+                .get_str("description")
+                .ok()
+                .map(|description| description.to_owned()),
+            content_type: document.get_str("content_type")?.to_owned(),
+            size: document.get_i64("size")?.max(0) as u64,
+            sha256: document.get_str("sha256")?.to_owned(),
+            pages: document
+                .get_i32("pages")
+                .ok()
+                .map(|pages| pages.max(0) as u32),
+            fields: document
+                .get_i32("fields")
+                .ok()
+                .map(|fields| fields.max(0) as u32),
+            tags: document
+                .get_array("tags")?
+                .iter()
+                .filter_map(|tag| tag.as_str().map(|tag| tag.to_owned()))
+                .collect(),
+            labels,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     id: Option<String>,
     pub token: String,
     pub file: String,
+    /// Name of the uploaded file, documents uploaded before it was stored only have `file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// Kind of XFA form detected at upload, `hybrid` or `dynamic`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xfa: Option<String>,
+    /// Logical name of the template, uploads with the same name are its versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Documents uploaded before metadata was stored have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DocumentMetadata>,
     pub date: DateTime<Utc>,
 }
 
 impl Document {
-    pub fn new(token: String, file: String) -> Self {
+    const UUID_LENGTH: usize = 36;
+
+    pub fn new(token: String, file: String, filename: Option<String>) -> Self {
         Self {
             id: None,
             token,
             file,
+            filename,
+            xfa: None,
+            name: None,
+            version: None,
+            metadata: None,
             date: Utc::now(),
         }
     }
+
+    /// The uploaded file name, or the stored one without the random UUID prefix added at upload
+    /// time for older documents.
+    pub fn title(&self) -> String {
+        if let Some(ref filename) = self.filename {
+            return filename.clone();
+        }
+
+        let file_name = crystalsoft_utils::get_filename(&self.file).unwrap_or_default();
+
+        match file_name.get(Self::UUID_LENGTH..) {
+            Some(title) if !title.is_empty() => title.to_owned(),
+            _ => file_name,
+        }
+    }
+
+    /// Name grouping the versions, documents uploaded before templates were named are their own
+    /// template.
+    pub fn template_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.file.clone())
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn template_version(&self) -> u32 {
+        self.version.unwrap_or(1)
+    }
 }
 
 impl CacheItem for Document {}
@@ -39,20 +186,55 @@ impl Model for Document {
             id: None,
             token: "".into(),
             file: "".into(),
+            filename: None,
+            xfa: None,
+            name: None,
+            version: None,
+            metadata: None,
             date: Utc::now(),
         }
     }
 
+    fn indexes() -> Vec<MongoDocument> {
+        vec![
+            doc! { "token": 1, "date": 1, "_id": 1 },
+            doc! { "date": 1, "_id": 1 },
+            doc! { "metadata.tags": 1 },
+        ]
+    }
+
+    /// Versions of a template, documents uploaded before templates were named have none
+    fn unique_indexes() -> Vec<MongoDocument> {
+        vec![doc! { "token": 1, "name": 1, "version": -1 }]
+    }
+
     fn debug(&self) -> String {
         format!("{:#?}", self)
     }
 
     fn to_document(&self) -> MongoDocument {
-        doc! {
+        let mut document = doc! {
             "token": self.token.clone(),
             "file": self.file.clone(),
             "date": self.date,
+        };
+        if let Some(ref filename) = self.filename {
+            document.insert("filename", filename.clone());
         }
+        if let Some(ref xfa) = self.xfa {
+            document.insert("xfa", xfa.clone());
+        }
+        if let Some(ref name) = self.name {
+            document.insert("name", name.clone());
+        }
+        if let Some(version) = self.version {
+            document.insert("version", version as i32);
+        }
+        if let Some(ref metadata) = self.metadata {
+            document.insert("metadata", metadata.to_document());
+        }
+
+        document
     }
 
     fn from_document(document: MongoDocument) -> Result<Self, ValueAccessError> {
@@ -60,9 +242,24 @@ impl Model for Document {
         // bson::from_bson::<Document>(bson::Bson::Document(document)).unwrap_or(Self::default())
 
         Ok(Self {
-            id: Some(document.get_object_id("_id")?.to_hex()),
+            // Documents kept in compilations have no id of their own
+            id: document.get_object_id("_id").ok().map(|id| id.to_hex()),
             token: document.get_str("token")?.to_owned(),
             file: document.get_str("file")?.to_owned(),
+            filename: document
+                .get_str("filename")
+                .ok()
+                .map(|filename| filename.to_owned()),
+            xfa: document.get_str("xfa").ok().map(|xfa| xfa.to_owned()),
+            name: document.get_str("name").ok().map(|name| name.to_owned()),
+            version: document
+                .get_i32("version")
+                .ok()
+                .map(|version| version.max(1) as u32),
+            metadata: match document.get_document("metadata") {
+                Ok(metadata) => Some(DocumentMetadata::from_document(metadata)?),
+                Err(_) => None,
+            },
             date: document.get_datetime("date")?.to_owned(),
         })
     }
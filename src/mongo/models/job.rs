@@ -0,0 +1,178 @@
+use bson::document::ValueAccessError;
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document as MongoDocument;
+use serde::{Deserialize, Serialize};
+use simple_cache::CacheItem;
+
+use crate::mongo::models::Model;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    fn from_str(status: &str) -> Self {
+        match status {
+            "running" => JobStatus::Running,
+            "done" => JobStatus::Done,
+            "failed" => JobStatus::Failed,
+            _ => JobStatus::Queued,
+        }
+    }
+}
+
+/// Compile running in the background, the id is generated before inserting so it can be returned
+/// right away and the result is stored in `file` once done.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    pub token: String,
+    pub status: JobStatus,
+    /// Percentage of the work done
+    pub progress: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Name of the result without the extension, which follows the content type
+    pub filename: String,
+    /// URL notified when the job ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback: Option<String>,
+    /// Compilation of the documents, available once they are compiled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compilation: Option<String>,
+    pub date: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+
+impl Job {
+    pub fn new(token: String, filename: String) -> Self {
+        Self {
+            id: Some(ObjectId::new().to_hex()),
+            token,
+            status: JobStatus::Queued,
+            progress: 0,
+            error: None,
+            file: None,
+            content_type: None,
+            filename,
+            callback: None,
+            compilation: None,
+            date: Utc::now(),
+            updated: Utc::now(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or_default()
+    }
+}
+
+impl CacheItem for Job {}
+
+impl Model for Job {
+    fn name() -> &'static str {
+        "job"
+    }
+
+    fn default() -> Self {
+        Self {
+            id: None,
+            token: "".into(),
+            status: JobStatus::Queued,
+            progress: 0,
+            error: None,
+            file: None,
+            content_type: None,
+            filename: "".into(),
+            callback: None,
+            compilation: None,
+            date: Utc::now(),
+            updated: Utc::now(),
+        }
+    }
+
+    fn debug(&self) -> String {
+        format!("{:#?}", self)
+    }
+
+    fn to_document(&self) -> MongoDocument {
+        let mut document = doc! {
+            "token": self.token.clone(),
+            "status": self.status.as_str(),
+            "progress": self.progress as i32,
+            "filename": self.filename.clone(),
+            "date": self.date,
+            "updated": self.updated,
+        };
+        if let Some(id) = self
+            .id
+            .as_deref()
+            .and_then(|id| ObjectId::with_string(id).ok())
+        {
+            document.insert("_id", id);
+        }
+        if let Some(ref error) = self.error {
+            document.insert("error", error.clone());
+        }
+        if let Some(ref file) = self.file {
+            document.insert("file", file.clone());
+        }
+        if let Some(ref content_type) = self.content_type {
+            document.insert("content_type", content_type.clone());
+        }
+        if let Some(ref callback) = self.callback {
+            document.insert("callback", callback.clone());
+        }
+        if let Some(ref compilation) = self.compilation {
+            document.insert("compilation", compilation.clone());
+        }
+
+        document
+    }
+
+    fn from_document(document: MongoDocument) -> Result<Self, ValueAccessError> {
+        Ok(Self {
+            id: Some(document.get_object_id("_id")?.to_hex()),
+            token: document.get_str("token")?.to_owned(),
+            status: JobStatus::from_str(document.get_str("status")?),
+            progress: document.get_i32("progress")?.clamp(0, 100) as u8,
+            error: document.get_str("error").ok().map(|error| error.to_owned()),
+            file: document.get_str("file").ok().map(|file| file.to_owned()),
+            content_type: document
+                .get_str("content_type")
+                .ok()
+                .map(|content_type| content_type.to_owned()),
+            filename: document.get_str("filename")?.to_owned(),
+            callback: document
+                .get_str("callback")
+                .ok()
+                .map(|callback| callback.to_owned()),
+            compilation: document
+                .get_str("compilation")
+                .ok()
+                .map(|compilation| compilation.to_owned()),
+            date: document.get_datetime("date")?.to_owned(),
+            updated: document.get_datetime("updated")?.to_owned(),
+        })
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use bson::document::ValueAccessError;
+use bson::{doc, Bson};
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document as MongoDocument;
+use serde::{Deserialize, Serialize};
+use simple_cache::CacheItem;
+
+use crate::mongo::models::Model;
+
+/// Named mapping of the columns of CSV and XLSX data to the field names of the token documents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MappingProfile {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    pub token: String,
+    pub name: String,
+    /// Field names by column header, other columns keep their header as field name
+    pub columns: HashMap<String, String>,
+    /// Column headers whose cells hold several values split on the `separator`
+    pub lists: Vec<String>,
+    pub separator: Option<String>,
+    pub date: DateTime<Utc>,
+}
+
+impl MappingProfile {
+    pub fn new(
+        token: String,
+        name: String,
+        columns: HashMap<String, String>,
+        lists: Vec<String>,
+        separator: Option<String>,
+    ) -> Self {
+        Self {
+            id: None,
+            token,
+            name,
+            columns,
+            lists,
+            separator,
+            date: Utc::now(),
+        }
+    }
+}
+
+impl CacheItem for MappingProfile {}
+
+impl Model for MappingProfile {
+    fn name() -> &'static str {
+        "mapping"
+    }
+
+    fn default() -> Self {
+        Self {
+            id: None,
+            token: "".into(),
+            name: "".into(),
+            columns: HashMap::new(),
+            lists: Vec::new(),
+            separator: None,
+            date: Utc::now(),
+        }
+    }
+
+    fn debug(&self) -> String {
+        format!("{:#?}", self)
+    }
+
+    fn to_document(&self) -> MongoDocument {
+        // Headers may contain dots, which aren't safe as key names
+        let columns = self
+            .columns
+            .iter()
+            .map(|(column, field)| {
+                Bson::Document(doc! {
+                    "column": column.clone(),
+                    "field": field.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut document = doc! {
+            "token": self.token.clone(),
+            "name": self.name.clone(),
+            "columns": columns,
+            "lists": self.lists.clone(),
+            "date": self.date,
+        };
+        if let Some(ref separator) = self.separator {
+            document.insert("separator", separator.clone());
+        }
+
+        document
+    }
+
+    fn from_document(document: MongoDocument) -> Result<Self, ValueAccessError> {
+        let mut columns = HashMap::new();
+        for column in document.get_array("columns")? {
+            if let Bson::Document(column) = column {
+                columns.insert(
+                    column.get_str("column")?.to_owned(),
+                    column.get_str("field")?.to_owned(),
+                );
+            }
+        }
+
+        Ok(Self {
+            id: Some(document.get_object_id("_id")?.to_hex()),
+            token: document.get_str("token")?.to_owned(),
+            name: document.get_str("name")?.to_owned(),
+            columns,
+            lists: document
+                .get_array("lists")?
+                .iter()
+                .filter_map(|list| list.as_str().map(|list| list.to_owned()))
+                .collect(),
+            separator: document
+                .get_str("separator")
+                .ok()
+                .map(|separator| separator.to_owned()),
+            date: document.get_datetime("date")?.to_owned(),
+        })
+    }
+}
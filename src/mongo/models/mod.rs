@@ -7,16 +7,29 @@ use bson::document::ValueAccessError;
 
 use simple_cache::CacheItem;
 
+pub mod blob;
+pub mod certificate;
+pub mod compilation;
+pub mod dead_letter;
 pub mod document;
+pub mod job;
+pub mod mapping;
+pub mod webhook;
 
 pub trait Model: CacheItem + Send + Sync + Unpin + Serialize + DeserializeOwned {
     fn name() -> &'static str;
 
-    fn prefix() -> String {
-        Self::name().to_string()
+    fn default() -> Self;
+
+    /// Keys of the indexes of the collection.
+    fn indexes() -> Vec<MongoDocument> {
+        Vec::new()
     }
 
-    fn default() -> Self;
+    /// Keys of the indexes refusing duplicate values.
+    fn unique_indexes() -> Vec<MongoDocument> {
+        Vec::new()
+    }
 
     fn debug(&self) -> String;
 
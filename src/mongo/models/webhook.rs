@@ -0,0 +1,74 @@
+use bson::doc;
+use bson::document::ValueAccessError;
+use chrono::{DateTime, Utc};
+use mongodb::bson::Document as MongoDocument;
+use serde::{Deserialize, Serialize};
+use simple_cache::CacheItem;
+
+use crate::mongo::models::Model;
+
+/// Callback notified when the compile jobs of a token end, the secret signs the payloads and
+/// isn't returned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    pub token: String,
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    pub date: DateTime<Utc>,
+}
+
+impl Webhook {
+    pub fn new(token: String, url: String, secret: String) -> Self {
+        Self {
+            id: None,
+            token,
+            url,
+            secret,
+            date: Utc::now(),
+        }
+    }
+}
+
+impl CacheItem for Webhook {}
+
+impl Model for Webhook {
+    fn name() -> &'static str {
+        "webhook"
+    }
+
+    fn default() -> Self {
+        Self {
+            id: None,
+            token: "".into(),
+            url: "".into(),
+            secret: "".into(),
+            date: Utc::now(),
+        }
+    }
+
+    fn debug(&self) -> String {
+        format!("{:#?}", self)
+    }
+
+    fn to_document(&self) -> MongoDocument {
+        doc! {
+            "token": self.token.clone(),
+            "url": self.url.clone(),
+            "secret": self.secret.clone(),
+            "date": self.date,
+        }
+    }
+
+    fn from_document(document: MongoDocument) -> Result<Self, ValueAccessError> {
+        Ok(Self {
+            id: Some(document.get_object_id("_id")?.to_hex()),
+            token: document.get_str("token")?.to_owned(),
+            url: document.get_str("url")?.to_owned(),
+            secret: document.get_str("secret")?.to_owned(),
+            date: document.get_datetime("date")?.to_owned(),
+        })
+    }
+}
@@ -1,5 +1,8 @@
+use bson::oid::ObjectId;
+use mongodb::bson::Document as MongoDocument;
+
 use crate::mongo::models::Model;
-use crate::mongo::{MongoDB, MongoResult};
+use crate::mongo::{Error, FindQuery, MongoDB, MongoResult};
 
 #[derive(Clone)]
 pub struct MongoWrapper {
@@ -12,10 +15,6 @@ impl MongoWrapper {
     }
 
     /// Generic
-    pub async fn get_all<T: 'static + Model, S: AsRef<str>>(&self, sort_by: S) -> Option<Vec<T>> {
-        self.mongo.get::<T, _>(None, sort_by).await
-    }
-
     pub async fn get_all_by<T: 'static + Model, S: AsRef<str>>(
         &self,
         key: S,
@@ -25,7 +24,76 @@ impl MongoWrapper {
         self.mongo.get::<T, _>(Some((key, value)), sort_by).await
     }
 
+    pub async fn find<T: 'static + Model>(&self, query: FindQuery) -> Option<Vec<T>> {
+        self.mongo.find::<T>(query).await
+    }
+
+    pub async fn create_indexes<T: 'static + Model>(&self) -> MongoResult<()> {
+        self.mongo.create_indexes::<T>().await
+    }
+
     pub async fn create<T: 'static + Model>(&self, model: T) -> MongoResult<()> {
         self.mongo.insert::<T>(model).await
     }
+
+    pub async fn get_by_id<T: 'static + Model, S: AsRef<str>>(&self, id: S) -> Option<T> {
+        match ObjectId::with_string(id.as_ref()) {
+            Ok(id) => self.mongo.find_one::<T>(id).await,
+            Err(_) => None,
+        }
+    }
+
+    pub async fn update<T: 'static + Model, S: AsRef<str>>(
+        &self,
+        id: S,
+        model: T,
+    ) -> MongoResult<()> {
+        let id = ObjectId::with_string(id.as_ref()).map_err(Error::InvalidId)?;
+
+        self.mongo.update_one::<T>(id, model).await
+    }
+
+    pub async fn update_where<T: 'static + Model>(
+        &self,
+        filter: MongoDocument,
+        update: MongoDocument,
+    ) -> MongoResult<Option<T>> {
+        self.mongo.update_where::<T>(filter, update).await
+    }
+
+    pub async fn update_many<T: 'static + Model>(
+        &self,
+        filter: MongoDocument,
+        update: MongoDocument,
+    ) -> MongoResult<i64> {
+        self.mongo.update_many::<T>(filter, update).await
+    }
+
+    pub async fn aggregate<T: 'static + Model>(
+        &self,
+        pipeline: Vec<MongoDocument>,
+    ) -> Option<Vec<T>> {
+        self.mongo.aggregate::<T>(pipeline).await
+    }
+
+    pub async fn upsert_where<T: 'static + Model>(
+        &self,
+        filter: MongoDocument,
+        update: MongoDocument,
+    ) -> MongoResult<Option<T>> {
+        self.mongo.upsert_where::<T>(filter, update).await
+    }
+
+    pub async fn delete<T: 'static + Model, S: AsRef<str>>(&self, id: S) -> MongoResult<()> {
+        let id = ObjectId::with_string(id.as_ref()).map_err(Error::InvalidId)?;
+
+        self.mongo.delete_one::<T>(id).await
+    }
+
+    pub async fn delete_where<T: 'static + Model>(
+        &self,
+        filter: MongoDocument,
+    ) -> MongoResult<bool> {
+        self.mongo.delete_where::<T>(filter).await
+    }
 }
@@ -0,0 +1,96 @@
+use actix_multipart::Multipart;
+use actix_web::{post, web, HttpResponse, Responder};
+use futures_lite::stream::StreamExt;
+
+use crate::data::Data;
+use crate::mongo::models::certificate::Certificate;
+use crate::services::{self, filler::signature, WsError};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(post_certificate);
+}
+
+#[post("/certificate/{token}")]
+pub async fn post_certificate(
+    data: web::Data<Data>,
+    token: web::Path<String>,
+    mut payload: Multipart,
+) -> impl Responder {
+    let max_size = data.upload.as_ref().and_then(|upload| upload.max_size);
+    let mut upload = None;
+    let mut password = None;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        if let Some(ref content_type) = field.content_disposition() {
+            let name = content_type.get_name().map(|name| name.to_owned());
+            let filename = content_type.get_filename().unwrap_or_default().to_owned();
+
+            match name.as_deref() {
+                Some("file") => match services::read_chuncked_buffer(&mut field, max_size).await {
+                    Ok(buf) => {
+                        upload = Some((filename, buf));
+                    }
+                    Err(e) => return services::read_error_response("file", e),
+                },
+                Some("password") => match services::read_field(&mut field).await {
+                    Ok(buf) => match String::from_utf8(buf) {
+                        Ok(value) => {
+                            password = Some(value);
+                        }
+                        Err(e) => {
+                            return HttpResponse::BadRequest().json(WsError {
+                                error: format!("Not a valid password: {:#?}", e),
+                            });
+                        }
+                    },
+                    Err(e) => return services::read_error_response("password", e),
+                },
+                Some(_) => {}
+                None => {}
+            }
+        }
+    }
+
+    let (filename, buf) = match upload {
+        Some(upload) => upload,
+        None => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: "File missing.".into(),
+            });
+        }
+    };
+
+    // The password is only used to check the certificate, it's asked again at signing time
+    let signer = match signature::Signer::from_pkcs12(&buf, password.as_deref().unwrap_or(""), None)
+    {
+        Ok(signer) => signer,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: format!("{}.", e),
+            });
+        }
+    };
+
+    let file = data.file.generate_filepath(if filename.is_empty() {
+        "certificate.p12"
+    } else {
+        &filename
+    });
+    match data.file.save(&file, buf).await {
+        Ok(_) => {
+            let certificate = Certificate::new(token.to_string(), file, signer.subject());
+            match data.create_certificate(certificate.clone()).await {
+                Ok(_) => HttpResponse::Created().json(certificate),
+                Err(e) => HttpResponse::InternalServerError().json(WsError {
+                    error: format!("An error occurred: {:#?}", e),
+                }),
+            }
+        }
+        Err(e) => {
+            sentry::capture_error(&e);
+
+            HttpResponse::InternalServerError().json(WsError {
+                error: format!("An error occurred uploading the file: {}", e),
+            })
+        }
+    }
+}
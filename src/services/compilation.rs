@@ -0,0 +1,85 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+use crate::data::Data;
+use crate::mongo::models::compilation::Compilation;
+use crate::services::{self, filler, WsError};
+
+/// `options` carries the export options as JSON, the compiled files are exported again with them.
+#[derive(Deserialize)]
+pub struct CompilationQuery {
+    format: Option<String>,
+    options: Option<String>,
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_compilation);
+    cfg.service(export_compilation);
+}
+
+#[get("/compilations/{token}/{id}")]
+pub async fn get_compilation(
+    data: web::Data<Data>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (token, id) = path.into_inner();
+
+    match get_token_compilation(&data, &token, &id).await {
+        Some(compilation) => HttpResponse::Ok().json(compilation),
+        None => HttpResponse::NotFound().json(WsError {
+            error: "Compilation not found!".into(),
+        }),
+    }
+}
+
+#[get("/compilations/{token}/{id}/export")]
+pub async fn export_compilation(
+    data: web::Data<Data>,
+    path: web::Path<(String, String)>,
+    query: web::Query<CompilationQuery>,
+    request: web::HttpRequest,
+) -> impl Responder {
+    let (token, id) = path.into_inner();
+    let compilation = match get_token_compilation(&data, &token, &id).await {
+        Some(compilation) => compilation,
+        None => {
+            return HttpResponse::NotFound().json(WsError {
+                error: "Compilation not found!".into(),
+            });
+        }
+    };
+
+    let values = match filler::get_query_options(query.options.as_deref(), &request) {
+        Ok(values) => values,
+        Err(response) => return response,
+    };
+    let options = match filler::get_export_options(&data, &compilation.token, &values).await {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+
+    if let Some(accept) = services::get_accepted_header(&request, query.format.as_deref()) {
+        let filename = options.get_filename(&compilation.token);
+        let export_result = filler::export_documents(
+            &data,
+            compilation.documents.clone(),
+            options,
+            &accept,
+            compilation.id(),
+        )
+        .await;
+
+        services::export_content(accept, filename, export_result)
+    } else {
+        HttpResponse::NotAcceptable().json(WsError {
+            error: "Only PDF, Streams or PNG and JPEG images are accepted".into(),
+        })
+    }
+}
+
+/// Compilations of other tokens are reported as missing.
+async fn get_token_compilation(data: &Data, token: &str, id: &str) -> Option<Compilation> {
+    data.get_compilation(id)
+        .await
+        .filter(|compilation| compilation.token == token)
+}
@@ -1,23 +1,107 @@
-use actix_multipart::{Field, Multipart, MultipartError};
-use actix_web::{get, post, web, HttpResponse, Responder};
+use std::collections::{BTreeSet, HashMap};
+
+use actix_multipart::Multipart;
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
 use futures_lite::stream::StreamExt;
-use serde::Deserialize;
+use log::warn;
+use serde::{Deserialize, Serialize};
 
+use crate::client;
 use crate::data::Data;
-use crate::file::FileError;
-use crate::mongo::models::document::Document;
-use crate::services::{self, filler::compiler, WsError};
+use crate::mongo::models::document::{Document, DocumentMetadata};
+use crate::services::{
+    self,
+    filler::{self, compiler, inspection, render, security, validation, xfa},
+    pagination, storage, ReadError, WsError, WsMessage,
+};
+
+const REMOTE_FILE_NAME: &str = "file.pdf";
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(post_document);
     cfg.service(get_document);
+    cfg.service(delete_document);
     cfg.service(get_documents);
     cfg.service(get_documents_by_token);
+    cfg.service(get_template_versions);
+    cfg.service(get_template_diff);
 }
 
+/// `name` is the logical name of the template, the uploaded file name by default. Uploading a
+/// template with the name of an existing one adds a new version of it. `tags` are separated by
+/// commas and `labels` is a JSON object of strings.
 #[derive(Debug, Deserialize)]
 pub struct FormData {
     file: String,
+    password: Option<String>,
+    name: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    tags: Option<String>,
+    labels: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct UploadMetadata {
+    name: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+    labels: HashMap<String, String>,
+}
+
+impl UploadMetadata {
+    fn set(&mut self, key: &str, value: String) -> Result<(), String> {
+        match key {
+            "name" => self.name = Some(value),
+            "title" => self.title = Some(value).filter(|title| !title.trim().is_empty()),
+            "description" => {
+                self.description = Some(value).filter(|description| !description.trim().is_empty())
+            }
+            "tags" => {
+                for tag in value.split(',').map(str::trim) {
+                    if !tag.is_empty() && !self.tags.iter().any(|value| value == tag) {
+                        self.tags.push(tag.to_string());
+                    }
+                }
+            }
+            "labels" => {
+                let labels =
+                    serde_json::from_str::<HashMap<String, String>>(&value).map_err(|e| {
+                        format!(
+                            "Not valid labels, a JSON object of strings is expected: {}",
+                            e
+                        )
+                    })?;
+                self.labels.extend(labels);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    options: Option<String>,
+    format: Option<String>,
+}
+
+/// Versions compared, the latest one and the one before it by default.
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    from: Option<u32>,
+    to: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct TemplateDiff {
+    name: String,
+    from: u32,
+    to: u32,
+    added: BTreeSet<String>,
+    removed: BTreeSet<String>,
 }
 
 #[post("/document/{token}")]
@@ -27,9 +111,30 @@ pub async fn post_document(
     form: Option<web::Form<FormData>>,
     mut payload: Multipart,
 ) -> impl Responder {
-    let mut filepath = None;
+    let upload_config = data.upload.clone().unwrap_or_default();
+    let mut upload = None;
+    let mut password = None;
+    let mut metadata = UploadMetadata::default();
     if let Some(form) = form {
-        filepath = data.file.download_and_save(form.file.as_str()).await;
+        password = form.password.clone();
+        let values = [
+            ("name", &form.name),
+            ("title", &form.title),
+            ("description", &form.description),
+            ("tags", &form.tags),
+            ("labels", &form.labels),
+        ];
+        for (key, value) in values.iter() {
+            if let Some(value) = value {
+                if let Err(message) = metadata.set(key, value.clone()) {
+                    return HttpResponse::BadRequest().json(WsError { error: message });
+                }
+            }
+        }
+        match download_file(form.file.as_str(), upload_config.max_size).await {
+            Ok(file) => upload = Some(file),
+            Err(e) => return download_error_response(e),
+        }
     } else {
         while let Ok(Some(mut field)) = payload.try_next().await {
             if let Some(ref content_type) = field.content_disposition() {
@@ -37,35 +142,19 @@ pub async fn post_document(
                     Some("file") => match content_type.get_filename() {
                         Some(filename) => {
                             if !filename.is_empty() {
-                                match read_chuncked_buffer(&mut field).await {
+                                match services::read_chuncked_buffer(
+                                    &mut field,
+                                    upload_config.max_size,
+                                )
+                                .await
+                                {
                                     Ok(buf) => {
-                                        let local_filepath = data.file.generate_filepath(&filename);
-                                        match data.file.save(&local_filepath, buf).await {
-                                            Ok(_) => {
-                                                filepath = Some(local_filepath);
-                                            }
-                                            Err(FileError::S3Error(e)) => {
-                                                sentry::capture_error(&e);
-
-                                                return HttpResponse::InternalServerError().json(WsError {
-                                                    error: format!(
-                                                        "An error occurred uploading the file: {:#?}",
-                                                        e
-                                                    ),
-                                                });
-                                            }
-                                            Err(FileError::IoError(e)) => {
-                                                sentry::capture_error(&e);
-
-                                                return HttpResponse::InternalServerError().json(WsError {
-                                                    error: format!(
-                                                        "An error occurred uploading the file: {:#?}",
-                                                        e
-                                                    ),
-                                                });
-                                            }
-                                            _ => {}
-                                        }
+                                        upload = Some((filename.to_string(), buf));
+                                    }
+                                    Err(ReadError::TooLarge { max }) => {
+                                        return upload_error_response(
+                                            validation::UploadError::TooLarge { max },
+                                        );
                                     }
                                     Err(e) => {
                                         sentry::capture_error(&e);
@@ -80,11 +169,12 @@ pub async fn post_document(
                                 }
                             }
                         }
-                        None => match read_chuncked_buffer(&mut field).await {
+                        None => match services::read_field(&mut field).await {
                             Ok(buf) => match std::str::from_utf8(buf.as_slice()) {
-                                Ok(uri) => {
-                                    filepath = data.file.download_and_save(uri).await;
-                                }
+                                Ok(uri) => match download_file(uri, upload_config.max_size).await {
+                                    Ok(file) => upload = Some(file),
+                                    Err(e) => return download_error_response(e),
+                                },
                                 Err(e) => {
                                     sentry::capture_error(&e);
 
@@ -96,18 +186,43 @@ pub async fn post_document(
                                     });
                                 }
                             },
+                            Err(e) => return services::read_error_response("file URI", e),
+                        },
+                    },
+                    Some("password") => match services::read_field(&mut field).await {
+                        Ok(buf) => match String::from_utf8(buf) {
+                            Ok(value) => {
+                                password = Some(value);
+                            }
                             Err(e) => {
-                                sentry::capture_error(&e);
-
-                                return HttpResponse::InternalServerError().json(WsError {
-                                    error: format!(
-                                        "An error occurred uploading the file: {:#?}",
-                                        e
-                                    ),
+                                return HttpResponse::BadRequest().json(WsError {
+                                    error: format!("Not a valid password: {:#?}", e),
                                 });
                             }
                         },
+                        Err(e) => return services::read_error_response("password", e),
                     },
+                    Some(key @ "name")
+                    | Some(key @ "title")
+                    | Some(key @ "description")
+                    | Some(key @ "tags")
+                    | Some(key @ "labels") => {
+                        let key = key.to_string();
+                        let value = match services::read_field(&mut field).await {
+                            Ok(buf) => match String::from_utf8(buf) {
+                                Ok(value) => value,
+                                Err(e) => {
+                                    return HttpResponse::BadRequest().json(WsError {
+                                        error: format!("Not a valid {}: {:#?}", key, e),
+                                    });
+                                }
+                            },
+                            Err(e) => return services::read_error_response(&key, e),
+                        };
+                        if let Err(message) = metadata.set(&key, value) {
+                            return HttpResponse::BadRequest().json(WsError { error: message });
+                        }
+                    }
                     Some(_) => {}
                     None => {}
                 }
@@ -115,49 +230,188 @@ pub async fn post_document(
         }
     }
 
-    if filepath.is_none() {
-        HttpResponse::BadRequest().json(WsError {
-            error: "File missing.".into(),
-        })
-    } else if let Some(file) = filepath {
-        let document = Document::new(token.to_string(), file);
-        match data.create_document(document.clone()).await {
-            Ok(_) => HttpResponse::Created().json(document),
-            Err(e) => HttpResponse::InternalServerError().json(WsError {
-                error: format!("An error occurred: {:#?}", e),
-            }),
+    let (filename, buf) = match upload {
+        Some(upload) => upload,
+        None => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: "File missing.".into(),
+            });
         }
-    } else {
-        HttpResponse::InternalServerError().json(WsError {
-            error: "An error occurred".into(),
-        })
+    };
+
+    if let Err(e) = validation::check_upload(&buf, &upload_config) {
+        return upload_error_response(e);
+    }
+
+    let buf = match security::decrypt_template(buf, password.as_deref()) {
+        Ok(buf) => buf,
+        Err(
+            e @ security::SecurityError::PasswordRequired
+            | e @ security::SecurityError::InvalidPassword,
+        ) => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: format!("{}.", e),
+            });
+        }
+        Err(e) => {
+            return HttpResponse::UnprocessableEntity().json(WsError {
+                error: format!("{}.", e),
+            });
+        }
+    };
+
+    if let Err(e) = validation::check_document(&buf, &upload_config) {
+        return upload_error_response(e);
     }
+
+    // XFA forms are filled only as AcroForm unless asked otherwise when compiling
+    let xfa_form = xfa::detect_buffer(&buf);
+    if let Some(xfa_form) = xfa_form {
+        warn!(
+            "\"{}\" is a {} XFA form, use the \"xfa\" option to handle it when compiling",
+            filename,
+            xfa_form.as_str()
+        );
+    }
+
+    let inspection = inspection::inspect_buffer(&buf);
+
+    let original_filename = get_original_filename(&filename);
+    let name = match metadata
+        .name
+        .map(|name| name.trim().to_string())
+        .or_else(|| original_filename.clone())
+    {
+        Some(name) if !name.is_empty() => name,
+        _ => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: "The template name can't be empty.".into(),
+            });
+        }
+    };
+
+    let sha256 = inspection.sha256.clone();
+    match storage::store_template(&data, &sha256, buf).await {
+        Ok(file) => {
+            let mut document = Document::new(token.to_string(), file.clone(), original_filename);
+            document.xfa = xfa_form.map(|xfa_form| xfa_form.as_str().to_owned());
+            document.name = Some(name);
+            document.metadata = Some(DocumentMetadata {
+                title: metadata.title,
+                description: metadata.description,
+                content_type: inspection.content_type,
+                size: inspection.size,
+                sha256: inspection.sha256,
+                pages: inspection.pages,
+                fields: inspection.fields,
+                tags: metadata.tags,
+                labels: metadata.labels,
+            });
+            match data.create_template_version(document).await {
+                Ok(document) => HttpResponse::Created().json(document),
+                Err(e) => {
+                    if let Err(e) = storage::release_template(&data, &file).await {
+                        sentry::capture_error(&e);
+                    }
+
+                    HttpResponse::InternalServerError().json(WsError {
+                        error: format!("An error occurred: {:#?}", e),
+                    })
+                }
+            }
+        }
+        Err(e) => {
+            sentry::capture_error(&e);
+
+            HttpResponse::InternalServerError().json(WsError {
+                error: format!("An error occurred uploading the file: {}", e),
+            })
+        }
+    }
+}
+
+/// Deletes the document, its file is deleted once no other document shares it.
+#[delete("/document/{token}/{id}")]
+pub async fn delete_document(
+    data: web::Data<Data>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (token, id) = path.into_inner();
+    let document = match data.get_document(&id).await {
+        Some(document) if document.token == token => document,
+        _ => {
+            return HttpResponse::NotFound().json(WsError {
+                error: "Document not found!".into(),
+            });
+        }
+    };
+
+    if let Err(e) = data.delete_document(&id).await {
+        return HttpResponse::InternalServerError().json(WsError {
+            error: format!("An error occurred: {:#?}", e),
+        });
+    }
+    if let Err(e) = storage::release_template(&data, &document.file).await {
+        sentry::capture_error(&e);
+
+        return HttpResponse::InternalServerError().json(WsError {
+            error: format!("An error occurred deleting the file: {}", e),
+        });
+    }
+
+    HttpResponse::Ok().json(WsMessage {
+        message: "Document deleted.".into(),
+    })
 }
 
 #[get("/document/{token}")]
 pub async fn get_document(
     data: web::Data<Data>,
     token: web::Path<String>,
+    query: web::Query<ExportQuery>,
     request: web::HttpRequest,
 ) -> impl Responder {
-    if let Some(documents) = data.get_documents_by_token(token.as_str()).await {
+    let values = match filler::get_query_options(query.options.as_deref(), &request) {
+        Ok(values) => values,
+        Err(response) => return response,
+    };
+    let options = match filler::get_export_options(&data, token.as_str(), &values).await {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+
+    if let Some(documents) = data
+        .get_templates_by_token(token.as_str(), &HashMap::new())
+        .await
+    {
         if documents.is_empty() {
             return HttpResponse::NotFound().json(WsError {
                 error: "No documents found for this token!".into(),
             });
         }
 
-        if let Some(accept) = services::get_accepted_header(&request) {
-            let export_result = if accept.as_str() == mime::APPLICATION_PDF {
-                compiler::merge_documents(data.file.clone(), documents, false).await
+        if let Some(accept) = services::get_accepted_header(&request, query.format.as_deref()) {
+            let filename = options.get_filename(token.as_str());
+            let export_result = if let Some(format) = render::ImageFormat::from_mime(&accept) {
+                compiler::render_documents(
+                    data.file.clone(),
+                    documents,
+                    None,
+                    options,
+                    format,
+                    data.render.as_ref(),
+                )
+                .await
+            } else if accept.as_str() == mime::APPLICATION_PDF {
+                compiler::merge_documents(data.file.clone(), documents, None, options).await
             } else {
-                compiler::zip_documents(data.file.clone(), documents, false).await
+                compiler::zip_documents(data.file.clone(), documents, None, options).await
             };
 
-            services::export_content(accept, export_result)
+            services::export_content(accept, filename, export_result)
         } else {
             HttpResponse::NotAcceptable().json(WsError {
-                error: "Only PDF or Streams are accepted".into(),
+                error: "Only PDF, Streams or PNG and JPEG images are accepted".into(),
             })
         }
     } else {
@@ -168,42 +422,195 @@ pub async fn get_document(
 }
 
 #[get("/documents")]
-pub async fn get_documents(data: web::Data<Data>) -> impl Responder {
-    if let Some(documents) = data.get_all_documents().await {
-        HttpResponse::Ok().json(documents)
-    } else {
-        HttpResponse::NotFound().json(WsError {
-            error: "No documents found for this token!".into(),
-        })
-    }
+pub async fn get_documents(
+    data: web::Data<Data>,
+    query: web::Query<pagination::ListQuery>,
+) -> impl Responder {
+    list_documents(&data, &query, None).await
 }
 
 #[get("/documents/{token}")]
 pub async fn get_documents_by_token(
     data: web::Data<Data>,
     token: web::Path<String>,
+    query: web::Query<pagination::ListQuery>,
 ) -> impl Responder {
-    if let Some(documents) = data.get_documents_by_token(token.as_str()).await {
-        HttpResponse::Ok().json(documents)
+    list_documents(&data, &query, Some(token.as_str())).await
+}
+
+#[get("/documents/{token}/templates/{name}")]
+pub async fn get_template_versions(
+    data: web::Data<Data>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (token, name) = path.into_inner();
+    if let Some(versions) = data.get_template_versions(token, name).await {
+        HttpResponse::Ok().json(versions)
     } else {
         HttpResponse::NotFound().json(WsError {
-            error: "No documents found for this token!".into(),
+            error: "No versions found for this template!".into(),
         })
     }
 }
 
-async fn read_chuncked_buffer(field: &mut Field) -> Result<Vec<u8>, MultipartError> {
-    let mut buf = Vec::new();
-    while let Some(chunk) = field.next().await {
-        match chunk {
-            Ok(data) => {
-                buf.extend(data);
+/// Fields added and removed between two versions of the template.
+#[get("/documents/{token}/templates/{name}/diff")]
+pub async fn get_template_diff(
+    data: web::Data<Data>,
+    path: web::Path<(String, String)>,
+    query: web::Query<DiffQuery>,
+) -> impl Responder {
+    let (token, name) = path.into_inner();
+    let versions = match data.get_template_versions(token, name.clone()).await {
+        Some(versions) => versions,
+        None => {
+            return HttpResponse::NotFound().json(WsError {
+                error: "No versions found for this template!".into(),
+            });
+        }
+    };
+
+    let latest = versions
+        .last()
+        .map(Document::template_version)
+        .unwrap_or_default();
+    let to = query.to.unwrap_or(latest);
+    let from = query.from.unwrap_or_else(|| {
+        versions
+            .iter()
+            .map(Document::template_version)
+            .filter(|version| *version < to)
+            .max()
+            .unwrap_or(to)
+    });
+    let find_version = |version: u32| {
+        versions
+            .iter()
+            .find(|document| document.template_version() == version)
+            .cloned()
+    };
+    let (from_document, to_document) = match (find_version(from), find_version(to)) {
+        (Some(from_document), Some(to_document)) => (from_document, to_document),
+        (None, _) => {
+            return HttpResponse::NotFound().json(WsError {
+                error: format!("Version {} of the template not found!", from),
+            });
+        }
+        (_, None) => {
+            return HttpResponse::NotFound().json(WsError {
+                error: format!("Version {} of the template not found!", to),
+            });
+        }
+    };
+
+    let mut fields = Vec::with_capacity(2);
+    for document in [from_document, to_document].iter() {
+        match compiler::extract_documents(data.file.clone(), std::slice::from_ref(document), None)
+            .await
+        {
+            Ok(map) => fields.push(map.into_keys().collect::<BTreeSet<_>>()),
+            Err(compiler::HandlerCompilerError::FillingError(e)) => {
+                return HttpResponse::UnprocessableEntity().json(WsError {
+                    error: format!("Error reading the PDF form: {:#?}", e),
+                });
             }
-            Err(e) => {
-                return Err(e);
+            Err(compiler::HandlerCompilerError::Error(message)) => {
+                return HttpResponse::InternalServerError().json(WsError { error: message });
             }
         }
     }
 
-    Ok(buf)
+    HttpResponse::Ok().json(TemplateDiff {
+        name,
+        from,
+        to,
+        added: fields[1].difference(&fields[0]).cloned().collect(),
+        removed: fields[0].difference(&fields[1]).cloned().collect(),
+    })
+}
+
+async fn list_documents(
+    data: &Data,
+    query: &pagination::ListQuery,
+    token: Option<&str>,
+) -> HttpResponse {
+    let (find_query, page) = match pagination::get_documents_query(query, token) {
+        Ok(find_query) => find_query,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: format!("{}.", message),
+            });
+        }
+    };
+
+    match data.find_documents(find_query).await {
+        // Empty pages are still pages
+        Some(documents) => pagination::page_response(documents, &page),
+        None => HttpResponse::InternalServerError().json(WsError {
+            error: "An error occurred while listing the documents.".into(),
+        }),
+    }
+}
+
+/// Every kind of invalid upload gets its own status.
+fn upload_error_response(e: validation::UploadError) -> HttpResponse {
+    let error = WsError {
+        error: format!("{}.", e),
+    };
+
+    match e {
+        validation::UploadError::NotPdf => HttpResponse::UnsupportedMediaType().json(error),
+        validation::UploadError::Invalid(_) => HttpResponse::UnprocessableEntity().json(error),
+        validation::UploadError::TooLarge { .. } | validation::UploadError::TooManyPages { .. } => {
+            HttpResponse::PayloadTooLarge().json(error)
+        }
+        validation::UploadError::JavaScript
+        | validation::UploadError::LaunchAction
+        | validation::UploadError::EmbeddedFiles => HttpResponse::BadRequest().json(error),
+    }
+}
+
+/// A remote file past the size limit is refused like an uploaded one.
+fn download_error_response(e: client::GetError) -> HttpResponse {
+    match e {
+        client::GetError::TooLarge { max } => {
+            upload_error_response(validation::UploadError::TooLarge { max })
+        }
+        client::GetError::Request(e) => HttpResponse::BadRequest().json(WsError {
+            error: format!("The remote file can't be downloaded: {}.", e),
+        }),
+    }
+}
+
+async fn download_file(
+    uri: &str,
+    max_size: Option<usize>,
+) -> Result<(String, Vec<u8>), client::GetError> {
+    // The last path segment names the file, query and fragment excluded
+    let filename = uri
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|segment| segment.contains('.'))
+        .unwrap_or(REMOTE_FILE_NAME)
+        .to_string();
+
+    client::get(uri, max_size).await.map(|buf| (filename, buf))
+}
+
+/// Browsers may send the client path, only the name is kept and control characters are removed.
+fn get_original_filename(filename: &str) -> Option<String> {
+    let filename = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>();
+
+    if filename.trim().is_empty() {
+        None
+    } else {
+        Some(filename)
+    }
 }
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use futures_lite::stream::StreamExt;
+use serde::Deserialize;
+
+use crate::data::Data;
+use crate::services::{
+    self,
+    filler::{compiler, fdf, security},
+    WsError,
+};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(post_extract);
+    cfg.service(get_extract);
+}
+
+/// `format` set to `xfdf` returns the values as XFDF, as does accepting `application/vnd.adobe.xfdf`.
+/// `compilation` reads the files compiled by a compilation of the token instead of the templates.
+#[derive(Debug, Deserialize)]
+pub struct ExtractQuery {
+    compilation: Option<String>,
+    format: Option<String>,
+}
+
+#[post("/extract")]
+pub async fn post_extract(
+    data: web::Data<Data>,
+    query: web::Query<ExtractQuery>,
+    request: web::HttpRequest,
+    mut payload: Multipart,
+) -> impl Responder {
+    let max_size = data.upload.as_ref().and_then(|upload| upload.max_size);
+    let mut upload = None;
+    let mut password = None;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        if let Some(ref content_type) = field.content_disposition() {
+            let name = content_type.get_name().map(|name| name.to_owned());
+
+            match name.as_deref() {
+                Some("file") => match services::read_chuncked_buffer(&mut field, max_size).await {
+                    Ok(buf) => {
+                        upload = Some(buf);
+                    }
+                    Err(e) => return services::read_error_response("file", e),
+                },
+                Some("password") => match services::read_field(&mut field).await {
+                    Ok(buf) => match String::from_utf8(buf) {
+                        Ok(value) => {
+                            password = Some(value);
+                        }
+                        Err(e) => {
+                            return HttpResponse::BadRequest().json(WsError {
+                                error: format!("Not a valid password: {:#?}", e),
+                            });
+                        }
+                    },
+                    Err(e) => return services::read_error_response("password", e),
+                },
+                Some(_) => {}
+                None => {}
+            }
+        }
+    }
+
+    let buf = match upload {
+        Some(buf) => buf,
+        None => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: "File missing.".into(),
+            });
+        }
+    };
+
+    let buf = match security::decrypt_template(buf, password.as_deref()) {
+        Ok(buf) => buf,
+        Err(
+            e @ security::SecurityError::PasswordRequired
+            | e @ security::SecurityError::InvalidPassword,
+        ) => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: format!("{}.", e),
+            });
+        }
+        Err(e) => {
+            return HttpResponse::UnprocessableEntity().json(WsError {
+                error: format!("{}.", e),
+            });
+        }
+    };
+
+    match compiler::extract_document(&buf) {
+        Ok(map) => export_values(&request, &query, map),
+        Err(e) => HttpResponse::UnprocessableEntity().json(WsError {
+            error: format!("Error reading the PDF form: {:#?}", e),
+        }),
+    }
+}
+
+#[get("/extract/{token}")]
+pub async fn get_extract(
+    data: web::Data<Data>,
+    token: web::Path<String>,
+    query: web::Query<ExtractQuery>,
+    request: web::HttpRequest,
+) -> impl Responder {
+    let documents = match query.compilation {
+        Some(ref id) => match data.get_compilation(id).await {
+            Some(compilation) if compilation.token == token.as_str() => Some(compilation.documents),
+            _ => {
+                return HttpResponse::NotFound().json(WsError {
+                    error: "Compilation not found!".into(),
+                });
+            }
+        },
+        None => {
+            data.get_templates_by_token(token.as_str(), &HashMap::new())
+                .await
+        }
+    };
+
+    if let Some(documents) = documents {
+        match compiler::extract_documents(
+            data.file.clone(),
+            &documents,
+            query.compilation.as_deref(),
+        )
+        .await
+        {
+            Ok(map) => export_values(&request, &query, map),
+            Err(compiler::HandlerCompilerError::FillingError(e)) => {
+                HttpResponse::UnprocessableEntity().json(WsError {
+                    error: format!("Error reading the PDF form: {:#?}", e),
+                })
+            }
+            Err(compiler::HandlerCompilerError::Error(message)) => {
+                HttpResponse::InternalServerError().json(WsError { error: message })
+            }
+        }
+    } else {
+        HttpResponse::NotFound().json(WsError {
+            error: "No documents found for this token!".into(),
+        })
+    }
+}
+
+fn export_values(
+    request: &web::HttpRequest,
+    query: &ExtractQuery,
+    map: compiler::PDFillerMap,
+) -> HttpResponse {
+    let xfdf = match query.format.as_deref() {
+        Some(format) => format.eq_ignore_ascii_case("xfdf"),
+        None => request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map(|accept| accept.contains(fdf::XFDF_CONTENT_TYPE))
+            .unwrap_or(false),
+    };
+
+    if xfdf {
+        HttpResponse::Ok()
+            .content_type(fdf::XFDF_CONTENT_TYPE)
+            .body(fdf::to_xfdf(&map))
+    } else {
+        HttpResponse::Ok().json(map)
+    }
+}
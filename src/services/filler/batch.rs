@@ -0,0 +1,455 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::io::{Cursor, Write};
+use std::rc::Rc;
+use std::str;
+
+use actix_web::{rt, web};
+use async_std::channel::{self, Sender};
+use async_std::sync::Arc;
+use futures_util::stream::{self, Stream, StreamExt};
+use log::error;
+use lopdf::{Document as PdfDocument, Error};
+use pdf_forms::{Form, LoadError};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::file::FileProvider;
+use crate::mongo::models::document::Document;
+use crate::services::filler::compiler::{
+    self, ExportChunk, ExportCompilerError, ExportCompilerResult, ExportOptions, ExportedContent,
+    PDFillerMap,
+};
+use crate::services::filler::form;
+use crate::services::filler::processor::{self, DocumentPages};
+use crate::services::filler::xfa::{self, XfaMode};
+
+pub const NDJSON_CONTENT_TYPES: [&str; 2] = ["application/x-ndjson", "application/jsonl"];
+
+/// Filled documents of a row, in the order of the templates.
+type RowDocuments = Vec<(Document, PdfDocument)>;
+
+/// Number, key and documents of a row.
+type CompiledRow = (usize, String, Result<RowDocuments, String>);
+
+const ROWS_KEY: &str = "rows";
+const ERRORS_ENTRY_NAME: &str = "errors.json";
+const BYTE_ORDER_MARK: char = '\u{feff}';
+
+#[derive(Debug)]
+pub enum BatchError {
+    Utf8(str::Utf8Error),
+    Json(serde_json::Error),
+    Line(usize, serde_json::Error),
+    NotAnObject(usize),
+    NoRows,
+}
+
+impl Display for BatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::Utf8(e) => write!(f, "Error decoding the body: {}", e),
+            BatchError::Json(e) => write!(f, "Couldn't decode the body as JSON: {}", e),
+            BatchError::Line(line, e) => {
+                write!(f, "Couldn't decode line {} as JSON: {}", line, e)
+            }
+            BatchError::NotAnObject(row) => {
+                write!(f, "Row {} is not a JSON object of field values", row)
+            }
+            BatchError::NoRows => write!(f, "The batch has no rows"),
+        }
+    }
+}
+
+impl From<str::Utf8Error> for BatchError {
+    fn from(e: str::Utf8Error) -> Self {
+        BatchError::Utf8(e)
+    }
+}
+
+impl From<serde_json::Error> for BatchError {
+    fn from(e: serde_json::Error) -> Self {
+        BatchError::Json(e)
+    }
+}
+
+/// Data map of a row, `key` names its file in the exported ZIP.
+pub struct BatchRow {
+    pub key: String,
+    pub map: PDFillerMap,
+}
+
+/// Row which couldn't be compiled, the other rows are exported anyway.
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    pub row: usize,
+    pub key: String,
+    pub error: String,
+}
+
+/// Stored document with its file, loaded once for every row.
+pub struct Template {
+    document: Document,
+    buffer: Vec<u8>,
+}
+
+pub struct BatchExport {
+    pub content: ExportedContent,
+    pub errors: Vec<RowError>,
+}
+
+pub enum BatchFailure {
+    Rows(Vec<RowError>),
+    Export(ExportCompilerError),
+}
+
+enum LoadedTemplate {
+    Form(Form),
+    Document(PdfDocument),
+}
+
+/// Export options and data maps of a batch. A JSON body is either an array of data maps or an
+/// object holding them in `rows` next to the export options, NDJSON bodies take the options from
+/// the `options` query parameter.
+pub fn parse_body(
+    content_type: Option<&str>,
+    body: &[u8],
+    options: Value,
+) -> Result<(Value, Vec<PDFillerMap>), BatchError> {
+    let body = str::from_utf8(body)?.trim_start_matches(BYTE_ORDER_MARK);
+
+    let (options, rows) = match content_type {
+        Some(content_type) if NDJSON_CONTENT_TYPES.contains(&content_type) => {
+            (options, parse_ndjson(body)?)
+        }
+        _ => match serde_json::from_str::<Value>(body)? {
+            Value::Array(values) => (options, get_maps(values)?),
+            Value::Object(mut values) => match values.remove(ROWS_KEY) {
+                Some(Value::Array(rows)) => (Value::Object(values), get_maps(rows)?),
+                _ => return Err(BatchError::NoRows),
+            },
+            _ => return Err(BatchError::NoRows),
+        },
+    };
+
+    if rows.is_empty() {
+        Err(BatchError::NoRows)
+    } else {
+        Ok((options, rows))
+    }
+}
+
+/// Names the rows after the value of their `key` field, their number when it's missing.
+pub fn name_rows(maps: Vec<PDFillerMap>, key: Option<&str>) -> Vec<BatchRow> {
+    let width = maps.len().to_string().len();
+
+    maps.into_iter()
+        .enumerate()
+        .map(|(index, map)| {
+            let name = key
+                .and_then(|key| map.get(key))
+                .and_then(|value| match value {
+                    Value::String(value) => Some(value.clone()),
+                    Value::Number(value) => Some(value.to_string()),
+                    _ => None,
+                })
+                .map(|value| sanitize_filename::sanitize(value.trim()))
+                .filter(|value| !value.is_empty());
+
+            BatchRow {
+                key: name.unwrap_or_else(|| format!("{:0width$}", index + 1, width = width)),
+                map,
+            }
+        })
+        .collect()
+}
+
+/// Loads the files of the documents, compiled rows are filled in memory as concurrent compiles
+/// would share the compiled files.
+pub async fn load_templates<F: FileProvider + ?Sized>(
+    file_type: Arc<Box<F>>,
+    documents: Vec<Document>,
+) -> Result<Vec<Template>, String> {
+    let mut templates = Vec::new();
+    for document in documents {
+        match file_type.load(&document.file).await {
+            Ok(buffer) => templates.push(Template { document, buffer }),
+            Err(e) => {
+                sentry::capture_error(&e);
+
+                return Err(format!("Error {:#?} loading a PDF file, aborted.", e));
+            }
+        }
+    }
+
+    Ok(templates)
+}
+
+/// Compiles the rows into a ZIP with a PDF for every row, the errors are listed in `errors.json`.
+pub fn zip_batch(
+    templates: Vec<Template>,
+    rows: Vec<BatchRow>,
+    options: ExportOptions,
+    concurrency: usize,
+) -> ExportCompilerResult<ExportedContent> {
+    let (sender, receiver) = channel::bounded(compiler::EXPORT_CHANNEL_CAPACITY);
+    rt::spawn(async move {
+        if let Err(e) = write_zip(templates, rows, &options, concurrency, &sender).await {
+            error!("Error making the batch ZIP file: {}", e);
+
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+
+    Ok(receiver.into())
+}
+
+/// Compiles the rows into a single PDF, failing only when no row could be compiled.
+pub async fn merge_batch(
+    templates: Vec<Template>,
+    rows: Vec<BatchRow>,
+    options: ExportOptions,
+    concurrency: usize,
+) -> Result<BatchExport, BatchFailure> {
+    let mut documents = Vec::new();
+    let mut titles = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut compiled = compile_rows(templates, rows, options.xfa, concurrency);
+    while let Some((row, key, result)) = compiled.next().await {
+        match result {
+            Ok(row_documents) => {
+                titles.extend(row_documents.iter().map(|_| key.clone()));
+                documents.extend(row_documents);
+            }
+            Err(error) => errors.push(RowError { row, key, error }),
+        }
+    }
+
+    if documents.is_empty() {
+        return Err(BatchFailure::Rows(errors));
+    }
+
+    let mut documents_objects = processor::get_loaded_containers(documents);
+    for (pages, title) in documents_objects.documents.iter_mut().zip(titles) {
+        pages.title = title;
+    }
+
+    let mut document = processor::process_documents(&documents_objects).ok_or_else(|| {
+        BatchFailure::Export(ExportCompilerError::GenericError(
+            "Error decoding the PDFs files.".into(),
+        ))
+    })?;
+    let content = export_batch_document(&mut document, documents_objects.documents, &options)
+        .await
+        .map_err(BatchFailure::Export)?;
+
+    Ok(BatchExport { content, errors })
+}
+
+/// Fills the rows keeping their order, up to `concurrency` rows at the same time.
+fn compile_rows(
+    templates: Vec<Template>,
+    rows: Vec<BatchRow>,
+    xfa: Option<XfaMode>,
+    concurrency: usize,
+) -> impl Stream<Item = CompiledRow> {
+    let templates = Arc::new(templates);
+
+    stream::iter(rows.into_iter().enumerate())
+        .map(move |(index, row)| {
+            let templates = templates.clone();
+
+            async move {
+                let result = compile_row(templates, row.map, xfa).await;
+
+                (index + 1, row.key, result)
+            }
+        })
+        .buffered(concurrency.max(1))
+}
+
+/// Fills every document with the row, only the image downloads run on the request thread.
+async fn compile_row(
+    templates: Arc<Vec<Template>>,
+    map: PDFillerMap,
+    xfa: Option<XfaMode>,
+) -> Result<RowDocuments, String> {
+    let loading = templates.clone();
+    let loaded = web::block(move || {
+        loading
+            .iter()
+            .map(|template| load_template(&template.buffer))
+            .collect::<Result<Vec<_>, String>>()
+    })
+    .await
+    .map_err(|e| format!("{:?}", e))??;
+
+    let mut images = Vec::new();
+    for loaded in loaded.iter() {
+        images.push(match loaded {
+            LoadedTemplate::Form(form) => form::get_form_images(&map, form)
+                .await
+                .map_err(|e| format!("Error during document filling: {:?}", e))?,
+            LoadedTemplate::Document(_) => form::FormImages::new(),
+        });
+    }
+
+    web::block(move || {
+        templates
+            .iter()
+            .zip(loaded)
+            .zip(images)
+            .map(|((template, loaded), images)| {
+                let pdf_document = match loaded {
+                    LoadedTemplate::Form(form) => {
+                        let mut form = form::fill_form_with_images(&map, form, &images, None)
+                            .map_err(|e| format!("Error during document filling: {:?}", e))?;
+                        xfa::apply(&mut form.document, &map, xfa).map_err(|e| e.to_string())?;
+
+                        form.document
+                    }
+                    LoadedTemplate::Document(pdf_document) => pdf_document,
+                };
+
+                Ok((template.document.clone(), pdf_document))
+            })
+            .collect::<Result<RowDocuments, String>>()
+    })
+    .await
+    .map_err(|e| format!("{:?}", e))?
+}
+
+fn load_template(buffer: &[u8]) -> Result<LoadedTemplate, String> {
+    match Form::load_from(Cursor::new(buffer)) {
+        Ok(form) => Ok(LoadedTemplate::Form(form)),
+        Err(LoadError::LopdfError(Error::DictKey)) => PdfDocument::load_mem(buffer)
+            .map(LoadedTemplate::Document)
+            .map_err(|e| format!("Error {:?} loading a PDF file", e)),
+        Err(e) => Err(format!("Error {:?} loading a PDF file", e)),
+    }
+}
+
+/// Sends every row once it's compiled, only the rows being compiled are kept in memory.
+async fn write_zip(
+    templates: Vec<Template>,
+    rows: Vec<BatchRow>,
+    options: &ExportOptions,
+    concurrency: usize,
+    sender: &Sender<ExportChunk>,
+) -> Result<(), String> {
+    let file_options = compiler::get_zip_file_options(options);
+
+    let ready = Rc::new(RefCell::new(Vec::new()));
+    let mut zip = zip::ZipWriter::new(compiler::ChunkWriter::new(ready.clone()));
+    zip.set_flush_on_finish_file(true);
+
+    let mut entry_names = HashSet::new();
+    entry_names.insert(ERRORS_ENTRY_NAME.to_string());
+    let mut errors = Vec::new();
+
+    let mut compiled = compile_rows(templates, rows, options.xfa, concurrency);
+    while let Some((row, key, result)) = compiled.next().await {
+        let buffer = match result {
+            Ok(documents) => export_row(&key, documents, options).await,
+            Err(error) => Err(error),
+        };
+
+        match buffer {
+            Ok(buffer) => {
+                let entry_name =
+                    compiler::get_unique_name(format!("{}.pdf", key), &mut entry_names);
+                zip.start_file(entry_name, file_options)
+                    .map_err(|e| format!("{:#?}", e))?;
+
+                // Starting an entry finishes the previous one, its bytes are ready to be sent
+                compiler::send_ready_chunk(&ready, sender).await?;
+
+                zip.write_all(&buffer).map_err(|e| format!("{:#?}", e))?;
+            }
+            Err(error) => errors.push(RowError { row, key, error }),
+        }
+    }
+
+    if !errors.is_empty() {
+        let report = serde_json::to_vec_pretty(&errors).map_err(|e| format!("{:#?}", e))?;
+
+        zip.start_file(ERRORS_ENTRY_NAME, file_options)
+            .map_err(|e| format!("{:#?}", e))?;
+        zip.write_all(&report).map_err(|e| format!("{:#?}", e))?;
+    }
+
+    let mut writer = zip.finish().map_err(|e| format!("{:#?}", e))?;
+    writer.flush().map_err(|e| format!("{:#?}", e))?;
+
+    compiler::send_ready_chunk(&ready, sender).await
+}
+
+/// Merges the documents of a row and applies the export options to it.
+async fn export_row(
+    key: &str,
+    mut documents: RowDocuments,
+    options: &ExportOptions,
+) -> Result<Vec<u8>, String> {
+    let (mut document, mut page_map) = if documents.len() == 1 {
+        let (document, pdf_document) = documents.remove(0);
+        let page_map = vec![DocumentPages::new(&document, &pdf_document)];
+
+        (pdf_document, page_map)
+    } else {
+        let documents_objects = processor::get_loaded_containers(documents);
+        match processor::process_documents(&documents_objects) {
+            Some(document) => (document, documents_objects.documents),
+            None => return Err("Error decoding the PDFs files.".into()),
+        }
+    };
+    for pages in page_map.iter_mut() {
+        pages.title = key.to_string();
+    }
+
+    match export_batch_document(&mut document, page_map, options).await {
+        Ok(content) => content.body.into_bytes().await,
+        Err(ExportCompilerError::GenericError(message)) => Err(message),
+    }
+}
+
+async fn export_batch_document(
+    document: &mut PdfDocument,
+    page_map: Vec<DocumentPages>,
+    options: &ExportOptions,
+) -> ExportCompilerResult<ExportedContent> {
+    let report = compiler::apply_export_options(document, page_map, options).await?;
+
+    compiler::export_document(document, report, options).await
+}
+
+fn get_maps(values: Vec<Value>) -> Result<Vec<PDFillerMap>, BatchError> {
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| match value {
+            Value::Object(map) => Ok(map.into_iter().collect()),
+            _ => Err(BatchError::NotAnObject(index + 1)),
+        })
+        .collect()
+}
+
+/// A JSON object on every line, empty lines are skipped.
+fn parse_ndjson(body: &str) -> Result<Vec<PDFillerMap>, BatchError> {
+    let mut maps = Vec::new();
+    for (index, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<Value>(line) {
+            Ok(Value::Object(map)) => maps.push(map.into_iter().collect()),
+            Ok(_) => return Err(BatchError::NotAnObject(maps.len() + 1)),
+            Err(e) => return Err(BatchError::Line(index + 1, e)),
+        }
+    }
+
+    Ok(maps)
+}
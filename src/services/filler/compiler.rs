@@ -1,22 +1,42 @@
-use std::collections::HashMap;
-use std::io::{Cursor, SeekFrom};
-use std::io::{Seek, Write};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Cursor, SeekFrom};
+use std::io::{Read, Seek, Write};
+use std::mem;
+use std::rc::Rc;
 
+use actix_web::rt;
+use async_std::channel::{self, Receiver, Sender};
 use async_std::sync::Arc;
+use chrono::Utc;
+use log::{error, warn};
 
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use pdf_forms::LoadError;
+use openssl::sha;
+
+use pdf_forms::{Form, LoadError};
 
 use lopdf::{Document as PdfDocument, Error};
 
-use zip::write::FileOptions;
+use zip::write::{FileOptions, SimpleFileOptions};
+use zip::{AesMode, CompressionMethod};
 
+use crate::config::RenderConfig;
 use crate::file::{FileError, FileProvider};
 use crate::mongo::models::document::Document;
+use crate::services::filler::cover::{self, CoverOptions};
 use crate::services::filler::form;
-use crate::services::filler::form::FillingError;
-use crate::services::filler::processor;
+use crate::services::filler::form::{FillReport, FillingError};
+use crate::services::filler::metadata::{self, MetadataOptions};
+use crate::services::filler::pdfa::{self, ConformanceReport, OutputProfile};
+use crate::services::filler::processor::{self, DocumentPages};
+use crate::services::filler::render::{self, ImageFormat, RenderOptions};
+use crate::services::filler::security::{self, EncryptionOptions};
+use crate::services::filler::signature::{self, SignatureOptions, Signer};
+use crate::services::filler::stamp::{self, StampOptions};
+use crate::services::filler::xfa::{self, XfaMode};
 
 pub type PDFillerMap = HashMap<String, Value>;
 
@@ -29,33 +49,414 @@ pub enum HandlerCompilerError {
 
 pub type ExportCompilerResult<T> = Result<T, ExportCompilerError>;
 
+/// Part of a streamed export, an error aborts the response.
+pub type ExportChunk = Result<Vec<u8>, String>;
+
+const DEFAULT_FILENAME: &str = "{token}";
+const DEFAULT_ENTRY_NAME: &str = "document.pdf";
+const EXPORT_EXTENSIONS: [&str; 5] = ["pdf", "zip", "png", "jpg", "jpeg"];
+
+/// Chunks waiting to be sent, the export is paused when the client is slower than the storage.
+pub const EXPORT_CHANNEL_CAPACITY: usize = 4;
+
 pub enum ExportCompilerError {
     GenericError(String),
 }
 
+#[derive(Default, Deserialize)]
+pub struct ExportOptions {
+    pub stamp: Option<StampOptions>,
+    pub cover: Option<CoverOptions>,
+    pub metadata: Option<MetadataOptions>,
+    pub encryption: Option<EncryptionOptions>,
+    pub output_profile: Option<OutputProfile>,
+    pub signature: Option<SignatureOptions>,
+    /// Handling of XFA forms, applied when compiling
+    pub xfa: Option<XfaMode>,
+    /// Used when pages are exported as images
+    pub render: Option<RenderOptions>,
+    /// Name of the exported file, `{token}` and `{date}` are replaced and the extension follows
+    /// the exported type
+    pub filename: Option<String>,
+    /// Loaded by the handlers from the signature options, see `signature::get_signer`
+    #[serde(skip)]
+    pub signer: Option<Signer>,
+}
+
+impl ExportOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.output_profile.is_some() && self.encrypts_pdf() {
+            return Err("PDF/A documents can't be encrypted".into());
+        }
+
+        // PDF/A requires every font to be embedded, the standard fonts these add aren't
+        if self.output_profile.is_some() {
+            if self.stamp.as_ref().is_some_and(|stamp| !stamp.is_empty()) {
+                return Err("PDF/A documents can't be stamped".into());
+            }
+            if self
+                .cover
+                .as_ref()
+                .is_some_and(|cover| cover.toc != Some(false))
+            {
+                return Err("PDF/A documents can't have a table of contents".into());
+            }
+            if self
+                .signature
+                .as_ref()
+                .is_some_and(|signature| signature.appearance.is_some())
+            {
+                return Err("PDF/A documents can't have a visible signature".into());
+            }
+        }
+
+        if self.signature.is_some() && self.encrypts_pdf() {
+            return Err("Signed documents can't be encrypted".into());
+        }
+
+        if let Some(ref metadata) = self.metadata {
+            metadata.validate()?;
+        }
+
+        if let Some(ref render) = self.render {
+            if self.encrypts_pdf() {
+                return Err("Rendered documents can't be encrypted".into());
+            }
+
+            render.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Renders the `filename` template without the extension, path separators and control
+    /// characters are replaced.
+    pub fn get_filename(&self, token: &str) -> String {
+        let filename = self
+            .filename
+            .as_deref()
+            .unwrap_or(DEFAULT_FILENAME)
+            .replace("{token}", token)
+            .replace("{date}", &Utc::now().format("%Y-%m-%d").to_string())
+            .chars()
+            .map(|c| {
+                if c == '/' || c == '\\' || c.is_control() {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect::<String>();
+
+        let stem = match filename.rsplit_once('.') {
+            Some((stem, extension))
+                if EXPORT_EXTENSIONS.contains(&extension.to_lowercase().as_str()) =>
+            {
+                stem
+            }
+            _ => filename.as_str(),
+        };
+
+        if stem.trim().is_empty() {
+            token.to_string()
+        } else {
+            stem.to_string()
+        }
+    }
+
+    fn encrypts_pdf(&self) -> bool {
+        self.encryption
+            .as_ref()
+            .map(EncryptionOptions::encrypts_pdf)
+            .unwrap_or(false)
+    }
+
+    /// Whether the merged document has to be loaded as a whole before being written.
+    fn processes_document(&self) -> bool {
+        self.stamp.is_some()
+            || self.cover.is_some()
+            || self.metadata.is_some()
+            || self.output_profile.is_some()
+            || self.signature.is_some()
+            || self.encrypts_pdf()
+    }
+}
+
+pub enum ExportBody {
+    Buffer(Vec<u8>),
+    Stream(Receiver<ExportChunk>),
+}
+
+impl ExportBody {
+    /// Waits for the whole streamed body, for exports processed after the merge.
+    pub async fn into_bytes(self) -> Result<Vec<u8>, String> {
+        match self {
+            ExportBody::Buffer(bytes) => Ok(bytes),
+            ExportBody::Stream(receiver) => {
+                let mut bytes = Vec::new();
+                while let Ok(chunk) = receiver.recv().await {
+                    bytes.extend(chunk?);
+                }
+
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// Exported file with the conformance report when an output profile was requested,
+/// `content_type` is set when it's not the accepted one.
+pub struct ExportedContent {
+    pub body: ExportBody,
+    pub report: Option<ConformanceReport>,
+    pub content_type: Option<mime::Mime>,
+}
+
+impl From<Vec<u8>> for ExportedContent {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self {
+            body: ExportBody::Buffer(bytes),
+            report: None,
+            content_type: None,
+        }
+    }
+}
+
+impl From<Receiver<ExportChunk>> for ExportedContent {
+    fn from(receiver: Receiver<ExportChunk>) -> Self {
+        Self {
+            body: ExportBody::Stream(receiver),
+            report: None,
+            content_type: None,
+        }
+    }
+}
+
+/// ZIP output keeping only the bytes of the entry being written, zip seeks back into it to
+/// update the local header and flushes it when the entry is finished.
+pub struct ChunkWriter {
+    buffer: Vec<u8>,
+    position: u64,
+    released: u64,
+    ready: Rc<RefCell<Vec<u8>>>,
+}
+
+impl ChunkWriter {
+    pub fn new(ready: Rc<RefCell<Vec<u8>>>) -> Self {
+        Self {
+            buffer: Vec::new(),
+            position: 0,
+            released: 0,
+            ready,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.released + self.buffer.len() as u64
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = (self.position - self.released) as usize;
+        let overwritten = buf.len().min(self.buffer.len().saturating_sub(start));
+        self.buffer[start..start + overwritten].copy_from_slice(&buf[..overwritten]);
+        self.buffer.extend_from_slice(&buf[overwritten..]);
+        self.position += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let flushed = (self.position - self.released) as usize;
+        self.ready.borrow_mut().extend(self.buffer.drain(..flushed));
+        self.released = self.position;
+
+        Ok(())
+    }
+}
+
+impl Seek for ChunkWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => offset_position(self.end(), offset),
+            SeekFrom::Current(offset) => offset_position(self.position, offset),
+        };
+
+        match position {
+            Some(position) if position >= self.released && position <= self.end() => {
+                self.position = position;
+
+                Ok(position)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot seek outside of the unsent ZIP data",
+            )),
+        }
+    }
+}
+
+impl Read for ChunkWriter {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Streamed ZIP files cannot be read",
+        ))
+    }
+}
+
+fn offset_position(position: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        position.checked_sub(offset.unsigned_abs())
+    } else {
+        position.checked_add(offset as u64)
+    }
+}
+
 pub async fn compile_documents<F: FileProvider + ?Sized>(
     file_type: Arc<Box<F>>,
     map: &PDFillerMap,
     documents: &[Document],
+    xfa: Option<XfaMode>,
+    compilation: &str,
 ) -> HandlerCompilerResult<()> {
     for document in documents.iter() {
-        if let Err(e) = compile_document(file_type.clone(), map, &document).await {
-            return Err(e);
-        }
+        compile_document(file_type.clone(), map, document, xfa, compilation).await?;
     }
 
     Ok(())
 }
 
+/// Fields of a document filled by a dry-run compile.
+#[derive(Serialize)]
+pub struct DryRunReport {
+    pub document: Document,
+    #[serde(flatten)]
+    pub report: FillReport,
+}
+
+/// Fills the documents like `compile_documents` without saving them.
+pub async fn dry_run_documents(
+    map: &PDFillerMap,
+    documents: Vec<Document>,
+    xfa: Option<XfaMode>,
+) -> HandlerCompilerResult<Vec<DryRunReport>> {
+    let mut reports = Vec::new();
+    for document in documents {
+        let report = match form::fields_filler_with_report(map, &document).await {
+            Ok((mut form, mut report)) => match xfa::apply(&mut form.document, map, xfa) {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        report.warn(warning);
+                    }
+
+                    report
+                }
+                Err(e) => {
+                    return Err(HandlerCompilerError::FillingError(FillingError::Xfa(
+                        e.to_string(),
+                    )));
+                }
+            },
+            Err(FillingError::Load(LoadError::LopdfError(Error::DictKey))) => {
+                FillReport::without_form(map)
+            }
+            Err(FillingError::Load(e)) => {
+                return Err(HandlerCompilerError::Error(format!(
+                    "Error {:#?} loading a PDF file, aborted.",
+                    e
+                )));
+            }
+            Err(e) => return Err(HandlerCompilerError::FillingError(e)),
+        };
+
+        reports.push(DryRunReport { document, report });
+    }
+
+    Ok(reports)
+}
+
+/// Hex encoded SHA-256 of the data, keys are sorted so the same data always has the same hash.
+pub fn get_data_hash(map: &PDFillerMap) -> String {
+    let sorted = map.iter().collect::<BTreeMap<_, _>>();
+    let content = serde_json::to_vec(&sorted).unwrap_or_default();
+
+    sha::sha256(&content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Field values of a PDF, documents without a form have none.
+pub fn extract_document(buffer: &[u8]) -> Result<PDFillerMap, LoadError> {
+    match Form::load_from(Cursor::new(buffer)) {
+        Ok(form) => Ok(form::get_form_values(&form)),
+        Err(LoadError::LopdfError(Error::DictKey)) => Ok(PDFillerMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Field values of the stored documents in a single map, the first document having a field sets
+/// its value as the same map fills every document when compiling.
+pub async fn extract_documents<F: FileProvider + ?Sized>(
+    file_type: Arc<Box<F>>,
+    documents: &[Document],
+    compilation: Option<&str>,
+) -> HandlerCompilerResult<PDFillerMap> {
+    let mut map = PDFillerMap::new();
+    for document in documents {
+        let file_path = file_type
+            .get_document_filepath(&document.file, compilation)
+            .ok_or_else(|| HandlerCompilerError::Error("Error getting the PDF file.".into()))?;
+
+        let buffer = file_type.load(&file_path).await.map_err(|e| {
+            HandlerCompilerError::Error(format!("Error {:#?} loading a PDF file, aborted.", e))
+        })?;
+
+        match extract_document(&buffer) {
+            Ok(values) => {
+                for (name, value) in values {
+                    map.entry(name).or_insert(value);
+                }
+            }
+            Err(e) => {
+                return Err(HandlerCompilerError::Error(format!(
+                    "Error {:#?} reading the PDF form, aborted.",
+                    e
+                )));
+            }
+        }
+    }
+
+    Ok(map)
+}
+
 pub async fn compile_document<F: FileProvider + ?Sized>(
     file_type: Arc<Box<F>>,
     map: &PDFillerMap,
     document: &Document,
+    xfa: Option<XfaMode>,
+    compilation: &str,
 ) -> HandlerCompilerResult<()> {
     match form::fields_filler(map, document).await {
         Ok(mut form) => {
+            match xfa::apply(&mut form.document, map, xfa) {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        warn!("{}", warning);
+                    }
+                }
+                Err(e) => {
+                    return Err(HandlerCompilerError::FillingError(FillingError::Xfa(
+                        e.to_string(),
+                    )));
+                }
+            }
+
             if let Some(compiled_filename) =
-                file_type.generate_compiled_filepath(document.file.as_str())
+                file_type.generate_compiled_filepath(document.file.as_str(), compilation)
             {
                 let mut buf = Vec::new();
                 match form.save_to(&mut buf) {
@@ -79,7 +480,7 @@ pub async fn compile_document<F: FileProvider + ?Sized>(
             FillingError::Load(e) => match e {
                 LoadError::LopdfError(Error::DictKey) => {
                     if let Some(compiled_filename) =
-                        file_type.generate_compiled_filepath(&document.file)
+                        file_type.generate_compiled_filepath(&document.file, compilation)
                     {
                         match crystalsoft_utils::read_file_buf(&document.file) {
                             Ok(buf) => save_compiled_file(file_type, compiled_filename, buf).await,
@@ -107,78 +508,177 @@ pub async fn compile_document<F: FileProvider + ?Sized>(
     }
 }
 
-pub async fn zip_documents<F: FileProvider + ?Sized>(
+pub async fn zip_documents<F: FileProvider + ?Sized + 'static>(
     file_type: Arc<Box<F>>,
     documents: Vec<Document>,
-    compiled: bool,
-) -> ExportCompilerResult<Vec<u8>> {
-    let buf = Vec::new();
-    let w = std::io::Cursor::new(buf);
-    let mut zip = zip::ZipWriter::new(w);
+    compilation: Option<String>,
+    options: ExportOptions,
+) -> ExportCompilerResult<ExportedContent> {
+    if options.output_profile.is_some() {
+        return Err(ExportCompilerError::GenericError(
+            "Output profiles are only available for PDF exports.".into(),
+        ));
+    }
+
+    let (sender, receiver) = channel::bounded(EXPORT_CHANNEL_CAPACITY);
+    rt::spawn(async move {
+        if let Err(e) = write_zip(
+            file_type,
+            documents,
+            compilation.as_deref(),
+            &options,
+            &sender,
+        )
+        .await
+        {
+            error!("Error making a ZIP file: {}", e);
+
+            let _ = sender.send(Err(e)).await;
+        }
+    });
 
+    Ok(receiver.into())
+}
+
+/// Sends every ZIP entry once it's written, only one document is kept in memory.
+async fn write_zip<F: FileProvider + ?Sized>(
+    file_type: Arc<Box<F>>,
+    documents: Vec<Document>,
+    compilation: Option<&str>,
+    options: &ExportOptions,
+    sender: &Sender<ExportChunk>,
+) -> Result<(), String> {
+    let file_options = get_zip_file_options(options);
+
+    let ready = Rc::new(RefCell::new(Vec::new()));
+    let mut zip = zip::ZipWriter::new(ChunkWriter::new(ready.clone()));
+    zip.set_flush_on_finish_file(true);
+
+    let mut entry_names = HashSet::new();
     for document in documents {
-        if let Some(ref file_name) = crystalsoft_utils::get_filename(&document.file) {
-            match zip.start_file(file_name, FileOptions::default()) {
-                Ok(_) => match if compiled {
-                    file_type.generate_compiled_filepath(&document.file)
-                } else {
-                    Some(document.file)
-                } {
-                    Some(ref file_path) => match file_type.load(file_path).await {
-                        Ok(buffer) => match zip.write_all(&buffer) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                return Err(ExportCompilerError::GenericError(format!(
-                                    "Error making a ZIP file: {:#?}",
-                                    e
-                                )));
-                            }
-                        },
-                        Err(e) => {
-                            return Err(ExportCompilerError::GenericError(format!(
-                                "Error making a ZIP file: {:#?}",
-                                e
-                            )));
-                        }
-                    },
-                    None => {
-                        return Err(ExportCompilerError::GenericError(
-                            "Error making a ZIP file.".into(),
-                        ));
-                    }
-                },
-                Err(e) => {
-                    return Err(ExportCompilerError::GenericError(format!(
-                        "Error making a ZIP file: {:#?}",
-                        e
-                    )));
-                }
-            }
+        let entry_name = get_entry_name(&document, &mut entry_names);
+        zip.start_file(entry_name, file_options)
+            .map_err(|e| format!("{:#?}", e))?;
+
+        // Starting an entry finishes the previous one, its bytes are ready to be sent
+        send_ready_chunk(&ready, sender).await?;
+
+        let file_path = file_type
+            .get_document_filepath(&document.file, compilation)
+            .ok_or_else(|| String::from("Error getting the PDF file"))?;
+
+        let buffer = file_type
+            .load(&file_path)
+            .await
+            .map_err(|e| format!("{:#?}", e))?;
+        let buffer = sign_buffer(buffer, options).await?;
+        let buffer = encrypt_buffer(buffer, options).map_err(|e| format!("{}", e))?;
+
+        zip.write_all(&buffer).map_err(|e| format!("{:#?}", e))?;
+    }
+
+    let mut writer = zip.finish().map_err(|e| format!("{:#?}", e))?;
+    writer.flush().map_err(|e| format!("{:#?}", e))?;
+
+    send_ready_chunk(&ready, sender).await
+}
+
+/// ZIP entries are encrypted with the `zip_password` if any.
+pub fn get_zip_file_options(options: &ExportOptions) -> FileOptions<'_, ()> {
+    match options
+        .encryption
+        .as_ref()
+        .and_then(|encryption| encryption.zip_password.as_ref())
+    {
+        Some(password) => {
+            SimpleFileOptions::default().with_aes_encryption(AesMode::Aes256, password)
         }
+        None => SimpleFileOptions::default(),
+    }
+}
+
+/// Names the entry after the uploaded file.
+fn get_entry_name(document: &Document, entry_names: &mut HashSet<String>) -> String {
+    let title = document.title();
+    let title = if title.is_empty() {
+        DEFAULT_ENTRY_NAME.to_string()
+    } else {
+        title
+    };
+
+    get_unique_name(title, entry_names)
+}
+
+/// The name itself or `name (2).pdf` and so on when it's already used.
+pub fn get_unique_name(title: String, entry_names: &mut HashSet<String>) -> String {
+    let (stem, extension) = match title.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (title.as_str(), String::new()),
+    };
+
+    let mut entry_name = title.clone();
+    let mut counter = 1;
+    // Entry names are compared case insensitively, as most file systems do when extracting
+    while !entry_names.insert(entry_name.to_lowercase()) {
+        counter += 1;
+        entry_name = format!("{} ({}){}", stem, counter, extension);
     }
 
-    let zip_result = zip.finish();
-    let mut bytes = zip_result.unwrap_or_default();
-    let _ = bytes.seek(SeekFrom::Start(0));
+    entry_name
+}
 
-    Ok(bytes.get_ref().to_vec())
+pub async fn send_ready_chunk(
+    ready: &Rc<RefCell<Vec<u8>>>,
+    sender: &Sender<ExportChunk>,
+) -> Result<(), String> {
+    let chunk = mem::take(&mut *ready.borrow_mut());
+    if chunk.is_empty() {
+        return Ok(());
+    }
+
+    // Fails when the request was dropped, there's nobody left to write to
+    sender
+        .send(Ok(chunk))
+        .await
+        .map_err(|e| format!("{:#?}", e))
 }
 
-pub async fn merge_documents<F: FileProvider + ?Sized>(
+pub async fn merge_documents<F: FileProvider + ?Sized + 'static>(
     file_type: Arc<Box<F>>,
     mut documents: Vec<Document>,
-    compiled: bool,
-) -> ExportCompilerResult<Vec<u8>> {
-    if documents.len() == 1 {
+    compilation: Option<String>,
+    options: ExportOptions,
+) -> ExportCompilerResult<ExportedContent> {
+    let options = &options;
+    if documents.len() > 1 && !options.processes_document() {
+        // Plain merges are written while the documents are loaded
+        let (sender, receiver) = channel::bounded(EXPORT_CHANNEL_CAPACITY);
+        rt::spawn(async move {
+            if let Err(e) =
+                processor::stream_documents(file_type, documents, compilation.as_deref(), &sender)
+                    .await
+            {
+                error!("Error merging the PDFs files: {}", e);
+
+                let _ = sender.send(Err(e)).await;
+            }
+        });
+
+        Ok(receiver.into())
+    } else if documents.len() == 1 {
         let document = documents.pop().unwrap();
-        if let Some(ref file_path) = if compiled {
-            file_type.generate_compiled_filepath(&document.file)
-        } else {
-            Some(document.file)
-        } {
+        if let Some(ref file_path) =
+            file_type.get_document_filepath(&document.file, compilation.as_deref())
+        {
             match file_type.load(file_path).await {
                 Ok(buffer) => match PdfDocument::load_mem(&buffer) {
-                    Ok(mut document) => get_document_buffer(&mut document),
+                    Ok(mut pdf_document) => {
+                        let page_map = vec![DocumentPages::new(&document, &pdf_document)];
+                        let report =
+                            apply_export_options(&mut pdf_document, page_map, options).await?;
+
+                        export_document(&mut pdf_document, report, options).await
+                    }
                     Err(e) => {
                         sentry::capture_error(&e);
 
@@ -203,13 +703,17 @@ pub async fn merge_documents<F: FileProvider + ?Sized>(
             ))
         }
     } else {
-        let documents_objects = processor::get_documents_containers(file_type, documents, compiled);
+        let documents_objects =
+            processor::get_documents_containers(file_type, documents, compilation.as_deref()).await;
         if documents_objects.pages.is_empty() || documents_objects.objects.is_empty() {
             Err(ExportCompilerError::GenericError(
                 "Cannot extract PDFs documents".into(),
             ))
-        } else if let Some(mut document) = processor::process_documents(documents_objects) {
-            get_document_buffer(&mut document)
+        } else if let Some(mut document) = processor::process_documents(&documents_objects) {
+            let report =
+                apply_export_options(&mut document, documents_objects.documents, options).await?;
+
+            export_document(&mut document, report, options).await
         } else {
             Err(ExportCompilerError::GenericError(
                 "Error decoding the PDFs files.".to_string(),
@@ -218,6 +722,197 @@ pub async fn merge_documents<F: FileProvider + ?Sized>(
     }
 }
 
+/// Renders the merged document, a single page is sent as an image and more pages as a ZIP of
+/// images encrypted with the `zip_password` if any.
+pub async fn render_documents<F: FileProvider + ?Sized + 'static>(
+    file_type: Arc<Box<F>>,
+    documents: Vec<Document>,
+    compilation: Option<String>,
+    options: ExportOptions,
+    format: ImageFormat,
+    config: Option<&RenderConfig>,
+) -> ExportCompilerResult<ExportedContent> {
+    if options.encrypts_pdf() {
+        return Err(ExportCompilerError::GenericError(
+            "Rendered documents can't be encrypted.".into(),
+        ));
+    }
+
+    let render_options = options.render.clone().unwrap_or_default();
+    let zip_password = options
+        .encryption
+        .as_ref()
+        .and_then(|encryption| encryption.zip_password.clone());
+
+    let content = merge_documents(file_type, documents, compilation, options).await?;
+    let buffer = content
+        .body
+        .into_bytes()
+        .await
+        .map_err(ExportCompilerError::GenericError)?;
+
+    let mut pages = render::render_document(buffer, format, &render_options, config)
+        .await
+        .map_err(|e| ExportCompilerError::GenericError(format!("{}.", e)))?;
+
+    if pages.len() == 1 {
+        return Ok(ExportedContent {
+            body: ExportBody::Buffer(pages.remove(0)),
+            report: content.report,
+            content_type: Some(format.mime()),
+        });
+    }
+
+    // Images are already compressed
+    let file_options = match zip_password {
+        Some(ref password) => SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .with_aes_encryption(AesMode::Aes256, password),
+        None => SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+    };
+
+    let first_page = render_options.first_page.unwrap_or(1) as usize;
+    let width = (first_page + pages.len() - 1).to_string().len();
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for (index, page) in pages.iter().enumerate() {
+        let entry_name = format!(
+            "page-{:0width$}.{}",
+            first_page + index,
+            format.extension(),
+            width = width
+        );
+
+        zip.start_file(entry_name, file_options)
+            .and_then(|_| zip.write_all(page).map_err(zip::result::ZipError::from))
+            .map_err(|e| {
+                ExportCompilerError::GenericError(format!("Error making a ZIP file: {:#?}", e))
+            })?;
+    }
+
+    match zip.finish() {
+        Ok(cursor) => Ok(ExportedContent {
+            body: ExportBody::Buffer(cursor.into_inner()),
+            report: content.report,
+            content_type: Some(mime::APPLICATION_OCTET_STREAM),
+        }),
+        Err(e) => Err(ExportCompilerError::GenericError(format!(
+            "Error making a ZIP file: {:#?}",
+            e
+        ))),
+    }
+}
+
+pub async fn apply_export_options(
+    document: &mut PdfDocument,
+    mut page_map: Vec<DocumentPages>,
+    options: &ExportOptions,
+) -> ExportCompilerResult<Option<ConformanceReport>> {
+    processor::update_page_map(document, &mut page_map);
+
+    if let Some(ref cover) = options.cover {
+        cover::add_front_matter(document, &mut page_map, cover)
+            .await
+            .map_err(|e| {
+                ExportCompilerError::GenericError(format!(
+                    "Error generating the cover pages: {}",
+                    e
+                ))
+            })?;
+    }
+
+    if let Some(ref stamp) = options.stamp {
+        stamp::stamp_pages(document, &page_map, stamp).map_err(|e| {
+            ExportCompilerError::GenericError(format!("Error stamping the PDF pages: {:#?}", e))
+        })?;
+    }
+
+    match options.output_profile {
+        Some(OutputProfile::PdfA2b) => {
+            let report = pdfa::convert(
+                document,
+                &page_map,
+                &options.metadata.clone().unwrap_or_default(),
+            )
+            .map_err(|e| {
+                ExportCompilerError::GenericError(format!(
+                    "Error converting the PDF to PDF/A: {:#?}",
+                    e
+                ))
+            })?;
+
+            if !report.conformant {
+                warn!("PDF/A conversion issues: {:?}", report.issues);
+            }
+
+            Ok(Some(report))
+        }
+        None => {
+            if let Some(ref metadata) = options.metadata {
+                metadata::set_metadata(document, &page_map, metadata).map_err(|e| {
+                    ExportCompilerError::GenericError(format!(
+                        "Error setting the PDF metadata: {:#?}",
+                        e
+                    ))
+                })?;
+            }
+
+            Ok(None)
+        }
+    }
+}
+
+pub async fn export_document(
+    document: &mut PdfDocument,
+    report: Option<ConformanceReport>,
+    options: &ExportOptions,
+) -> ExportCompilerResult<ExportedContent> {
+    let mut bytes = get_document_buffer(document)?;
+
+    if report.is_some() {
+        bytes = pdfa::write_binary_header(bytes).map_err(|e| {
+            ExportCompilerError::GenericError(format!("Error writing the PDF/A file: {}", e))
+        })?;
+    }
+
+    // Signing comes last, any later change would invalidate the signature
+    bytes = sign_buffer(bytes, options)
+        .await
+        .map_err(ExportCompilerError::GenericError)?;
+
+    Ok(ExportedContent {
+        body: ExportBody::Buffer(encrypt_export(bytes, options)?),
+        report,
+        content_type: None,
+    })
+}
+
+fn encrypt_export(buffer: Vec<u8>, options: &ExportOptions) -> ExportCompilerResult<Vec<u8>> {
+    encrypt_buffer(buffer, options)
+        .map_err(|e| ExportCompilerError::GenericError(format!("Error encrypting the PDF: {}", e)))
+}
+
+fn encrypt_buffer(
+    buffer: Vec<u8>,
+    options: &ExportOptions,
+) -> Result<Vec<u8>, security::SecurityError> {
+    match options.encryption {
+        Some(ref encryption) if encryption.encrypts_pdf() => {
+            security::encrypt_document(buffer, encryption)
+        }
+        _ => Ok(buffer),
+    }
+}
+
+async fn sign_buffer(buffer: Vec<u8>, options: &ExportOptions) -> Result<Vec<u8>, String> {
+    match (options.signature.as_ref(), options.signer.as_ref()) {
+        (Some(signature), Some(signer)) => signature::sign_document(buffer, signer, signature)
+            .await
+            .map_err(|e| format!("Error signing the PDF: {}", e)),
+        _ => Ok(buffer),
+    }
+}
+
 fn get_document_buffer(document: &mut PdfDocument) -> ExportCompilerResult<Vec<u8>> {
     let buf = Vec::<u8>::new();
     let mut cursor = Cursor::new(buf);
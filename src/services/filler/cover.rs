@@ -0,0 +1,416 @@
+use std::fmt;
+use std::io::Cursor;
+
+use chrono::Utc;
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document as PdfDocument, Object, ObjectId, Stream, StringFormat};
+use pdf_forms::Form;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::services::filler::compiler::PDFillerMap;
+use crate::services::filler::form::{self, FillingError};
+use crate::services::filler::processor::DocumentPages;
+use crate::services::filler::stamp;
+use crate::utils;
+
+const TOC_FONT: &str = "Helvetica";
+const TOC_TITLE_FONT: &str = "Helvetica-Bold";
+const TOC_FONT_RESOURCE: &str = "PFToc1";
+const TOC_TITLE_FONT_RESOURCE: &str = "PFToc2";
+const TOC_FONT_ENCODING: &str = "WinAnsiEncoding";
+const TOC_FONT_SIZE: f64 = 11.0;
+const TOC_TITLE_FONT_SIZE: f64 = 18.0;
+const TOC_LINE_HEIGHT: f64 = 20.0;
+const TOC_MARGIN: f64 = 56.0;
+const TOC_DATE_COLUMN_WIDTH: f64 = 140.0;
+const TOC_DEFAULT_TITLE: &str = "Table of Contents";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CoverOptions {
+    /// Title printed on the table of contents and passed to the cover template as `title`
+    pub title: Option<String>,
+    /// Generates the table of contents, enabled by default
+    pub toc: Option<bool>,
+    /// Id of a document uploaded for the token, its fillable fields are filled and it's used as
+    /// the first page
+    pub template: Option<String>,
+    /// Values for the cover template fields
+    pub data: Option<PDFillerMap>,
+    /// Loaded by the handlers from `template`, see `filler::get_export_options`
+    #[serde(skip)]
+    pub template_buffer: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub enum CoverError {
+    Filling(FillingError),
+    Pdf(lopdf::Error),
+}
+
+impl fmt::Display for CoverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverError::Filling(e) => {
+                write!(f, "{:#?}", e)
+            }
+            CoverError::Pdf(e) => {
+                write!(f, "{:#?}", e)
+            }
+        }
+    }
+}
+
+impl From<lopdf::Error> for CoverError {
+    fn from(e: lopdf::Error) -> Self {
+        CoverError::Pdf(e)
+    }
+}
+
+struct TocEntry {
+    title: String,
+    date: String,
+    page: usize,
+    page_id: ObjectId,
+}
+
+/// Prepends the cover page and the table of contents to the document, the generated pages are
+/// added to the page map as the first entry.
+pub async fn add_front_matter(
+    document: &mut PdfDocument,
+    page_map: &mut Vec<DocumentPages>,
+    options: &CoverOptions,
+) -> Result<(), CoverError> {
+    let pages_id = document
+        .catalog()?
+        .get(b"Pages")
+        .and_then(Object::as_reference)?;
+
+    let pages_count = page_map
+        .iter()
+        .map(|document_pages| document_pages.pages.len())
+        .sum::<usize>();
+
+    let cover_pages = match options.template_buffer {
+        Some(ref buffer) => {
+            let cover = get_cover(buffer, options, page_map.len(), pages_count).await?;
+
+            import_pages(document, cover, pages_id)?
+        }
+        None => Vec::new(),
+    };
+
+    let toc_pages = if options.toc.unwrap_or(true) {
+        let page_box = page_map
+            .first()
+            .and_then(|document_pages| document_pages.pages.first())
+            .map(|page_id| utils::get_page_box(document, *page_id))
+            .unwrap_or_else(|| utils::get_page_box(document, pages_id));
+
+        let entries_per_page =
+            (((page_box.3 - page_box.1) - TOC_MARGIN * 2.0 - TOC_TITLE_FONT_SIZE * 2.0)
+                / TOC_LINE_HEIGHT)
+                .max(1.0) as usize;
+
+        let toc_pages_count = page_map.len().div_ceil(entries_per_page).max(1);
+
+        let mut page = cover_pages.len() + toc_pages_count + 1;
+        let entries = page_map
+            .iter()
+            .filter_map(|document_pages| {
+                let entry = document_pages.pages.first().map(|page_id| TocEntry {
+                    title: document_pages.title.clone(),
+                    date: document_pages.date.format("%Y-%m-%d").to_string(),
+                    page,
+                    page_id: *page_id,
+                });
+
+                page += document_pages.pages.len();
+
+                entry
+            })
+            .collect::<Vec<_>>();
+
+        add_toc_pages(
+            document,
+            pages_id,
+            options.title.as_deref().unwrap_or(TOC_DEFAULT_TITLE),
+            &entries,
+            entries_per_page,
+            page_box,
+        )?
+    } else {
+        Vec::new()
+    };
+
+    let front_pages = [cover_pages, toc_pages].concat();
+    if front_pages.is_empty() {
+        return Ok(());
+    }
+
+    let pages = document.get_object_mut(pages_id)?.as_dict_mut()?;
+    let mut kids = pages
+        .get(b"Kids")
+        .and_then(Object::as_array)
+        .cloned()
+        .unwrap_or_default();
+    let count = pages.get(b"Count").and_then(Object::as_i64).unwrap_or(0);
+
+    kids.splice(
+        0..0,
+        front_pages
+            .iter()
+            .map(|page_id| Object::Reference(*page_id)),
+    );
+    pages.set("Kids", kids);
+    pages.set("Count", count + front_pages.len() as i64);
+
+    page_map.insert(
+        0,
+        DocumentPages {
+            title: options
+                .title
+                .clone()
+                .unwrap_or_else(|| TOC_DEFAULT_TITLE.into()),
+            date: Utc::now(),
+            pages: front_pages,
+            info: Dictionary::new(),
+        },
+    );
+
+    Ok(())
+}
+
+async fn get_cover(
+    buffer: &[u8],
+    options: &CoverOptions,
+    documents_count: usize,
+    pages_count: usize,
+) -> Result<PdfDocument, CoverError> {
+    let mut map = PDFillerMap::new();
+    if let Some(ref title) = options.title {
+        map.insert("title".into(), Value::String(title.clone()));
+    }
+    map.insert(
+        "date".into(),
+        Value::String(Utc::now().format("%Y-%m-%d").to_string()),
+    );
+    map.insert(
+        "documents_count".into(),
+        Value::String(documents_count.to_string()),
+    );
+    map.insert("pages_count".into(), Value::String(pages_count.to_string()));
+    if let Some(ref data) = options.data {
+        map.extend(data.clone());
+    }
+
+    match Form::load_from(Cursor::new(buffer)) {
+        Ok(form) => form::fill_form(&map, form, None)
+            .await
+            .map(|form| form.document)
+            .map_err(CoverError::Filling),
+        // Templates without a form are used as they are
+        Err(_) => Ok(PdfDocument::load_mem(buffer)?),
+    }
+}
+
+/// Moves the pages of another document into this one, inherited attributes are copied onto
+/// every page as the source page tree is discarded.
+fn import_pages(
+    document: &mut PdfDocument,
+    mut other: PdfDocument,
+    pages_id: ObjectId,
+) -> Result<Vec<ObjectId>, CoverError> {
+    other.renumber_objects_with(document.max_id + 1);
+
+    let page_ids = other.get_pages().into_values().collect::<Vec<_>>();
+    for page_id in page_ids.iter() {
+        let inherited = [&b"Resources"[..], b"MediaBox", b"CropBox", b"Rotate"]
+            .iter()
+            .filter_map(|key| {
+                utils::get_inherited_attribute(&other, *page_id, key)
+                    .map(|object| (key.to_vec(), object.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        let page = other.get_object_mut(*page_id)?.as_dict_mut()?;
+        for (key, object) in inherited {
+            if !page.has(&key) {
+                page.set(key, object);
+            }
+        }
+        page.set("Parent", pages_id);
+    }
+
+    for (object_id, object) in other.objects.into_iter() {
+        match object.type_name().unwrap_or("") {
+            "Catalog" | "Pages" | "Outlines" | "Outline" => {}
+            _ => {
+                document.objects.insert(object_id, object);
+            }
+        }
+    }
+
+    document.max_id = document.max_id.max(other.max_id);
+
+    Ok(page_ids)
+}
+
+fn add_toc_pages(
+    document: &mut PdfDocument,
+    pages_id: ObjectId,
+    title: &str,
+    entries: &[TocEntry],
+    entries_per_page: usize,
+    page_box: (f64, f64, f64, f64),
+) -> Result<Vec<ObjectId>, CoverError> {
+    let (x1, y1, x2, y2) = page_box;
+
+    let font_id = add_font(document, TOC_FONT);
+    let title_font_id = add_font(document, TOC_TITLE_FONT);
+
+    let mut fonts = Dictionary::new();
+    fonts.set(TOC_FONT_RESOURCE, font_id);
+    fonts.set(TOC_TITLE_FONT_RESOURCE, title_font_id);
+
+    let mut resources = Dictionary::new();
+    resources.set("Font", fonts);
+    let resources_id = document.add_object(resources);
+
+    let chunks = if entries.is_empty() {
+        vec![entries]
+    } else {
+        entries.chunks(entries_per_page).collect()
+    };
+
+    let mut page_ids = Vec::new();
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let mut operations = Vec::new();
+        let mut annotations = Vec::new();
+
+        let mut y = y2 - TOC_MARGIN - TOC_TITLE_FONT_SIZE;
+        if index == 0 {
+            operations.extend(text(
+                title,
+                TOC_TITLE_FONT_RESOURCE,
+                TOC_TITLE_FONT_SIZE,
+                x1 + TOC_MARGIN,
+                y,
+            ));
+        }
+        y -= TOC_TITLE_FONT_SIZE * 2.0;
+
+        for entry in chunk {
+            let page = entry.page.to_string();
+            let page_x = x2 - TOC_MARGIN - stamp::get_text_width(&page, TOC_FONT, TOC_FONT_SIZE);
+            let date_x = x2 - TOC_MARGIN - TOC_DATE_COLUMN_WIDTH;
+
+            // Titles are truncated so they don't overlap the date column
+            let max_title_chars = ((date_x - x1 - TOC_MARGIN) / (TOC_FONT_SIZE * 0.5)) as usize;
+            let entry_title = if entry.title.chars().count() > max_title_chars {
+                format!(
+                    "{}...",
+                    entry
+                        .title
+                        .chars()
+                        .take(max_title_chars.saturating_sub(3))
+                        .collect::<String>()
+                )
+            } else {
+                entry.title.clone()
+            };
+
+            operations.extend(text(
+                &entry_title,
+                TOC_FONT_RESOURCE,
+                TOC_FONT_SIZE,
+                x1 + TOC_MARGIN,
+                y,
+            ));
+            operations.extend(text(
+                &entry.date,
+                TOC_FONT_RESOURCE,
+                TOC_FONT_SIZE,
+                date_x,
+                y,
+            ));
+            operations.extend(text(&page, TOC_FONT_RESOURCE, TOC_FONT_SIZE, page_x, y));
+
+            let mut link = Dictionary::new();
+            link.set("Type", Object::Name(b"Annot".to_vec()));
+            link.set("Subtype", Object::Name(b"Link".to_vec()));
+            link.set(
+                "Rect",
+                vec![
+                    (x1 + TOC_MARGIN).into(),
+                    (y - 4.0).into(),
+                    (x2 - TOC_MARGIN).into(),
+                    (y + TOC_FONT_SIZE).into(),
+                ],
+            );
+            link.set("Border", vec![0.into(), 0.into(), 0.into()]);
+            link.set(
+                "Dest",
+                vec![
+                    Object::Reference(entry.page_id),
+                    Object::Name(b"Fit".to_vec()),
+                ],
+            );
+            annotations.push(Object::Reference(document.add_object(link)));
+
+            y -= TOC_LINE_HEIGHT;
+        }
+
+        let content_id = document.add_object(Stream::new(
+            Dictionary::new(),
+            Content { operations }.encode()?,
+        ));
+
+        let mut page = Dictionary::new();
+        page.set("Type", Object::Name(b"Page".to_vec()));
+        page.set("Parent", pages_id);
+        page.set("MediaBox", vec![x1.into(), y1.into(), x2.into(), y2.into()]);
+        page.set("Resources", resources_id);
+        page.set("Contents", content_id);
+        page.set("Annots", annotations);
+
+        page_ids.push(document.add_object(page));
+    }
+
+    Ok(page_ids)
+}
+
+fn add_font(document: &mut PdfDocument, font: &str) -> ObjectId {
+    let mut dictionary = Dictionary::new();
+    dictionary.set("Type", Object::Name(b"Font".to_vec()));
+    dictionary.set("Subtype", Object::Name(b"Type1".to_vec()));
+    dictionary.set("BaseFont", Object::Name(font.as_bytes().to_vec()));
+    dictionary.set(
+        "Encoding",
+        Object::Name(TOC_FONT_ENCODING.as_bytes().to_vec()),
+    );
+
+    document.add_object(dictionary)
+}
+
+fn text(value: &str, font_resource: &str, font_size: f64, x: f64, y: f64) -> Vec<Operation> {
+    vec![
+        Operation::new("BT", vec![]),
+        Operation::new(
+            "Tf",
+            vec![
+                Object::Name(font_resource.as_bytes().to_vec()),
+                font_size.into(),
+            ],
+        ),
+        Operation::new("Td", vec![x.into(), y.into()]),
+        Operation::new(
+            "Tj",
+            vec![Object::String(
+                PdfDocument::encode_text(Some(TOC_FONT_ENCODING), value),
+                StringFormat::Literal,
+            )],
+        ),
+        Operation::new("ET", vec![]),
+    ]
+}
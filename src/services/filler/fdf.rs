@@ -0,0 +1,256 @@
+use std::fmt::{Display, Formatter};
+
+use lopdf_security::{Document as SecuredDocument, Object as SecuredObject};
+use serde_json::Value;
+use xml::reader::{EventReader, XmlEvent};
+
+use crate::services::filler::compiler::PDFillerMap;
+use crate::services::filler::metadata;
+use crate::services::filler::validation;
+
+pub const FDF_CONTENT_TYPE: &str = "application/vnd.fdf";
+pub const XFDF_CONTENT_TYPE: &str = "application/vnd.adobe.xfdf";
+
+const FDF_HEADER: &[u8] = b"%FDF-";
+const XFDF_NAMESPACE: &str = "http://ns.adobe.com/xfdf/";
+const CHECKED_STATE: &str = "Yes";
+const UNCHECKED_STATE: &str = "Off";
+/// Nested fields are flattened, deeper trees are malformed
+const MAX_FIELD_DEPTH: usize = 32;
+
+#[derive(Debug)]
+pub enum FdfError {
+    Pdf(lopdf_security::Error),
+    Xml(xml::reader::Error),
+    Format(&'static str),
+}
+
+impl Display for FdfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FdfError::Pdf(e) => write!(f, "Not a valid FDF file: {}", e),
+            FdfError::Xml(e) => write!(f, "Not a valid XFDF file: {}", e),
+            FdfError::Format(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<lopdf_security::Error> for FdfError {
+    fn from(e: lopdf_security::Error) -> Self {
+        FdfError::Pdf(e)
+    }
+}
+
+impl From<xml::reader::Error> for FdfError {
+    fn from(e: xml::reader::Error) -> Self {
+        FdfError::Xml(e)
+    }
+}
+
+/// Reads the field values of an FDF file, fields are named by their partial name as the forms
+/// are filled.
+pub fn parse_fdf(buffer: &[u8]) -> Result<PDFillerMap, FdfError> {
+    if !buffer.starts_with(FDF_HEADER) {
+        return Err(FdfError::Format("Not an FDF file"));
+    }
+
+    // FDF shares the PDF syntax, files usually come without a cross-reference table which is
+    // rebuilt while loading
+    let mut buffer = buffer.to_vec();
+    buffer[..validation::PDF_MAGIC.len()].copy_from_slice(validation::PDF_MAGIC);
+    let document = SecuredDocument::load_mem(&buffer)?;
+
+    let fields = document
+        .trailer
+        .get(b"Root")
+        .and_then(|root| document.dereference(root))
+        .and_then(|(_, root)| root.as_dict())
+        .and_then(|root| root.get(b"FDF"))
+        .and_then(|fdf| document.dereference(fdf))
+        .and_then(|(_, fdf)| fdf.as_dict())
+        .and_then(|fdf| fdf.get(b"Fields"))
+        .and_then(|fields| document.dereference(fields))
+        .and_then(|(_, fields)| fields.as_array())
+        .map_err(|_| FdfError::Format("The FDF file has no fields"))?;
+
+    let mut map = PDFillerMap::new();
+    read_fdf_fields(&document, fields, &mut map, 0)?;
+
+    Ok(map)
+}
+
+fn read_fdf_fields(
+    document: &SecuredDocument,
+    fields: &[SecuredObject],
+    map: &mut PDFillerMap,
+    depth: usize,
+) -> Result<(), FdfError> {
+    if depth > MAX_FIELD_DEPTH {
+        return Err(FdfError::Format("The FDF fields are nested too deeply"));
+    }
+
+    for field in fields {
+        let field = match document
+            .dereference(field)
+            .and_then(|(_, field)| field.as_dict())
+        {
+            Ok(field) => field,
+            Err(_) => continue,
+        };
+
+        if let Ok(kids) = field
+            .get(b"Kids")
+            .and_then(|kids| document.dereference(kids))
+            .and_then(|(_, kids)| kids.as_array())
+        {
+            read_fdf_fields(document, kids, map, depth + 1)?;
+        }
+
+        let name = match field.get(b"T").and_then(SecuredObject::as_str) {
+            Ok(name) => metadata::decode_text_string(name),
+            Err(_) => continue,
+        };
+        let value = match field
+            .get(b"V")
+            .and_then(|value| document.dereference(value))
+        {
+            Ok((_, value)) => get_fdf_value(document, value),
+            Err(_) => continue,
+        };
+
+        if let Some(value) = value {
+            map.insert(name, value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Text values are strings, check boxes and radio buttons carry the name of their state and list
+/// boxes an array of the selected options.
+fn get_fdf_value(document: &SecuredDocument, value: &SecuredObject) -> Option<Value> {
+    match value {
+        SecuredObject::String(bytes, _) => Some(Value::String(metadata::decode_text_string(bytes))),
+        SecuredObject::Name(name) => Some(Value::String(String::from_utf8_lossy(name).into())),
+        SecuredObject::Array(values) => Some(Value::Array(
+            values
+                .iter()
+                .filter_map(|value| document.dereference(value).ok())
+                .filter_map(|(_, value)| get_fdf_value(document, value))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// Reads the field values of an XFDF file, a field with more than one `value` is a list box.
+pub fn parse_xfdf(buffer: &[u8]) -> Result<PDFillerMap, FdfError> {
+    let mut map = PDFillerMap::new();
+    let mut fields: Vec<(String, Vec<String>)> = Vec::new();
+    let mut text: Option<String> = None;
+    let mut root = false;
+
+    for event in EventReader::new(buffer) {
+        match event? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => match name.local_name.as_str() {
+                "xfdf" => root = true,
+                "field" if root => {
+                    if fields.len() >= MAX_FIELD_DEPTH {
+                        return Err(FdfError::Format("The XFDF fields are nested too deeply"));
+                    }
+
+                    let field_name = attributes
+                        .into_iter()
+                        .find(|attribute| attribute.name.local_name == "name")
+                        .map(|attribute| attribute.value)
+                        .unwrap_or_default();
+                    fields.push((field_name, Vec::new()));
+                }
+                "value" if !fields.is_empty() => text = Some(String::new()),
+                _ => {}
+            },
+            XmlEvent::Characters(characters) | XmlEvent::CData(characters) => {
+                if let Some(ref mut text) = text {
+                    text.push_str(&characters);
+                }
+            }
+            XmlEvent::Whitespace(characters) => {
+                if let Some(ref mut text) = text {
+                    text.push_str(&characters);
+                }
+            }
+            XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                "value" => {
+                    if let (Some(text), Some((_, values))) = (text.take(), fields.last_mut()) {
+                        values.push(text);
+                    }
+                }
+                "field" => {
+                    if let Some((field_name, mut values)) = fields.pop() {
+                        if field_name.is_empty() || values.is_empty() {
+                            continue;
+                        }
+
+                        let value = if values.len() == 1 {
+                            Value::String(values.remove(0))
+                        } else {
+                            Value::Array(values.into_iter().map(Value::String).collect())
+                        };
+                        map.insert(field_name, value);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    if !root {
+        return Err(FdfError::Format("Not an XFDF file"));
+    }
+
+    Ok(map)
+}
+
+/// Writes the field values as XFDF, check boxes are written with the usual `Yes` and `Off` states.
+pub fn to_xfdf(map: &PDFillerMap) -> String {
+    let mut names = map.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut fields = String::new();
+    for name in names {
+        let values = match &map[name] {
+            Value::Array(values) => values.iter().map(get_xfdf_value).collect(),
+            value => vec![get_xfdf_value(value)],
+        };
+
+        fields.push_str(&format!(
+            "    <field name=\"{}\">\n",
+            metadata::escape_xml(name)
+        ));
+        for value in values {
+            fields.push_str(&format!(
+                "      <value>{}</value>\n",
+                metadata::escape_xml(&value)
+            ));
+        }
+        fields.push_str("    </field>\n");
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<xfdf xmlns=\"{}\" xml:space=\"preserve\">\n  <fields>\n{}  </fields>\n</xfdf>\n",
+        XFDF_NAMESPACE, fields
+    )
+}
+
+fn get_xfdf_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Bool(true) => CHECKED_STATE.into(),
+        Value::Bool(false) => UNCHECKED_STATE.into(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
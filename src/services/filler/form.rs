@@ -1,12 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
 use std::str;
 
+use serde::Serialize;
 use serde_json::Value;
 
 use pdf_forms::{FieldState, Form, LoadError, ValueError};
 
 use lopdf::xobject;
 
+use log::warn;
+
 use regex::Regex;
 
 use crate::client;
@@ -17,150 +21,451 @@ pub type PDFillerMap = HashMap<String, Value>;
 
 const REQUIRED_MARKER: char = '!';
 const IMAGE_REGEX: &str = r"_af_image$";
+/// States leaving a check box unchecked, compared without case: FDF and XFDF use "Off" and
+/// spreadsheets or query strings the usual false values
+const UNCHECKED_STATES: [&str; 6] = ["off", "false", "no", "n", "0", ""];
 
 pub type FormResult = Result<Form, FillingError>;
 
+/// Images of the `_af_image` fields, downloaded before filling
+pub type FormImages = HashMap<String, Vec<u8>>;
+
+/// Values the fields hold after filling, the map keys no field used and what couldn't be filled
+/// as requested.
+#[derive(Default, Serialize)]
+pub struct FillReport {
+    pub fields: BTreeMap<String, Value>,
+    pub unmatched: Vec<String>,
+    /// Required fields the map has no value for
+    pub missing: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl FillReport {
+    /// Report of a document without form fields, none of the keys is used.
+    pub fn without_form(map: &PDFillerMap) -> Self {
+        let mut unmatched = map.keys().cloned().collect::<Vec<_>>();
+        unmatched.sort();
+
+        Self {
+            fields: BTreeMap::new(),
+            unmatched,
+            missing: Vec::new(),
+            warnings: vec!["The document has no form fields".into()],
+        }
+    }
+
+    pub fn warn(&mut self, message: String) {
+        warn!("{}", message);
+
+        self.warnings.push(message);
+    }
+}
+
 #[derive(Debug)]
 pub enum FillingError {
     Load(LoadError),
     Value(ValueError),
     RequiredField(String),
+    SingleValue(String),
+    Xfa(String),
     InternalError,
 }
 
+impl Display for FillingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FillingError::Load(e) => write!(f, "The form can't be loaded: {}", e),
+            FillingError::Value(e) => write!(f, "A field can't be filled: {}", e),
+            FillingError::RequiredField(name) => write!(f, "Field \"{}\" is required", name),
+            FillingError::SingleValue(name) => {
+                write!(f, "Field \"{}\" takes a single value", name)
+            }
+            FillingError::Xfa(message) => write!(f, "The XFA form can't be filled: {}", message),
+            FillingError::InternalError => write!(f, "Internal error"),
+        }
+    }
+}
+
 pub async fn fields_filler(map: &PDFillerMap, document: &Document) -> FormResult {
     match Form::load(&document.file) {
-        Ok(mut form) => {
-            for (index, name) in form.get_all_names().iter().enumerate() {
-                if let Some(name) = name {
-                    let name = name.trim_start_matches(REQUIRED_MARKER);
-
-                    let mut value = map.get(name);
-                    let result = {
-                        if value.is_some() {
-                            match form.get_state(index) {
-                                FieldState::Text { required, .. } => {
-                                    if required && value.is_none() {
-                                        Err(FillingError::RequiredField(name.to_owned()))
-                                    } else if let Some(value) = value {
-                                        form.set_text(index, value.as_str().unwrap_or("").into())
-                                            .map_err(FillingError::Value)
-                                    } else {
-                                        Ok(())
-                                    }
-                                }
-                                FieldState::Radio { required, .. } => {
-                                    if required && value.is_none() {
-                                        Err(FillingError::RequiredField(name.to_owned()))
-                                    } else if let Some(value) = value {
-                                        form.set_radio(index, value.as_str().unwrap_or("").into())
-                                            .map_err(FillingError::Value)
-                                    } else {
-                                        Ok(())
-                                    }
-                                }
-                                FieldState::CheckBox { required, .. } => {
-                                    if required && value.is_none() {
-                                        Err(FillingError::RequiredField(name.to_owned()))
-                                    } else if let Some(value) = value {
-                                        form.set_check_box(index, value.as_bool().unwrap_or(false))
-                                            .map_err(FillingError::Value)
-                                    } else {
-                                        Ok(())
-                                    }
-                                }
-                                FieldState::ListBox { required, .. } => {
-                                    if required && value.is_none() {
-                                        Err(FillingError::RequiredField(name.to_owned()))
-                                    } else if let Some(value) = value {
-                                        match value.as_array() {
-                                            Some(values) => form
-                                                .set_list_box(
-                                                    index,
-                                                    values
-                                                        .iter()
-                                                        .map(|value| {
-                                                            value.as_str().unwrap_or("").to_string()
-                                                        })
-                                                        .collect(),
-                                                )
-                                                .map_err(FillingError::Value),
-                                            None => Ok(()),
-                                        }
-                                    } else {
-                                        Ok(())
-                                    }
+        Ok(form) => fill_form(map, form, None).await,
+        Err(e) => Err(FillingError::Load(e)),
+    }
+}
+
+/// Fills like `fields_filler` and reports what was filled, for dry runs.
+pub async fn fields_filler_with_report(
+    map: &PDFillerMap,
+    document: &Document,
+) -> Result<(Form, FillReport), FillingError> {
+    let mut report = FillReport::default();
+    match Form::load(&document.file) {
+        Ok(form) => fill_form(map, form, Some(&mut report))
+            .await
+            .map(|form| (form, report)),
+        Err(e) => Err(FillingError::Load(e)),
+    }
+}
+
+/// The report is only built when one is given, compiles don't pay for it.
+pub async fn fill_form(
+    map: &PDFillerMap,
+    form: Form,
+    report: Option<&mut FillReport>,
+) -> FormResult {
+    let images = get_form_images(map, &form).await?;
+
+    fill_form_with_images(map, form, &images, report)
+}
+
+/// Downloads the images of the `_af_image` fields, keyed by the map key holding their URI.
+pub async fn get_form_images(map: &PDFillerMap, form: &Form) -> Result<FormImages, FillingError> {
+    let image_regex = get_image_regex()?;
+
+    let mut images = FormImages::new();
+    for name in form.get_all_names().iter().flatten() {
+        let name = name.trim_start_matches(REQUIRED_MARKER);
+        if map.contains_key(name) {
+            continue;
+        }
+
+        let image_key = image_regex.replace(name, "");
+        if images.contains_key(image_key.as_ref()) {
+            continue;
+        }
+        if let Some(uri) = map.get(image_key.as_ref()) {
+            if let Ok(image) = client::get(uri.as_str().unwrap_or(""), None).await {
+                images.insert(image_key.into_owned(), image);
+            }
+        }
+    }
+
+    Ok(images)
+}
+
+/// Fills like `fill_form` without waiting on the network, the images are already downloaded.
+pub fn fill_form_with_images(
+    map: &PDFillerMap,
+    mut form: Form,
+    images: &FormImages,
+    mut report: Option<&mut FillReport>,
+) -> FormResult {
+    let image_regex = get_image_regex()?;
+    let mut matched = BTreeSet::new();
+
+    for (index, name) in form.get_all_names().iter().enumerate() {
+        if let Some(name) = name {
+            let name = name.trim_start_matches(REQUIRED_MARKER);
+
+            let mut value = map.get(name);
+            let mut placed_image = None;
+            let result = {
+                if value.is_some() {
+                    matched.insert(name.to_owned());
+                    if let Some(report) = report.as_deref_mut() {
+                        report_coercion(report, name, &form.get_state(index), value);
+                    }
+
+                    match form.get_state(index) {
+                        FieldState::Text { required, .. } => {
+                            if required && value.is_none() {
+                                Err(FillingError::RequiredField(name.to_owned()))
+                            } else if let Some(value) = value {
+                                form.set_text(index, value.as_str().unwrap_or("").into())
+                                    .map_err(FillingError::Value)
+                            } else {
+                                Ok(())
+                            }
+                        }
+                        FieldState::Radio { required, .. } => {
+                            if required && value.is_none() {
+                                Err(FillingError::RequiredField(name.to_owned()))
+                            } else if let Some(value) = value {
+                                form.set_radio(index, value.as_str().unwrap_or("").into())
+                                    .map_err(FillingError::Value)
+                            } else {
+                                Ok(())
+                            }
+                        }
+                        FieldState::CheckBox { required, .. } => {
+                            if required && value.is_none() {
+                                Err(FillingError::RequiredField(name.to_owned()))
+                            } else if let Some(value) = value {
+                                form.set_check_box(index, get_check_box_value(value))
+                                    .map_err(FillingError::Value)
+                            } else {
+                                Ok(())
+                            }
+                        }
+                        FieldState::ListBox { required, .. } => {
+                            if required && value.is_none() {
+                                Err(FillingError::RequiredField(name.to_owned()))
+                            } else if let Some(value) = value {
+                                match get_list_values(value) {
+                                    Some(values) => form
+                                        .set_list_box(index, values)
+                                        .map_err(FillingError::Value),
+                                    None => Ok(()),
                                 }
-                                FieldState::ComboBox { required, .. } => {
-                                    if required && value.is_none() {
-                                        Err(FillingError::RequiredField(name.to_owned()))
-                                    } else if let Some(value) = value {
-                                        match value.as_array() {
-                                            Some(values) => form
-                                                .set_combo_box(
-                                                    index,
-                                                    values
-                                                        .iter()
-                                                        .map(|value| {
-                                                            value.as_str().unwrap_or("").to_string()
-                                                        })
-                                                        .collect(),
-                                                )
-                                                .map_err(FillingError::Value),
-                                            None => Ok(()),
-                                        }
-                                    } else {
-                                        Ok(())
+                            } else {
+                                Ok(())
+                            }
+                        }
+                        FieldState::ComboBox { required, .. } => {
+                            if required && value.is_none() {
+                                Err(FillingError::RequiredField(name.to_owned()))
+                            } else if let Some(value) = value {
+                                // Combo boxes select one value, arrays are accepted as listed by dry runs
+                                match get_list_values(value) {
+                                    Some(values) if values.len() > 1 => {
+                                        Err(FillingError::SingleValue(name.to_owned()))
                                     }
+                                    Some(mut values) => form
+                                        .set_combo_box(index, values.pop().unwrap_or_default())
+                                        .map_err(FillingError::Value),
+                                    None => Ok(()),
                                 }
-                                _ => Ok(()),
+                            } else {
+                                Ok(())
                             }
-                        } else {
-                            // This is needed as the current regex is a bit unuseful
-                            #[allow(clippy::trivial_regex)]
-                            let image_regex = Regex::new(IMAGE_REGEX)
-                                .map_err(|_err| FillingError::InternalError)?;
-
-                            value = map.get(image_regex.replace(name, "").as_ref());
-
-                            if let Some(uri) = value {
-                                let object_id = form.get_object_id(index);
-                                if let Ok(page_id) = form.document.get_object_page(object_id) {
-                                    if let Some(image) =
-                                        client::get(uri.as_str().unwrap_or("")).await
-                                    {
-                                        if let Ok(object) = form.document.get_object(object_id) {
-                                            if let Ok(dict) = object.as_dict() {
-                                                if let Ok(rect) = utils::get_object_rect(dict) {
-                                                    if let Ok(stream) = xobject::image_from(image) {
-                                                        let _ = form.document.insert_image(
-                                                            page_id,
-                                                            stream,
-                                                            (rect.0, rect.1),
-                                                            (rect.3, rect.2),
-                                                        );
-
-                                                        let _ = form.remove_field(index);
-                                                    }
-                                                }
+                        }
+                        FieldState::Button => Ok(()),
+                        FieldState::Unknown => {
+                            // Signature fields aren't filled with values
+                            let message = format!(
+                                "Field \"{}\" can't be filled, signature fields are signed through the \"signature\" export option",
+                                name
+                            );
+                            match report.as_deref_mut() {
+                                Some(report) => report.warn(message),
+                                None => warn!("{}", message),
+                            }
+
+                            Ok(())
+                        }
+                    }
+                } else {
+                    let image_key = image_regex.replace(name, "");
+                    value = map.get(image_key.as_ref());
+
+                    if let Some(uri) = value {
+                        let image = images.get(image_key.as_ref()).cloned();
+                        matched.insert(image_key.into_owned());
+
+                        let object_id = form.get_object_id(index);
+                        if let Ok(page_id) = form.document.get_object_page(object_id) {
+                            if let Some(image) = image {
+                                if let Ok(object) = form.document.get_object(object_id) {
+                                    if let Ok(dict) = object.as_dict() {
+                                        if let Ok(rect) = utils::get_object_rect(dict) {
+                                            if let Ok(stream) = xobject::image_from(image) {
+                                                let _ = form.document.insert_image(
+                                                    page_id,
+                                                    stream,
+                                                    (rect.0, rect.1),
+                                                    (rect.3, rect.2),
+                                                );
+
+                                                let _ = form.remove_field(index);
+
+                                                placed_image = Some(uri.clone());
                                             }
                                         }
                                     }
                                 }
                             }
-
-                            Ok(())
                         }
-                    };
 
-                    if let Err(e) = result {
-                        return Err(e);
+                        if let (None, Some(report)) = (&placed_image, report.as_deref_mut()) {
+                            report.warn(format!(
+                                "Image {} couldn't be placed on field \"{}\"",
+                                uri, name
+                            ));
+                        }
                     }
+
+                    Ok(())
+                }
+            };
+
+            result?;
+
+            if let Some(report) = report.as_deref_mut() {
+                let state = form.get_state(index);
+                if value.is_none() && is_required(&state) {
+                    report.missing.push(name.to_owned());
                 }
+
+                // Placed images replace their field
+                let field_value = match placed_image {
+                    Some(uri) => uri,
+                    None => get_field_value(state),
+                };
+                report.fields.insert(name.to_owned(), field_value);
             }
+        }
+    }
+
+    if let Some(report) = report {
+        report.unmatched = map
+            .keys()
+            .filter(|key| !matched.contains(*key))
+            .cloned()
+            .collect();
+        report.unmatched.sort();
+    }
+
+    Ok(form)
+}
+
+fn get_image_regex() -> Result<Regex, FillingError> {
+    // This is needed as the current regex is a bit unuseful
+    #[allow(clippy::trivial_regex)]
+    let image_regex = Regex::new(IMAGE_REGEX).map_err(|_err| FillingError::InternalError)?;
+
+    Ok(image_regex)
+}
+
+fn is_required(state: &FieldState) -> bool {
+    match state {
+        FieldState::Text { required, .. }
+        | FieldState::Radio { required, .. }
+        | FieldState::CheckBox { required, .. }
+        | FieldState::ListBox { required, .. }
+        | FieldState::ComboBox { required, .. } => *required,
+        FieldState::Button | FieldState::Unknown => false,
+    }
+}
 
-            Ok(form)
+/// Reads the value of every field in the shape `fill_form` accepts, push buttons and signature
+/// fields have no value.
+pub fn get_form_values(form: &Form) -> PDFillerMap {
+    let mut map = PDFillerMap::new();
+    for (index, name) in form.get_all_names().iter().enumerate() {
+        if let Some(name) = name {
+            let value = get_field_value(form.get_state(index));
+            if !value.is_null() {
+                map.entry(name.trim_start_matches(REQUIRED_MARKER).to_owned())
+                    .or_insert(value);
+            }
+        }
+    }
+
+    map
+}
+
+/// Warns when the value has to be converted to the type of the field.
+fn report_coercion(report: &mut FillReport, name: &str, state: &FieldState, value: Option<&Value>) {
+    let value = match value {
+        Some(value) => value,
+        None => return,
+    };
+
+    let expected = match state {
+        FieldState::Text { .. } | FieldState::Radio { .. } if !value.is_string() => {
+            "a string, it's filled as empty"
+        }
+        FieldState::CheckBox { .. } if !value.is_boolean() && !value.is_string() => {
+            "a boolean, it's unchecked"
+        }
+        FieldState::ListBox { .. } | FieldState::ComboBox { .. }
+            if !value.is_array() && !value.is_string() =>
+        {
+            "an array of strings, it's left unchanged"
+        }
+        FieldState::ListBox { .. } | FieldState::ComboBox { .. }
+            if value
+                .as_array()
+                .map(|values| values.iter().any(|value| !value.is_string()))
+                .unwrap_or(false) =>
+        {
+            "an array of strings, other values are filled as empty"
+        }
+        _ => return,
+    };
+
+    report.warn(format!(
+        "Field \"{}\" expects {}: {} given",
+        name, expected, value
+    ));
+}
+
+/// Check boxes accept the name of their state too, as FDF and XFDF data carry it.
+fn get_check_box_value(value: &Value) -> bool {
+    match value {
+        Value::Bool(value) => *value,
+        Value::String(state) => {
+            let state = state.trim().to_lowercase();
+            !UNCHECKED_STATES.contains(&state.as_str())
+        }
+        _ => false,
+    }
+}
+
+/// A single string selects one option.
+fn get_list_values(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::Array(values) => Some(
+            values
+                .iter()
+                .map(|value| value.as_str().unwrap_or("").to_string())
+                .collect(),
+        ),
+        Value::String(value) => Some(vec![value.clone()]),
+        _ => None,
+    }
+}
+
+fn get_field_value(state: FieldState) -> Value {
+    match state {
+        FieldState::Text { text, .. } => Value::String(text),
+        FieldState::Radio { selected, .. } => Value::String(selected),
+        FieldState::CheckBox { is_checked, .. } => Value::Bool(is_checked),
+        FieldState::ListBox { selected, .. } | FieldState::ComboBox { selected, .. } => {
+            Value::Array(selected.into_iter().map(Value::String).collect())
+        }
+        FieldState::Button | FieldState::Unknown => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::get_check_box_value;
+
+    #[test]
+    fn check_box_values() {
+        for value in [
+            json!(true),
+            json!("Yes"),
+            json!("On"),
+            json!("1"),
+            json!("Choice1"),
+        ]
+        .iter()
+        {
+            assert!(get_check_box_value(value), "{} should check the box", value);
+        }
+        for value in [
+            json!(false),
+            json!("Off"),
+            json!("false"),
+            json!("FALSE"),
+            json!("0"),
+            json!("no"),
+            json!(""),
+            json!(null),
+            json!(1),
+        ]
+        .iter()
+        {
+            assert!(
+                !get_check_box_value(value),
+                "{} should leave the box unchecked",
+                value
+            );
         }
-        Err(e) => Err(FillingError::Load(e)),
     }
 }
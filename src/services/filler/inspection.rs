@@ -0,0 +1,49 @@
+use std::io::Cursor;
+
+use lopdf::Document as PdfDocument;
+use openssl::sha;
+use pdf_forms::Form;
+
+use crate::services::filler::validation;
+
+/// Values read from an uploaded template, the counts are missing when it can't be parsed.
+#[derive(Debug)]
+pub struct Inspection {
+    pub content_type: String,
+    pub size: u64,
+    pub sha256: String,
+    pub pages: Option<u32>,
+    pub fields: Option<u32>,
+}
+
+pub fn inspect_buffer(buffer: &[u8]) -> Inspection {
+    let content_type = if validation::has_pdf_header(buffer) {
+        mime::APPLICATION_PDF
+    } else {
+        mime::APPLICATION_OCTET_STREAM
+    };
+    let sha256 = sha::sha256(buffer)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    // Documents without a form still count their pages
+    let (pages, fields) = match Form::load_from(Cursor::new(buffer)) {
+        Ok(form) => (
+            Some(form.document.get_pages().len() as u32),
+            Some(form.len() as u32),
+        ),
+        Err(_) => match PdfDocument::load_mem(buffer) {
+            Ok(document) => (Some(document.get_pages().len() as u32), Some(0)),
+            Err(_) => (None, None),
+        },
+    };
+
+    Inspection {
+        content_type: content_type.to_string(),
+        size: buffer.len() as u64,
+        sha256,
+        pages,
+        fields,
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+use lopdf::{Dictionary, Document as PdfDocument, Object, Stream, StringFormat};
+use serde::Deserialize;
+
+use crate::services::filler::processor::DocumentPages;
+
+const PRODUCER: &str = concat!("PDFiller v", env!("CARGO_PKG_VERSION"));
+pub const PDF_DATE_FORMAT: &str = "D:%Y%m%d%H%M%S+00'00'";
+const XMP_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+const INFO_TITLE: &str = "Title";
+const INFO_AUTHOR: &str = "Author";
+const INFO_SUBJECT: &str = "Subject";
+const INFO_KEYWORDS: &str = "Keywords";
+const INFO_CREATOR: &str = "Creator";
+const INFO_PRODUCER: &str = "Producer";
+const INFO_CREATION_DATE: &str = "CreationDate";
+const INFO_MOD_DATE: &str = "ModDate";
+
+const STANDARD_KEYS: [&str; 8] = [
+    INFO_TITLE,
+    INFO_AUTHOR,
+    INFO_SUBJECT,
+    INFO_KEYWORDS,
+    INFO_CREATOR,
+    INFO_PRODUCER,
+    INFO_CREATION_DATE,
+    INFO_MOD_DATE,
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MetadataOptions {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    pub creator: Option<String>,
+    pub producer: Option<String>,
+    /// Custom keys added to the Info dictionary and to the XMP packet
+    pub custom: Option<BTreeMap<String, String>>,
+    /// Generates the XMP metadata stream referenced by the catalog
+    pub xmp: Option<bool>,
+    /// Uses the source documents metadata for missing values, enabled by default
+    pub inherit: Option<bool>,
+}
+
+impl MetadataOptions {
+    /// Custom keys can't replace the standard ones, the XMP packet is built from the typed fields.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(key) = self
+            .custom
+            .iter()
+            .flat_map(BTreeMap::keys)
+            .find(|key| STANDARD_KEYS.contains(&key.as_str()))
+        {
+            return Err(format!(
+                "The custom metadata key \"{}\" is a standard one, set it by its own field",
+                key
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Document information ready to be written both in the Info dictionary and in the XMP packet.
+#[derive(Debug, Clone)]
+pub struct Info {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    pub creator: Option<String>,
+    pub producer: String,
+    pub custom: BTreeMap<String, String>,
+    pub created: DateTime<Utc>,
+}
+
+impl Info {
+    pub fn new(page_map: &[DocumentPages], options: &MetadataOptions) -> Self {
+        let fallback = |key: &str| {
+            if options.inherit.unwrap_or(true) {
+                page_map.iter().find_map(|document_pages| {
+                    document_pages
+                        .info
+                        .get(key.as_bytes())
+                        .and_then(Object::as_str)
+                        .ok()
+                        .map(decode_text_string)
+                        .filter(|value| !value.is_empty())
+                })
+            } else {
+                None
+            }
+        };
+
+        let mut custom = BTreeMap::new();
+        if options.inherit.unwrap_or(true) {
+            for document_pages in page_map.iter().rev() {
+                for (key, value) in document_pages.info.iter() {
+                    let key = String::from_utf8_lossy(key).to_string();
+                    if !STANDARD_KEYS.contains(&key.as_str()) {
+                        if let Ok(value) = value.as_str() {
+                            custom.insert(key, decode_text_string(value));
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(ref values) = options.custom {
+            custom.extend(
+                values
+                    .iter()
+                    .filter(|(key, _)| !STANDARD_KEYS.contains(&key.as_str()))
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+
+        Self {
+            title: options.title.clone().or_else(|| fallback(INFO_TITLE)),
+            author: options.author.clone().or_else(|| fallback(INFO_AUTHOR)),
+            subject: options.subject.clone().or_else(|| fallback(INFO_SUBJECT)),
+            keywords: options.keywords.clone().or_else(|| fallback(INFO_KEYWORDS)),
+            creator: options.creator.clone().or_else(|| fallback(INFO_CREATOR)),
+            producer: options.producer.clone().unwrap_or_else(|| PRODUCER.into()),
+            custom,
+            created: Utc::now(),
+        }
+    }
+
+    pub fn to_dictionary(&self) -> Dictionary {
+        let mut dictionary = Dictionary::new();
+
+        let values = [
+            (INFO_TITLE, self.title.as_ref()),
+            (INFO_AUTHOR, self.author.as_ref()),
+            (INFO_SUBJECT, self.subject.as_ref()),
+            (INFO_KEYWORDS, self.keywords.as_ref()),
+            (INFO_CREATOR, self.creator.as_ref()),
+            (INFO_PRODUCER, Some(&self.producer)),
+        ];
+        for (key, value) in values.iter() {
+            if let Some(value) = value {
+                dictionary.set(*key, encode_text_string(value));
+            }
+        }
+
+        for (key, value) in self.custom.iter() {
+            dictionary.set(key.as_str(), encode_text_string(value));
+        }
+
+        let date = Object::string_literal(self.created.format(PDF_DATE_FORMAT).to_string());
+        dictionary.set(INFO_CREATION_DATE, date.clone());
+        dictionary.set(INFO_MOD_DATE, date);
+
+        dictionary
+    }
+
+    /// Serializes the information as an XMP packet, `extra` contains additional RDF descriptions
+    /// such as the PDF/A identification schema.
+    pub fn to_xmp(&self, extra: &str) -> String {
+        let date = self.created.format(XMP_DATE_FORMAT).to_string();
+
+        let mut properties = Vec::new();
+        if let Some(ref title) = self.title {
+            properties.push(format!(
+                "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>",
+                escape_xml(title)
+            ));
+        }
+        if let Some(ref author) = self.author {
+            properties.push(format!(
+                "<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>",
+                escape_xml(author)
+            ));
+        }
+        if let Some(ref subject) = self.subject {
+            properties.push(format!(
+                "<dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>",
+                escape_xml(subject)
+            ));
+        }
+        if let Some(ref keywords) = self.keywords {
+            properties.push(format!(
+                "<pdf:Keywords>{}</pdf:Keywords>",
+                escape_xml(keywords)
+            ));
+        }
+        if let Some(ref creator) = self.creator {
+            properties.push(format!(
+                "<xmp:CreatorTool>{}</xmp:CreatorTool>",
+                escape_xml(creator)
+            ));
+        }
+        properties.push(format!(
+            "<pdf:Producer>{}</pdf:Producer>",
+            escape_xml(&self.producer)
+        ));
+        properties.push(format!("<xmp:CreateDate>{}</xmp:CreateDate>", date));
+        properties.push(format!("<xmp:ModifyDate>{}</xmp:ModifyDate>", date));
+        properties.push(format!("<xmp:MetadataDate>{}</xmp:MetadataDate>", date));
+
+        let custom = self
+            .custom
+            .iter()
+            .filter(|(key, _)| is_xml_name(key))
+            .map(|(key, value)| format!("<pdfx:{0}>{1}</pdfx:{0}>", key, escape_xml(value)))
+            .collect::<String>();
+
+        format!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+             <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+             <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+             <rdf:Description rdf:about=\"\" \
+             xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+             xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" \
+             xmlns:pdf=\"http://ns.adobe.com/pdf/1.3/\" \
+             xmlns:pdfx=\"http://ns.adobe.com/pdfx/1.3/\">\n\
+             <dc:format>application/pdf</dc:format>\n\
+             {}\n\
+             {}\n\
+             </rdf:Description>\n\
+             {}\
+             </rdf:RDF>\n\
+             </x:xmpmeta>\n\
+             <?xpacket end=\"w\"?>",
+            properties.join("\n"),
+            custom,
+            extra
+        )
+    }
+}
+
+/// Sets the Info dictionary on the trailer and, when requested, the XMP metadata on the catalog.
+pub fn set_metadata(
+    document: &mut PdfDocument,
+    page_map: &[DocumentPages],
+    options: &MetadataOptions,
+) -> Result<Info, lopdf::Error> {
+    let info = Info::new(page_map, options);
+
+    let info_id = document.add_object(info.to_dictionary());
+    document.trailer.set("Info", info_id);
+
+    if options.xmp.unwrap_or(false) {
+        set_xmp(document, &info.to_xmp(""))?;
+    }
+
+    Ok(info)
+}
+
+pub fn set_xmp(document: &mut PdfDocument, xmp: &str) -> Result<(), lopdf::Error> {
+    let mut dictionary = Dictionary::new();
+    dictionary.set("Type", Object::Name(b"Metadata".to_vec()));
+    dictionary.set("Subtype", Object::Name(b"XML".to_vec()));
+
+    // Metadata streams are left uncompressed so they can be read by non PDF aware tools
+    let stream = Stream::new(dictionary, xmp.as_bytes().to_vec()).with_compression(false);
+    let metadata_id = document.add_object(stream);
+
+    let catalog_id = document.trailer.get(b"Root")?.as_reference()?;
+    document
+        .get_object_mut(catalog_id)?
+        .as_dict_mut()?
+        .set("Metadata", metadata_id);
+
+    Ok(())
+}
+
+/// Text strings are written as PDFDocEncoding when possible, UTF-16BE with BOM otherwise.
+pub fn encode_text_string(value: &str) -> Object {
+    if value.is_ascii() {
+        Object::string_literal(value)
+    } else {
+        let mut bytes = vec![0xFE, 0xFF];
+        for unit in value.encode_utf16() {
+            bytes.extend_from_slice(&unit.to_be_bytes());
+        }
+
+        Object::String(bytes, StringFormat::Hexadecimal)
+    }
+}
+
+pub fn decode_text_string(bytes: &[u8]) -> String {
+    if bytes.starts_with(&[0xFE, 0xFF]) {
+        let units = bytes[2..]
+            .chunks(2)
+            .filter(|chunk| chunk.len() == 2)
+            .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+            .collect::<Vec<_>>();
+
+        String::from_utf16_lossy(&units)
+    } else {
+        // PDFDocEncoding matches Latin-1 for the printable characters
+        bytes.iter().map(|byte| *byte as char).collect()
+    }
+}
+
+/// Parses a PDF date (`D:YYYYMMDDHHmmSSOHH'mm'`), every part after the year is optional.
+pub fn parse_pdf_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim().trim_start_matches("D:");
+    let digits = value
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>();
+    if digits.len() < 4 {
+        return None;
+    }
+
+    let part = |start: usize, default: u32| -> Option<u32> {
+        match digits.get(start..start + 2) {
+            Some(part) => part.parse().ok(),
+            None => Some(default),
+        }
+    };
+    let date = NaiveDate::from_ymd_opt(digits[..4].parse().ok()?, part(4, 1)?, part(6, 1)?)?
+        .and_hms_opt(part(8, 0)?, part(10, 0)?, part(12, 0)?)?;
+
+    let offset = value[digits.len()..].replace('\'', "");
+    let seconds = match offset.chars().next() {
+        Some(sign @ '+') | Some(sign @ '-') => {
+            let hours = offset
+                .get(1..3)
+                .and_then(|hours| hours.parse::<i32>().ok())?;
+            let minutes = offset
+                .get(3..5)
+                .and_then(|minutes| minutes.parse::<i32>().ok())
+                .unwrap_or(0);
+            let seconds = hours * 3600 + minutes * 60;
+
+            if sign == '-' {
+                -seconds
+            } else {
+                seconds
+            }
+        }
+        _ => 0,
+    };
+
+    FixedOffset::east_opt(seconds)?
+        .from_local_datetime(&date)
+        .single()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+pub fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn is_xml_name(value: &str) -> bool {
+    let mut chars = value.chars();
+
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
@@ -1,118 +1,644 @@
+pub mod batch;
 pub mod compiler;
+mod cover;
+pub mod fdf;
 mod form;
+pub mod inspection;
+mod metadata;
+mod pdfa;
 mod processor;
+pub mod render;
+pub mod security;
+pub mod sheet;
+pub mod signature;
+mod stamp;
+pub mod validation;
+pub mod verification;
+mod writer;
+pub mod xfa;
 
+use std::collections::HashMap;
 use std::str;
 
+use actix_web::http::header;
 use actix_web::{post, web, HttpResponse, Responder};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::config::BatchConfig;
 use crate::data::Data;
+use crate::mongo::models::compilation::Compilation;
+use crate::mongo::models::document::Document;
+use crate::mongo::models::mapping::MappingProfile;
 use crate::services::{self, WsError};
 
-pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(compile_documents);
+const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+const DEFAULT_BATCH_MAX_ROWS: usize = 10000;
+const DEFAULT_BATCH_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+const BATCH_ERRORS_HEADER: &str = "x-batch-errors";
+const BATCH_ERROR_COUNT_HEADER: &str = "x-batch-error-count";
+/// Bytes of the errors header, proxies commonly refuse headers past 4 or 8 KiB
+const BATCH_ERRORS_HEADER_SIZE: usize = 2048;
+const COMPILATION_HEADER: &str = "x-compilation-id";
+/// Headers carrying the passwords of the export options, with their section and key
+const PASSWORD_HEADERS: [(&str, &str, &str); 4] = [
+    ("x-user-password", "encryption", "user_password"),
+    ("x-owner-password", "encryption", "owner_password"),
+    ("x-zip-password", "encryption", "zip_password"),
+    ("x-signature-password", "signature", "password"),
+];
+
+/// `options` carries the export options as JSON when the body holds FDF, XFDF, CSV or XLSX data,
+/// `mapping` names the mapping profile of the CSV and XLSX columns and `sheet` the XLSX sheet.
+#[derive(Deserialize)]
+pub struct CompileQuery {
+    pub format: Option<String>,
+    pub options: Option<String>,
+    pub mapping: Option<String>,
+    pub sheet: Option<String>,
 }
 
+/// `key` names the row field whose value names the row PDF in the ZIP, rows are numbered otherwise.
 #[derive(Deserialize)]
-pub struct CompileOptions {
-    pub merge: Option<bool>,
+pub struct BatchQuery {
+    pub format: Option<String>,
+    pub key: Option<String>,
+    pub options: Option<String>,
+    pub mapping: Option<String>,
+    pub sheet: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BatchErrors {
+    error: String,
+    rows: Vec<batch::RowError>,
+}
+
+pub fn config(cfg: &mut web::ServiceConfig, batch: Option<&BatchConfig>) {
+    cfg.service(compile_documents);
+
+    // Batches are far larger than the default payload limit
+    let max_body_size = batch
+        .and_then(|batch| batch.max_body_size)
+        .unwrap_or(DEFAULT_BATCH_MAX_BODY_SIZE);
+    cfg.service(
+        web::resource("/compile/{token}/batch")
+            .app_data(web::PayloadConfig::new(max_body_size))
+            .route(web::post().to(compile_batch)),
+    );
 }
 
 #[post("/compile/{token}")]
 pub async fn compile_documents(
     data: web::Data<Data>,
     token: web::Path<String>,
+    query: web::Query<CompileQuery>,
+    request: web::HttpRequest,
+    bytes: web::Bytes,
+) -> impl Responder {
+    let values = match get_request_values(&data, token.as_str(), &request, &query, &bytes).await {
+        Ok(values) => values,
+        Err(response) => return response,
+    };
+
+    let options = match get_export_options(&data, token.as_str(), &values).await {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    let map = match get_data_map(&values) {
+        Ok(map) => map,
+        Err(response) => return response,
+    };
+    let documents = match get_template_documents(&data, token.as_str(), &values).await {
+        Ok(documents) => documents,
+        Err(response) => return response,
+    };
+
+    // Dry runs return the filled values, nothing is compiled
+    if values.get("dry_run").and_then(Value::as_bool) == Some(true) {
+        return match compiler::dry_run_documents(&map, documents, options.xfa).await {
+            Ok(reports) => HttpResponse::Ok().json(reports),
+            Err(compiler::HandlerCompilerError::FillingError(e)) => HttpResponse::BadRequest()
+                .json(WsError {
+                    error: format!("Error during document filling: {:#?}", e),
+                }),
+            Err(compiler::HandlerCompilerError::Error(message)) => {
+                HttpResponse::InternalServerError().json(WsError { error: message })
+            }
+        };
+    }
+
+    let accept = match services::get_accepted_header(&request, query.format.as_deref()) {
+        Some(accept) => accept,
+        None => {
+            return HttpResponse::NotAcceptable().json(WsError {
+                error: "Only PDF, Streams or PNG and JPEG images are accepted".into(),
+            })
+        }
+    };
+
+    let compilation = new_compilation(&data, token.as_str(), &map, &documents);
+    match compiler::compile_documents(
+        data.file.clone(),
+        &map,
+        &documents,
+        options.xfa,
+        compilation.id(),
+    )
+    .await
+    {
+        Ok(_) => {
+            if let Err(e) = data.create_compilation(compilation.clone()).await {
+                return HttpResponse::InternalServerError().json(WsError {
+                    error: format!("An error occurred: {:#?}", e),
+                });
+            }
+
+            let filename = options.get_filename(token.as_str());
+            let export_result =
+                export_documents(&data, documents, options, &accept, compilation.id()).await;
+
+            let mut response = services::export_content(accept, filename, export_result);
+            if let Ok(id) = header::HeaderValue::from_str(compilation.id()) {
+                response
+                    .headers_mut()
+                    .insert(header::HeaderName::from_static(COMPILATION_HEADER), id);
+            }
+
+            response
+        }
+        Err(compiler::HandlerCompilerError::FillingError(e)) => {
+            HttpResponse::BadRequest().json(WsError {
+                error: format!("Error during document filling: {:#?}", e),
+            })
+        }
+        Err(compiler::HandlerCompilerError::Error(message)) => {
+            HttpResponse::InternalServerError().json(WsError { error: message })
+        }
+    }
+}
+
+/// Compiles the documents for every row of a JSON array, NDJSON, CSV or XLSX body, as a ZIP with a PDF
+/// for every row or as a single PDF. Rows failing are reported in the `errors.json` entry of the
+/// ZIP or in the `x-batch-errors` header of the PDF, capped in size with the total in
+/// `x-batch-error-count`.
+pub async fn compile_batch(
+    data: web::Data<Data>,
+    token: web::Path<String>,
+    query: web::Query<BatchQuery>,
     request: web::HttpRequest,
     bytes: web::Bytes,
 ) -> impl Responder {
-    match str::from_utf8(&bytes) {
-        Ok(body) => match serde_json::from_str::<Value>(body) {
-            Ok(values) => {
-                if let Some(value) = values.get("data") {
-                    match <compiler::PDFillerMap>::deserialize(value) {
-                        Ok(ref map) => {
-                            if let Some(documents) =
-                                data.get_documents_by_token(token.as_str()).await
-                            {
-                                if documents.is_empty() {
-                                    return HttpResponse::NotFound().json(WsError {
-                                        error: "No documents found for this token!".into(),
-                                    });
-                                }
-
-                                match compiler::compile_documents(
-                                    data.file.clone(),
-                                    map,
-                                    &documents,
-                                )
-                                .await
-                                {
-                                    Ok(_) => {
-                                        if let Some(accept) =
-                                            services::get_accepted_header(&request)
-                                        {
-                                            let export_result =
-                                                if accept.as_str() == mime::APPLICATION_PDF {
-                                                    compiler::merge_documents(
-                                                        data.file.clone(),
-                                                        documents,
-                                                        true,
-                                                    )
-                                                    .await
-                                                } else {
-                                                    compiler::zip_documents(
-                                                        data.file.clone(),
-                                                        documents,
-                                                        true,
-                                                    )
-                                                    .await
-                                                };
-
-                                            services::export_content(accept, export_result)
-                                        } else {
-                                            HttpResponse::NotAcceptable().json(WsError {
-                                                error: "Only PDF or Streams are accepted".into(),
-                                            })
-                                        }
-                                    }
-                                    Err(compiler::HandlerCompilerError::FillingError(e)) => {
-                                        HttpResponse::BadRequest().json(WsError {
-                                            error: format!(
-                                                "Error during document filling: {:#?}",
-                                                e
-                                            ),
-                                        })
-                                    }
-                                    Err(compiler::HandlerCompilerError::Error(message)) => {
-                                        HttpResponse::InternalServerError()
-                                            .json(WsError { error: message })
-                                    }
-                                }
-                            } else {
-                                HttpResponse::NotFound().json(WsError {
-                                    error: "No documents found for this token!".into(),
-                                })
-                            }
-                        }
-                        Err(e) => HttpResponse::BadRequest().json(WsError {
-                            error: format!("Not a valid PDFiller request: {:#?}", e),
-                        }),
-                    }
+    let options = match get_query_options(query.options.as_deref(), &request) {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    let content_type = get_content_type(&request);
+    let (values, maps) = if sheet::is_sheet(content_type.as_deref()) {
+        let profile =
+            match get_mapping_profile(&data, token.as_str(), query.mapping.as_deref()).await {
+                Ok(profile) => profile,
+                Err(response) => return response,
+            };
+
+        match sheet::read_rows(
+            content_type.as_deref(),
+            &bytes,
+            query.sheet.as_deref(),
+            profile.as_ref(),
+        ) {
+            Ok(maps) if !maps.is_empty() => (options, maps),
+            Ok(_) => {
+                return HttpResponse::BadRequest().json(WsError {
+                    error: "The batch has no rows.".into(),
+                });
+            }
+            Err(e) => {
+                return HttpResponse::BadRequest().json(WsError {
+                    error: format!("{}.", e),
+                });
+            }
+        }
+    } else {
+        match batch::parse_body(content_type.as_deref(), &bytes, options) {
+            Ok(body) => body,
+            Err(e) => {
+                return HttpResponse::BadRequest().json(WsError {
+                    error: format!("{}.", e),
+                });
+            }
+        }
+    };
+
+    let config = data.batch.as_ref();
+    let max_rows = config
+        .and_then(|config| config.max_rows)
+        .unwrap_or(DEFAULT_BATCH_MAX_ROWS);
+    if maps.len() > max_rows {
+        return HttpResponse::PayloadTooLarge().json(WsError {
+            error: format!(
+                "The batch has {} rows, at most {} are accepted.",
+                maps.len(),
+                max_rows
+            ),
+        });
+    }
+    let concurrency = config
+        .and_then(|config| config.concurrency)
+        .unwrap_or(DEFAULT_BATCH_CONCURRENCY);
+
+    let options = match get_export_options(&data, token.as_str(), &values).await {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    let accept = match services::get_accepted_header(&request, query.format.as_deref()) {
+        Some(accept)
+            if accept.as_str() == mime::APPLICATION_PDF
+                || accept.as_str() == mime::APPLICATION_OCTET_STREAM =>
+        {
+            accept
+        }
+        _ => {
+            return HttpResponse::NotAcceptable().json(WsError {
+                error: "Only PDF or ZIP files are accepted for batches".into(),
+            });
+        }
+    };
+    let documents = match get_template_documents(&data, token.as_str(), &values).await {
+        Ok(documents) => documents,
+        Err(response) => return response,
+    };
+    let templates = match batch::load_templates(data.file.clone(), documents).await {
+        Ok(templates) => templates,
+        Err(message) => {
+            return HttpResponse::InternalServerError().json(WsError { error: message });
+        }
+    };
+
+    let rows = batch::name_rows(maps, query.key.as_deref());
+    let filename = options.get_filename(token.as_str());
+    if accept.as_str() != mime::APPLICATION_PDF {
+        let export_result = batch::zip_batch(templates, rows, options, concurrency);
+
+        return services::export_content(accept, filename, export_result);
+    }
+
+    match batch::merge_batch(templates, rows, options, concurrency).await {
+        Ok(export) => {
+            let mut response = services::export_content(accept, filename, Ok(export.content));
+            if !export.errors.is_empty() {
+                let headers = response.headers_mut();
+                headers.insert(
+                    header::HeaderName::from_static(BATCH_ERROR_COUNT_HEADER),
+                    header::HeaderValue::from(export.errors.len()),
+                );
+                if let Some(errors) = get_batch_errors_header(&export.errors) {
+                    headers.insert(header::HeaderName::from_static(BATCH_ERRORS_HEADER), errors);
+                }
+            }
+
+            response
+        }
+        Err(batch::BatchFailure::Rows(errors)) => {
+            HttpResponse::UnprocessableEntity().json(BatchErrors {
+                error: "None of the rows could be compiled.".into(),
+                rows: errors,
+            })
+        }
+        Err(batch::BatchFailure::Export(e)) => services::export_content(accept, filename, Err(e)),
+    }
+}
+
+/// JSON array of the first row errors fitting the header, the count header has all of them.
+fn get_batch_errors_header(errors: &[batch::RowError]) -> Option<header::HeaderValue> {
+    let mut entries = Vec::new();
+    let mut size = 2;
+    for error in errors {
+        // Header values must be visible ASCII, the messages may contain anything
+        let entry = serde_json::to_string(error)
+            .ok()?
+            .chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() {
+                    c
                 } else {
+                    '?'
+                }
+            })
+            .collect::<String>();
+        size += entry.len() + 1;
+        if size > BATCH_ERRORS_HEADER_SIZE {
+            break;
+        }
+
+        entries.push(entry);
+    }
+
+    header::HeaderValue::from_str(&format!("[{}]", entries.join(","))).ok()
+}
+
+/// Export options of the request, validated and with the signer loaded.
+pub async fn get_export_options(
+    data: &Data,
+    token: &str,
+    values: &Value,
+) -> Result<compiler::ExportOptions, HttpResponse> {
+    let mut options = match compiler::ExportOptions::deserialize(values) {
+        Ok(options) => options,
+        Err(e) => {
+            return Err(HttpResponse::BadRequest().json(WsError {
+                error: format!("Not valid export options: {:#?}", e),
+            }));
+        }
+    };
+    if let Err(message) = options.validate() {
+        return Err(HttpResponse::BadRequest().json(WsError { error: message }));
+    }
+    if let Some(ref signature_options) = options.signature {
+        match signature::get_signer(data, token, signature_options).await {
+            Ok(signer) => options.signer = Some(signer),
+            Err(e) => {
+                return Err(HttpResponse::BadRequest().json(WsError {
+                    error: format!("{}.", e),
+                }));
+            }
+        }
+    }
+    if let Some(ref mut cover) = options.cover {
+        if let Some(ref template) = cover.template {
+            cover.template_buffer = Some(get_cover_template(data, token, template).await?);
+        }
+    }
+
+    Ok(options)
+}
+
+/// Cover templates are documents uploaded for the token, they're never downloaded.
+async fn get_cover_template(data: &Data, token: &str, id: &str) -> Result<Vec<u8>, HttpResponse> {
+    let document = match data.get_document(id).await {
+        Some(document) if document.token == token => document,
+        _ => {
+            return Err(HttpResponse::NotFound().json(WsError {
+                error: format!("Cover template {} not found for this token!", id),
+            }));
+        }
+    };
+
+    match data.file.load(&document.file).await {
+        Ok(buffer) => Ok(buffer),
+        Err(e) => {
+            sentry::capture_error(&e);
+
+            Err(HttpResponse::InternalServerError().json(WsError {
+                error: format!("An error occurred loading the cover template: {}", e),
+            }))
+        }
+    }
+}
+
+/// The latest version of the templates, or the ones pinned by name in `versions`.
+pub async fn get_template_documents(
+    data: &Data,
+    token: &str,
+    values: &Value,
+) -> Result<Vec<Document>, HttpResponse> {
+    let versions = match values.get("versions") {
+        Some(value) => <HashMap<String, u32>>::deserialize(value).map_err(|e| {
+            HttpResponse::BadRequest().json(WsError {
+                error: format!("Not valid template versions: {:#?}", e),
+            })
+        })?,
+        None => HashMap::new(),
+    };
+
+    let documents = match data.get_templates_by_token(token, &versions).await {
+        Some(documents) if !documents.is_empty() => documents,
+        _ => {
+            return Err(HttpResponse::NotFound().json(WsError {
+                error: "No documents found for this token!".into(),
+            }));
+        }
+    };
+    for (name, version) in versions.iter() {
+        if !documents.iter().any(|document| {
+            document.template_name() == *name && document.template_version() == *version
+        }) {
+            return Err(HttpResponse::NotFound().json(WsError {
+                error: format!(
+                    "Version {} of the template \"{}\" not found!",
+                    version, name
+                ),
+            }));
+        }
+    }
+
+    Ok(documents)
+}
+
+/// The `data` map filling the documents.
+pub fn get_data_map(values: &Value) -> Result<compiler::PDFillerMap, HttpResponse> {
+    match values.get("data") {
+        Some(value) => <compiler::PDFillerMap>::deserialize(value).map_err(|e| {
+            HttpResponse::BadRequest().json(WsError {
+                error: format!("Not a valid PDFiller request: {:#?}", e),
+            })
+        }),
+        None => Err(HttpResponse::BadRequest().json(WsError {
+            error: "Not a valid PDFiller request.".into(),
+        })),
+    }
+}
+
+/// A compilation of the documents with the data, its files are written under a path of its own.
+pub fn new_compilation(
+    data: &Data,
+    token: &str,
+    map: &compiler::PDFillerMap,
+    documents: &[Document],
+) -> Compilation {
+    let mut compilation = Compilation::new(token.to_string(), compiler::get_data_hash(map));
+    compilation.path = data.file.generate_compilation_path(compilation.id());
+    compilation.documents = documents.to_vec();
+
+    compilation
+}
+
+/// Exports the documents compiled by the compilation as the accepted content type.
+pub async fn export_documents(
+    data: &Data,
+    documents: Vec<Document>,
+    options: compiler::ExportOptions,
+    accept: &str,
+    compilation: &str,
+) -> compiler::ExportCompilerResult<compiler::ExportedContent> {
+    let compilation = Some(compilation.to_string());
+    if let Some(format) = render::ImageFormat::from_mime(accept) {
+        compiler::render_documents(
+            data.file.clone(),
+            documents,
+            compilation,
+            options,
+            format,
+            data.render.as_ref(),
+        )
+        .await
+    } else if accept == mime::APPLICATION_PDF {
+        compiler::merge_documents(data.file.clone(), documents, compilation, options).await
+    } else {
+        compiler::zip_documents(data.file.clone(), documents, compilation, options).await
+    }
+}
+
+/// Reads the request as JSON, or as FDF, XFDF and single row CSV and XLSX data whose export
+/// options come in the `options` query parameter as JSON.
+pub async fn get_request_values(
+    data: &Data,
+    token: &str,
+    request: &web::HttpRequest,
+    query: &CompileQuery,
+    bytes: &[u8],
+) -> Result<Value, HttpResponse> {
+    let content_type = get_content_type(request);
+
+    let map = match content_type.as_deref() {
+        Some(fdf::FDF_CONTENT_TYPE) => fdf::parse_fdf(bytes),
+        Some(fdf::XFDF_CONTENT_TYPE) => fdf::parse_xfdf(bytes),
+        content_type if sheet::is_sheet(content_type) => {
+            let profile = get_mapping_profile(data, token, query.mapping.as_deref()).await?;
+
+            match sheet::read_rows(
+                content_type,
+                bytes,
+                query.sheet.as_deref(),
+                profile.as_ref(),
+            ) {
+                Ok(mut maps) if maps.len() == 1 => Ok(maps.remove(0)),
+                Ok(maps) => {
+                    return Err(HttpResponse::BadRequest().json(WsError {
+                        error: format!(
+                            "The file has {} rows, a single one is compiled, use the batch endpoint for more.",
+                            maps.len()
+                        ),
+                    }));
+                }
+                Err(e) => {
+                    return Err(HttpResponse::BadRequest().json(WsError {
+                        error: format!("{}.", e),
+                    }));
+                }
+            }
+        }
+        _ => {
+            return match str::from_utf8(bytes) {
+                Ok(body) => serde_json::from_str::<Value>(body).map_err(|e| {
                     HttpResponse::BadRequest().json(WsError {
-                        error: "Not a valid PDFiller request.".into(),
+                        error: format!("Couldn't decode the body as JSON: {:#?}", e),
                     })
-                }
+                }),
+                Err(e) => Err(HttpResponse::InternalServerError().json(WsError {
+                    error: format!("Error decoding the body: {:#?}", e),
+                })),
+            };
+        }
+    };
+
+    match map {
+        Ok(map) => {
+            let mut values = get_query_options(query.options.as_deref(), request)?;
+            values["data"] = Value::Object(map.into_iter().collect());
+
+            Ok(values)
+        }
+        Err(e) => Err(HttpResponse::BadRequest().json(WsError {
+            error: format!("{}.", e),
+        })),
+    }
+}
+
+/// The mapping profile named in the query, a missing profile is an error.
+async fn get_mapping_profile(
+    data: &Data,
+    token: &str,
+    name: Option<&str>,
+) -> Result<Option<MappingProfile>, HttpResponse> {
+    match name {
+        Some(name) => match data.get_mapping_profile(token, name).await {
+            Some(profile) => Ok(Some(profile)),
+            None => Err(HttpResponse::NotFound().json(WsError {
+                error: format!("No mapping profile \"{}\" found for this token!", name),
+            })),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Export options given as JSON in the `options` query parameter, for bodies holding only data.
+/// Query strings end up in logs, passwords are only read from the `PASSWORD_HEADERS`.
+pub fn get_query_options(
+    options: Option<&str>,
+    request: &web::HttpRequest,
+) -> Result<Value, HttpResponse> {
+    let mut values = match options {
+        Some(options) => match serde_json::from_str::<Value>(options) {
+            Ok(values) if values.is_object() => values,
+            _ => {
+                return Err(HttpResponse::BadRequest().json(WsError {
+                    error: "Not valid export options: a JSON object is expected".into(),
+                }));
             }
-            Err(e) => HttpResponse::BadRequest().json(WsError {
-                error: format!("Couldn't decode the body as JSON: {:#?}", e),
-            }),
         },
-        Err(e) => HttpResponse::InternalServerError().json(WsError {
-            error: format!("Error decoding the body: {:#?}", e),
-        }),
+        None => Value::Object(Default::default()),
+    };
+    if has_password(&values) {
+        return Err(HttpResponse::BadRequest().json(WsError {
+            error: format!(
+                "Passwords can't be sent in the query string, use the {} headers.",
+                PASSWORD_HEADERS
+                    .iter()
+                    .map(|(name, _, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }));
+    }
+
+    for &(name, section, key) in PASSWORD_HEADERS.iter() {
+        if let Some(password) = request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+        {
+            // Only the options ask for a signature, the header gives its password
+            if !values[section].is_object() {
+                if section == "signature" {
+                    continue;
+                }
+                values[section] = Value::Object(Default::default());
+            }
+            values[section][key] = Value::String(password.to_string());
+        }
     }
+
+    Ok(values)
+}
+
+fn has_password(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map
+            .iter()
+            .any(|(key, value)| key.ends_with("password") || has_password(value)),
+        Value::Array(values) => values.iter().any(has_password),
+        _ => false,
+    }
+}
+
+/// Media type of the body without its parameters.
+fn get_content_type(request: &web::HttpRequest) -> Option<String> {
+    request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|content_type| content_type.trim().to_lowercase())
 }
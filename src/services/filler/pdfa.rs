@@ -0,0 +1,478 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use lopdf::{Dictionary, Document as PdfDocument, Object, ObjectId, Stream, StringFormat};
+use lopdf_security::Document as SerializableDocument;
+use serde::{Deserialize, Serialize};
+
+use crate::services::filler::metadata::{self, Info, MetadataOptions};
+use crate::services::filler::processor::DocumentPages;
+
+const PDFA_VERSION: &str = "1.7";
+const PDFA_2B: &str = "PDF/A-2b";
+const PDFA_ID_XMP: &str = "<rdf:Description rdf:about=\"\" \
+                           xmlns:pdfaid=\"http://www.aiim.org/pdfa/ns/id/\">\n\
+                           <pdfaid:part>2</pdfaid:part>\n\
+                           <pdfaid:conformance>B</pdfaid:conformance>\n\
+                           </rdf:Description>\n";
+
+const OUTPUT_CONDITION: &str = "sRGB IEC61966-2.1";
+const DOCUMENT_ID_LENGTH: usize = 16;
+
+const ANNOTATION_INVISIBLE: i64 = 1;
+const ANNOTATION_HIDDEN: i64 = 1 << 1;
+const ANNOTATION_PRINT: i64 = 1 << 2;
+const ANNOTATION_NO_VIEW: i64 = 1 << 5;
+
+const FORBIDDEN_ACTIONS: [&str; 11] = [
+    "Launch",
+    "Sound",
+    "Movie",
+    "ResetForm",
+    "ImportData",
+    "Hide",
+    "SetOCGState",
+    "Rendition",
+    "Trans",
+    "GoTo3DView",
+    "JavaScript",
+];
+const FORBIDDEN_ANNOTATIONS: [&str; 4] = ["3D", "Sound", "Screen", "Movie"];
+const ACTION_KEYS: [&str; 3] = ["A", "OpenAction", "Next"];
+const FONT_FILE_KEYS: [&str; 3] = ["FontFile", "FontFile2", "FontFile3"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum OutputProfile {
+    #[serde(rename = "pdfa-2b")]
+    PdfA2b,
+}
+
+/// Outcome of the conversion: `fixed` lists the changes made to the document while `issues`
+/// lists what couldn't be made conformant.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConformanceReport {
+    pub profile: String,
+    pub conformant: bool,
+    pub fixed: Vec<String>,
+    pub issues: Vec<String>,
+}
+
+#[derive(Default)]
+struct Findings {
+    fixed: BTreeSet<String>,
+    issues: BTreeSet<String>,
+}
+
+/// Converts the document to PDF/A-2b: the sRGB OutputIntent, the Info dictionary with the
+/// matching XMP packet and a document ID are added while JavaScript and the other forbidden
+/// actions are removed. Fonts can't be embedded so they are only reported.
+pub fn convert(
+    document: &mut PdfDocument,
+    page_map: &[DocumentPages],
+    options: &MetadataOptions,
+) -> Result<ConformanceReport, lopdf::Error> {
+    let mut findings = Findings::default();
+
+    document.version = PDFA_VERSION.into();
+
+    if document.trailer.remove(b"Encrypt").is_some() {
+        findings.fixed.insert("Encryption removed".into());
+    }
+
+    remove_forbidden_actions(document, &mut findings);
+    fix_catalog(document, &mut findings)?;
+    fix_annotations(document, &mut findings);
+    check_fonts(document, &mut findings);
+    check_streams(document, &mut findings);
+
+    set_output_intent(document)?;
+    set_document_id(document);
+
+    let info = metadata::set_metadata(
+        document,
+        page_map,
+        &MetadataOptions {
+            xmp: Some(false),
+            ..options.clone()
+        },
+    )?;
+    // Custom properties would need an XMP extension schema, they are kept in the Info dictionary
+    let xmp_info = Info {
+        custom: BTreeMap::new(),
+        ..info
+    };
+    metadata::set_xmp(document, &xmp_info.to_xmp(PDFA_ID_XMP))?;
+
+    Ok(ConformanceReport {
+        profile: PDFA_2B.into(),
+        conformant: findings.issues.is_empty(),
+        fixed: findings.fixed.into_iter().collect(),
+        issues: findings.issues.into_iter().collect(),
+    })
+}
+
+/// lopdf 0.26 doesn't write the binary comment after the header required by PDF/A, the document
+/// is written again with a release that does.
+pub fn write_binary_header(buffer: Vec<u8>) -> Result<Vec<u8>, lopdf_security::Error> {
+    let mut document = SerializableDocument::load_mem(&buffer)?;
+
+    let mut serialized = Vec::new();
+    document.save_to(&mut serialized)?;
+
+    Ok(serialized)
+}
+
+fn is_forbidden_action(document: &PdfDocument, object: &Object) -> bool {
+    document
+        .dereference(object)
+        .and_then(|(_, object)| object.as_dict())
+        .and_then(|action| action.get(b"S"))
+        .and_then(Object::as_name_str)
+        .map(|action| FORBIDDEN_ACTIONS.contains(&action))
+        .unwrap_or(false)
+}
+
+fn remove_forbidden_actions(document: &mut PdfDocument, findings: &mut Findings) {
+    let mut forbidden = Vec::new();
+    for (object_id, object) in document.objects.iter() {
+        let dictionary = match object {
+            Object::Dictionary(dictionary) => dictionary,
+            Object::Stream(stream) => &stream.dict,
+            _ => continue,
+        };
+
+        for key in ACTION_KEYS.iter() {
+            if let Ok(action) = dictionary.get(key.as_bytes()) {
+                if is_forbidden_action(document, action) {
+                    forbidden.push((*object_id, *key));
+                }
+            }
+        }
+    }
+
+    for (object_id, key) in forbidden {
+        if let Some(dictionary) = get_dictionary_mut(document, object_id) {
+            dictionary.remove(key.as_bytes());
+            findings
+                .fixed
+                .insert("JavaScript and forbidden actions removed".into());
+        }
+    }
+
+    for object in document.objects.values_mut() {
+        let dictionary = match object {
+            Object::Dictionary(dictionary) => dictionary,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+
+        if dictionary.remove(b"AA").is_some() {
+            findings.fixed.insert("Additional actions removed".into());
+        }
+    }
+}
+
+fn fix_catalog(document: &mut PdfDocument, findings: &mut Findings) -> Result<(), lopdf::Error> {
+    let catalog_id = document.trailer.get(b"Root")?.as_reference()?;
+
+    let names_id = document
+        .get_dictionary(catalog_id)?
+        .get(b"Names")
+        .and_then(Object::as_reference)
+        .ok();
+    let names = match names_id {
+        Some(names_id) => get_dictionary_mut(document, names_id),
+        None => document
+            .get_object_mut(catalog_id)?
+            .as_dict_mut()?
+            .get_mut(b"Names")
+            .and_then(Object::as_dict_mut)
+            .ok(),
+    };
+    if let Some(names) = names {
+        if names.remove(b"JavaScript").is_some() {
+            findings
+                .fixed
+                .insert("JavaScript and forbidden actions removed".into());
+        }
+        if names.has(b"EmbeddedFiles") {
+            findings
+                .issues
+                .insert("Embedded files may not be PDF/A conformant".into());
+        }
+    }
+
+    let acroform_id = document
+        .get_dictionary(catalog_id)?
+        .get(b"AcroForm")
+        .and_then(Object::as_reference)
+        .ok();
+    let acroform = match acroform_id {
+        Some(acroform_id) => get_dictionary_mut(document, acroform_id),
+        None => document
+            .get_object_mut(catalog_id)?
+            .as_dict_mut()?
+            .get_mut(b"AcroForm")
+            .and_then(Object::as_dict_mut)
+            .ok(),
+    };
+    if let Some(acroform) = acroform {
+        if acroform.remove(b"XFA").is_some() {
+            findings.fixed.insert("XFA forms removed".into());
+        }
+        if let Ok(Object::Boolean(true)) = acroform.get(b"NeedAppearances") {
+            acroform.remove(b"NeedAppearances");
+            findings
+                .fixed
+                .insert("NeedAppearances flag removed from the form".into());
+        }
+    }
+
+    Ok(())
+}
+
+fn fix_annotations(document: &mut PdfDocument, findings: &mut Findings) {
+    for object in document.objects.values_mut() {
+        let annotation = match object {
+            Object::Dictionary(dictionary)
+                if dictionary.type_is(b"Annot")
+                    || (dictionary.has(b"Subtype") && dictionary.has(b"Rect")) =>
+            {
+                dictionary
+            }
+            _ => continue,
+        };
+
+        let subtype = annotation
+            .get(b"Subtype")
+            .and_then(Object::as_name_str)
+            .unwrap_or("")
+            .to_string();
+
+        if FORBIDDEN_ANNOTATIONS.contains(&subtype.as_str()) {
+            findings
+                .issues
+                .insert(format!("{} annotations aren't allowed", subtype));
+            continue;
+        }
+        if subtype == "Popup" {
+            continue;
+        }
+
+        let flags = annotation.get(b"F").and_then(Object::as_i64).unwrap_or(0);
+        let conformant_flags = (flags | ANNOTATION_PRINT)
+            & !(ANNOTATION_INVISIBLE | ANNOTATION_HIDDEN | ANNOTATION_NO_VIEW);
+        if flags != conformant_flags {
+            annotation.set("F", conformant_flags);
+            findings
+                .fixed
+                .insert("Annotations made printable and visible".into());
+        }
+
+        if subtype != "Link" && !annotation.has(b"AP") {
+            findings.issues.insert(format!(
+                "{} annotations without an appearance stream",
+                subtype
+            ));
+        }
+    }
+}
+
+fn check_fonts(document: &PdfDocument, findings: &mut Findings) {
+    for object in document.objects.values() {
+        let font = match object.as_dict() {
+            Ok(dictionary) if dictionary.type_is(b"Font") => dictionary,
+            _ => continue,
+        };
+
+        let subtype = font
+            .get(b"Subtype")
+            .and_then(Object::as_name_str)
+            .unwrap_or("");
+        if subtype == "Type3" {
+            continue;
+        }
+
+        let descriptor_font = if subtype == "Type0" {
+            font.get(b"DescendantFonts")
+                .and_then(|fonts| document.dereference(fonts))
+                .and_then(|(_, fonts)| fonts.as_array())
+                .ok()
+                .and_then(|fonts| fonts.first())
+                .and_then(|font| document.dereference(font).ok())
+                .and_then(|(_, font)| font.as_dict().ok())
+        } else {
+            Some(font)
+        };
+
+        let embedded = descriptor_font
+            .and_then(|font| font.get(b"FontDescriptor").ok())
+            .and_then(|descriptor| document.dereference(descriptor).ok())
+            .and_then(|(_, descriptor)| descriptor.as_dict().ok())
+            .map(|descriptor| {
+                FONT_FILE_KEYS
+                    .iter()
+                    .any(|key| descriptor.has(key.as_bytes()))
+            })
+            .unwrap_or(false);
+
+        if !embedded {
+            let name = font
+                .get(b"BaseFont")
+                .and_then(Object::as_name_str)
+                .unwrap_or("unknown");
+
+            findings
+                .issues
+                .insert(format!("Font {} isn't embedded", name));
+        }
+    }
+}
+
+fn check_streams(document: &PdfDocument, findings: &mut Findings) {
+    for object in document.objects.values() {
+        if let Object::Stream(stream) = object {
+            let lzw = match stream.dict.get(b"Filter") {
+                Ok(Object::Name(filter)) => filter == b"LZWDecode",
+                Ok(Object::Array(filters)) => filters
+                    .iter()
+                    .any(|filter| filter.as_name().ok() == Some(b"LZWDecode".as_ref())),
+                _ => false,
+            };
+            if lzw {
+                findings
+                    .issues
+                    .insert("Streams compressed with LZW aren't allowed".into());
+            }
+
+            if stream.dict.has(b"F") {
+                findings
+                    .issues
+                    .insert("Streams referencing external files aren't allowed".into());
+            }
+        }
+    }
+}
+
+fn set_output_intent(document: &mut PdfDocument) -> Result<(), lopdf::Error> {
+    let mut profile_dictionary = Dictionary::new();
+    profile_dictionary.set("N", 3);
+    let profile_id = document.add_object(Stream::new(profile_dictionary, srgb_icc_profile()));
+
+    let mut output_intent = Dictionary::new();
+    output_intent.set("Type", Object::Name(b"OutputIntent".to_vec()));
+    output_intent.set("S", Object::Name(b"GTS_PDFA1".to_vec()));
+    output_intent.set(
+        "OutputConditionIdentifier",
+        Object::string_literal(OUTPUT_CONDITION),
+    );
+    output_intent.set("Info", Object::string_literal(OUTPUT_CONDITION));
+    output_intent.set("DestOutputProfile", profile_id);
+    let output_intent_id = document.add_object(output_intent);
+
+    let catalog_id = document.trailer.get(b"Root")?.as_reference()?;
+    document
+        .get_object_mut(catalog_id)?
+        .as_dict_mut()?
+        .set("OutputIntents", vec![Object::Reference(output_intent_id)]);
+
+    Ok(())
+}
+
+fn set_document_id(document: &mut PdfDocument) {
+    let id = rand::random::<[u8; DOCUMENT_ID_LENGTH]>().to_vec();
+
+    document.trailer.set(
+        "ID",
+        vec![
+            Object::String(id.clone(), StringFormat::Hexadecimal),
+            Object::String(id, StringFormat::Hexadecimal),
+        ],
+    );
+}
+
+fn get_dictionary_mut(document: &mut PdfDocument, object_id: ObjectId) -> Option<&mut Dictionary> {
+    match document.get_object_mut(object_id) {
+        Ok(Object::Dictionary(dictionary)) => Some(dictionary),
+        Ok(Object::Stream(stream)) => Some(&mut stream.dict),
+        _ => None,
+    }
+}
+
+/// Minimal ICC v2 display profile describing sRGB with D50 adapted primaries and a 2.2 gamma.
+fn srgb_icc_profile() -> Vec<u8> {
+    fn s15_fixed16(value: f64) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    fn xyz(x: f64, y: f64, z: f64) -> Vec<u8> {
+        [
+            b"XYZ ".as_ref(),
+            &[0; 4],
+            &s15_fixed16(x),
+            &s15_fixed16(y),
+            &s15_fixed16(z),
+        ]
+        .concat()
+    }
+
+    let description = [
+        b"desc".as_ref(),
+        &[0; 4],
+        &(OUTPUT_CONDITION.len() as u32 + 1).to_be_bytes(),
+        OUTPUT_CONDITION.as_bytes(),
+        &[0],
+        &[0; 4 + 4 + 2 + 1 + 67],
+    ]
+    .concat();
+    let copyright = [b"text".as_ref(), &[0; 4], b"No copyright, use freely", &[0]].concat();
+    let gamma = [
+        b"curv".as_ref(),
+        &[0; 4],
+        &1u32.to_be_bytes(),
+        &0x0233u16.to_be_bytes(),
+    ]
+    .concat();
+
+    let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", description),
+        (b"cprt", copyright),
+        (b"wtpt", xyz(0.9642, 1.0, 0.8249)),
+        (b"rXYZ", xyz(0.4361, 0.2225, 0.0139)),
+        (b"gXYZ", xyz(0.3851, 0.7169, 0.0971)),
+        (b"bXYZ", xyz(0.1431, 0.0606, 0.7141)),
+        (b"rTRC", gamma.clone()),
+        (b"gTRC", gamma.clone()),
+        (b"bTRC", gamma),
+    ];
+
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    let data_offset = 128 + 4 + tags.len() * 12;
+    for (signature, tag) in tags.iter() {
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&((data_offset + data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+
+        data.extend_from_slice(tag);
+        data.resize(data.len().div_ceil(4) * 4, 0);
+    }
+
+    let size = 128 + table.len() + data.len();
+
+    let mut header = Vec::with_capacity(128);
+    header.extend_from_slice(&(size as u32).to_be_bytes());
+    header.extend_from_slice(&[0; 4]);
+    header.extend_from_slice(&0x0210_0000u32.to_be_bytes());
+    header.extend_from_slice(b"mntrRGB XYZ ");
+    for value in [2021u16, 1, 1, 0, 0, 0].iter() {
+        header.extend_from_slice(&value.to_be_bytes());
+    }
+    header.extend_from_slice(b"acsp");
+    header.extend_from_slice(&[0; 28]);
+    header.extend_from_slice(&s15_fixed16(0.9642));
+    header.extend_from_slice(&s15_fixed16(1.0));
+    header.extend_from_slice(&s15_fixed16(0.8249));
+    header.resize(128, 0);
+
+    [header, table, data].concat()
+}
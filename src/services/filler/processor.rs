@@ -1,56 +1,126 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
 use std::str;
 
+use async_std::channel::Sender;
 use async_std::sync::Arc;
+use chrono::{DateTime, Utc};
 use log::error;
 use lopdf::{Dictionary, Document as PdfDocument, Object, ObjectId};
 
 use crate::file::FileProvider;
 use crate::mongo::models::document::Document;
+use crate::services::filler::compiler::ExportChunk;
+use crate::services::filler::writer::PdfStreamWriter;
 
 const PDF_VERSION: &str = "1.5";
+const STREAM_PAGES_ID: ObjectId = (1, 0);
+const INHERITABLE_PAGE_KEYS: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+const SKIPPED_TYPES: [&str; 7] = [
+    "Catalog",
+    "Pages",
+    "Outlines",
+    "Outline",
+    "ObjStm",
+    "XRef",
+    "Linearized",
+];
 
+#[derive(Default)]
 pub struct DocumentObjects {
     pub objects: BTreeMap<ObjectId, Object>,
     pub pages: BTreeMap<ObjectId, Object>,
+    pub documents: Vec<DocumentPages>,
 }
 
-pub fn get_documents_containers<F: FileProvider + ?Sized>(
+impl DocumentObjects {
+    /// Renumbers the objects of the document after the ones already added.
+    fn add_document(
+        &mut self,
+        document: &Document,
+        mut pdf_document: PdfDocument,
+        max_id: &mut u32,
+    ) {
+        pdf_document.renumber_objects_with(*max_id);
+
+        *max_id = pdf_document.max_id + 1;
+
+        self.documents
+            .push(DocumentPages::new(document, &pdf_document));
+
+        self.pages.extend(
+            pdf_document
+                .get_pages()
+                .into_values()
+                .map(|object_id| {
+                    (
+                        object_id,
+                        pdf_document.get_object(object_id).unwrap().to_owned(),
+                    )
+                })
+                .collect::<BTreeMap<ObjectId, Object>>(),
+        );
+        self.objects.extend(pdf_document.objects);
+    }
+}
+
+/// Pages belonging to a single source document, in reading order, with its Info dictionary.
+pub struct DocumentPages {
+    pub title: String,
+    pub date: DateTime<Utc>,
+    pub pages: Vec<ObjectId>,
+    pub info: Dictionary,
+}
+
+impl DocumentPages {
+    pub fn new(document: &Document, pdf_document: &PdfDocument) -> Self {
+        Self {
+            title: document.title(),
+            date: document.date,
+            pages: pdf_document.get_pages().into_values().collect(),
+            info: pdf_document
+                .trailer
+                .get(b"Info")
+                .and_then(|info| pdf_document.dereference(info))
+                .and_then(|(_, info)| info.as_dict())
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Object ids change when the merged document is renumbered, the page map is rebuilt from the
+/// final page tree keeping the page count of every document.
+pub fn update_page_map(document: &PdfDocument, page_map: &mut [DocumentPages]) {
+    let mut pages = document.get_pages().into_values();
+    for document_pages in page_map.iter_mut() {
+        document_pages.pages = pages.by_ref().take(document_pages.pages.len()).collect();
+    }
+}
+
+/// Loads the documents from the file provider for merges processed as a whole, such as signed or
+/// encrypted ones, which unlike `stream_documents` are kept in memory and not streamed.
+pub async fn get_documents_containers<F: FileProvider + ?Sized>(
     file_type: Arc<Box<F>>,
     documents: Vec<Document>,
-    compiled: bool,
+    compilation: Option<&str>,
 ) -> DocumentObjects {
     let mut max_id = 1;
-
-    let mut documents_pages = BTreeMap::new();
-    let mut documents_objects = BTreeMap::new();
+    let mut documents_objects = DocumentObjects::default();
 
     for document in documents {
-        if let Some(ref file_name) = if compiled {
-            file_type.generate_compiled_filepath(&document.file)
-        } else {
-            Some(document.file)
-        } {
-            match PdfDocument::load(file_name) {
-                Ok(mut document) => {
-                    document.renumber_objects_with(max_id);
-
-                    max_id = document.max_id + 1;
-
-                    documents_pages.extend(
-                        document
-                            .get_pages()
-                            .into_iter()
-                            .map(|(_, object_id)| {
-                                (
-                                    object_id,
-                                    document.get_object(object_id).unwrap().to_owned(),
-                                )
-                            })
-                            .collect::<BTreeMap<ObjectId, Object>>(),
-                    );
-                    documents_objects.extend(document.objects);
-                }
+        if let Some(ref file_path) = file_type.get_document_filepath(&document.file, compilation) {
+            match file_type.load(file_path).await {
+                Ok(buffer) => match PdfDocument::load_mem(&buffer) {
+                    Ok(pdf_document) => {
+                        documents_objects.add_document(&document, pdf_document, &mut max_id)
+                    }
+                    Err(e) => {
+                        sentry::capture_error(&e);
+
+                        error!("Error loading the PDF: {:#?}", e);
+                    }
+                },
                 Err(e) => {
                     sentry::capture_error(&e);
 
@@ -60,13 +130,22 @@ pub fn get_documents_containers<F: FileProvider + ?Sized>(
         }
     }
 
-    DocumentObjects {
-        pages: documents_pages,
-        objects: documents_objects,
+    documents_objects
+}
+
+/// Containers of documents already in memory, like the filled forms of a batch.
+pub fn get_loaded_containers(documents: Vec<(Document, PdfDocument)>) -> DocumentObjects {
+    let mut max_id = 1;
+    let mut documents_objects = DocumentObjects::default();
+
+    for (document, pdf_document) in documents {
+        documents_objects.add_document(&document, pdf_document, &mut max_id);
     }
+
+    documents_objects
 }
 
-pub fn process_documents(documents_objects: DocumentObjects) -> Option<PdfDocument> {
+pub fn process_documents(documents_objects: &DocumentObjects) -> Option<PdfDocument> {
     let mut document = PdfDocument::with_version(PDF_VERSION);
 
     let mut catalog_object: Option<(ObjectId, Object)> = None;
@@ -86,7 +165,7 @@ pub fn process_documents(documents_objects: DocumentObjects) -> Option<PdfDocume
             }
             "Pages" => {
                 if let Some(dictionary) =
-                    upsert_dictionary(&object, pages_object.as_ref().map(|(_, object)| object))
+                    upsert_dictionary(object, pages_object.as_ref().map(|(_, object)| object))
                 {
                     pages_object = Some((
                         if let Some((id, _)) = pages_object {
@@ -128,6 +207,15 @@ pub fn process_documents(documents_objects: DocumentObjects) -> Option<PdfDocume
     if let Ok(dictionary) = pages_object.1.as_dict() {
         let mut dictionary = dictionary.clone();
         dictionary.set("Count", documents_objects.pages.len() as u32);
+        dictionary.set(
+            "Kids",
+            documents_objects
+                .documents
+                .iter()
+                .flat_map(|document| document.pages.iter())
+                .map(|object_id| Object::Reference(*object_id))
+                .collect::<Vec<_>>(),
+        );
 
         document
             .objects
@@ -168,3 +256,160 @@ fn upsert_dictionary(object: &Object, other_object: Option<&Object>) -> Option<D
         None
     }
 }
+
+/// Merges the documents like `process_documents` sending every document as soon as it's loaded,
+/// only the page references and the form fields are kept until the page tree is written at the end.
+pub async fn stream_documents<F: FileProvider + ?Sized>(
+    file_type: Arc<Box<F>>,
+    documents: Vec<Document>,
+    compilation: Option<&str>,
+    sender: &Sender<ExportChunk>,
+) -> Result<(), String> {
+    let mut writer = PdfStreamWriter::default();
+    let mut buffer = Vec::new();
+    writer.write_header(&mut buffer, PDF_VERSION);
+
+    let mut max_id = STREAM_PAGES_ID.0 + 1;
+    let mut kids = Vec::new();
+    let mut fields = Vec::new();
+    let mut acroform: Option<Dictionary> = None;
+
+    for document in documents {
+        let file_path = match file_type.get_document_filepath(&document.file, compilation) {
+            Some(file_path) => file_path,
+            None => continue,
+        };
+
+        let mut pdf_document = match file_type.load(&file_path).await {
+            Ok(buffer) => match PdfDocument::load_mem(&buffer) {
+                Ok(pdf_document) => pdf_document,
+                Err(e) => {
+                    sentry::capture_error(&e);
+
+                    error!("Error loading the PDF: {:#?}", e);
+
+                    continue;
+                }
+            },
+            Err(e) => {
+                sentry::capture_error(&e);
+
+                error!("Error loading the PDF: {:#?}", e);
+
+                continue;
+            }
+        };
+
+        pdf_document.renumber_objects_with(max_id);
+        max_id = pdf_document.max_id + 1;
+        pdf_document.compress();
+
+        let pages = pdf_document
+            .get_pages()
+            .into_values()
+            .collect::<BTreeSet<_>>();
+        for page_id in pdf_document.get_pages().into_values() {
+            if let Some(page) = get_inherited_page(&pdf_document, page_id) {
+                pdf_document
+                    .objects
+                    .insert(page_id, Object::Dictionary(page));
+                kids.push(Object::Reference(page_id));
+            }
+        }
+
+        if let Ok(form) = pdf_document
+            .catalog()
+            .and_then(|catalog| catalog.get(b"AcroForm"))
+            .and_then(|form| pdf_document.dereference(form))
+            .and_then(|(_, form)| form.as_dict())
+        {
+            if let Ok(form_fields) = form
+                .get(b"Fields")
+                .and_then(|form_fields| pdf_document.dereference(form_fields))
+                .and_then(|(_, form_fields)| form_fields.as_array())
+            {
+                fields.extend(form_fields.iter().cloned());
+            }
+            if acroform.is_none() {
+                let mut dictionary = form.clone();
+                dictionary.remove(b"Fields");
+                acroform = Some(dictionary);
+            }
+        }
+
+        for (object_id, object) in pdf_document.objects.iter() {
+            let type_name = object.type_name().unwrap_or("");
+            if SKIPPED_TYPES.contains(&type_name)
+                || (type_name == "Page" && !pages.contains(object_id))
+            {
+                continue;
+            }
+
+            writer.write_object(&mut buffer, *object_id, object);
+        }
+
+        // The request was dropped when the receiver is gone
+        sender
+            .send(Ok(mem::take(&mut buffer)))
+            .await
+            .map_err(|e| format!("{:#?}", e))?;
+    }
+
+    if kids.is_empty() {
+        return Err("Cannot extract PDFs documents".into());
+    }
+
+    let mut pages = Dictionary::new();
+    pages.set("Type", Object::Name(b"Pages".to_vec()));
+    pages.set("Count", kids.len() as u32);
+    pages.set("Kids", kids);
+    writer.write_object(&mut buffer, STREAM_PAGES_ID, &Object::Dictionary(pages));
+
+    let catalog_id = (max_id, 0);
+    let mut catalog = Dictionary::new();
+    catalog.set("Type", Object::Name(b"Catalog".to_vec()));
+    catalog.set("Pages", STREAM_PAGES_ID);
+    if !fields.is_empty() {
+        let mut acroform = acroform.unwrap_or_default();
+        acroform.set("Fields", fields);
+        catalog.set("AcroForm", acroform);
+    }
+    writer.write_object(&mut buffer, catalog_id, &Object::Dictionary(catalog));
+
+    let mut trailer = Dictionary::new();
+    trailer.set("Root", catalog_id);
+    writer.write_trailer(&mut buffer, trailer);
+
+    sender
+        .send(Ok(buffer))
+        .await
+        .map_err(|e| format!("{:#?}", e))
+}
+
+/// Returns the page with the attributes inherited from the page tree, the tree isn't kept when
+/// the pages are moved under a single node.
+fn get_inherited_page(document: &PdfDocument, page_id: ObjectId) -> Option<Dictionary> {
+    let mut page = document.get_dictionary(page_id).ok()?.clone();
+
+    let mut visited = BTreeSet::new();
+    let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+    while let Some(parent_id) = parent {
+        if !visited.insert(parent_id) {
+            break;
+        }
+
+        let node = document.get_dictionary(parent_id).ok()?;
+        for key in INHERITABLE_PAGE_KEYS.iter() {
+            if !page.has(key) {
+                if let Ok(value) = node.get(key) {
+                    page.set(*key, value.clone());
+                }
+            }
+        }
+
+        parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+    page.set("Parent", STREAM_PAGES_ID);
+
+    Some(page)
+}
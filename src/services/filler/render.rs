@@ -0,0 +1,220 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use actix_web::error::BlockingError;
+use actix_web::web;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::config::RenderConfig;
+
+const DEFAULT_COMMAND: &str = "pdftoppm";
+const DEFAULT_DPI: u32 = 150;
+const MAX_DPI: u32 = 600;
+const MAX_SIZE: u32 = 10000;
+const PAGE_PREFIX: &str = "page";
+
+#[derive(Clone, Copy)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    pub fn from_mime<S: AsRef<str>>(content_type: S) -> Option<Self> {
+        let content_type = content_type.as_ref();
+        if content_type == mime::IMAGE_PNG {
+            Some(ImageFormat::Png)
+        } else if content_type == mime::IMAGE_JPEG {
+            Some(ImageFormat::Jpeg)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime(&self) -> mime::Mime {
+        match self {
+            ImageFormat::Png => mime::IMAGE_PNG,
+            ImageFormat::Jpeg => mime::IMAGE_JPEG,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+        }
+    }
+
+    fn argument(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "-png",
+            ImageFormat::Jpeg => "-jpeg",
+        }
+    }
+}
+
+/// Rendering of the exported pages, pages are numbered from 1 and `size` scales the longest side
+/// of every page to the given pixels for thumbnails, taking precedence over `dpi`.
+#[derive(Clone, Default, Deserialize)]
+pub struct RenderOptions {
+    pub dpi: Option<u32>,
+    pub first_page: Option<u32>,
+    pub last_page: Option<u32>,
+    pub size: Option<u32>,
+}
+
+impl RenderOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(dpi) = self.dpi {
+            if dpi == 0 || dpi > MAX_DPI {
+                return Err(format!("The DPI must be between 1 and {}", MAX_DPI));
+            }
+        }
+
+        if let Some(size) = self.size {
+            if size == 0 || size > MAX_SIZE {
+                return Err(format!(
+                    "The size must be between 1 and {} pixels",
+                    MAX_SIZE
+                ));
+            }
+        }
+
+        match (self.first_page, self.last_page) {
+            (Some(0), _) | (_, Some(0)) => Err("Pages are numbered from 1".into()),
+            (Some(first_page), Some(last_page)) if first_page > last_page => {
+                Err("The first page comes after the last page".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn arguments(&self, format: ImageFormat) -> Vec<String> {
+        let mut arguments = vec![
+            format.argument().to_string(),
+            "-r".into(),
+            self.dpi.unwrap_or(DEFAULT_DPI).to_string(),
+        ];
+        if let Some(first_page) = self.first_page {
+            arguments.extend(vec!["-f".into(), first_page.to_string()]);
+        }
+        if let Some(last_page) = self.last_page {
+            arguments.extend(vec!["-l".into(), last_page.to_string()]);
+        }
+        if let Some(size) = self.size {
+            arguments.extend(vec!["-scale-to".into(), size.to_string()]);
+        }
+
+        arguments
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    Io(io::Error),
+    Blocking(BlockingError),
+    Renderer(String),
+    NoPages,
+}
+
+impl Display for RenderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderError::Io(e) => write!(f, "Error running the renderer: {}", e),
+            RenderError::Blocking(e) => write!(f, "Error running the renderer: {}", e),
+            RenderError::Renderer(message) => write!(f, "The renderer failed: {}", message),
+            RenderError::NoPages => write!(f, "No pages in the requested range"),
+        }
+    }
+}
+
+impl From<io::Error> for RenderError {
+    fn from(e: io::Error) -> Self {
+        RenderError::Io(e)
+    }
+}
+
+/// Runs the renderer asking for its version, only to know that it's installed.
+pub fn check_renderer(config: Option<&RenderConfig>) -> Result<(), RenderError> {
+    let command = config
+        .and_then(|config| config.command.as_deref())
+        .unwrap_or(DEFAULT_COMMAND);
+
+    Command::new(command).arg("-v").output()?;
+
+    Ok(())
+}
+
+/// Renders the pages with poppler's `pdftoppm` (or the configured command accepting the same
+/// arguments), returning one image per page in reading order.
+pub async fn render_document(
+    buffer: Vec<u8>,
+    format: ImageFormat,
+    options: &RenderOptions,
+    config: Option<&RenderConfig>,
+) -> Result<Vec<Vec<u8>>, RenderError> {
+    let command = config
+        .and_then(|config| config.command.clone())
+        .unwrap_or_else(|| DEFAULT_COMMAND.into());
+    let arguments = options.arguments(format);
+
+    let directory = std::env::temp_dir().join(format!("pdfiller-{}", Uuid::new_v4()));
+    let working_directory = directory.clone();
+    let result = web::block(move || run_renderer(&working_directory, &command, arguments, buffer))
+        .await
+        .map_err(RenderError::Blocking)
+        .and_then(|result| result);
+
+    // The files are read before returning, they're not needed anymore
+    let _ = fs::remove_dir_all(&directory);
+
+    result
+}
+
+fn run_renderer(
+    directory: &Path,
+    command: &str,
+    arguments: Vec<String>,
+    buffer: Vec<u8>,
+) -> Result<Vec<Vec<u8>>, RenderError> {
+    fs::create_dir_all(directory)?;
+
+    let input = directory.join("document.pdf");
+    fs::write(&input, buffer)?;
+
+    let output = Command::new(command)
+        .args(arguments)
+        .arg(&input)
+        .arg(directory.join(PAGE_PREFIX))
+        .output()?;
+    if !output.status.success() {
+        return Err(RenderError::Renderer(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    // Page numbers are zero padded to the same width, names sort in reading order
+    let mut pages = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with(PAGE_PREFIX))
+                .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    pages.sort();
+
+    if pages.is_empty() {
+        return Err(RenderError::NoPages);
+    }
+
+    pages
+        .iter()
+        .map(|page| fs::read(page).map_err(RenderError::from))
+        .collect()
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use async_std::sync::Arc;
+use lopdf_security::encryption::crypt_filters::{Aes256CryptFilter, CryptFilter};
+use lopdf_security::{
+    Dictionary, Document as SecuredDocument, EncryptionState, EncryptionVersion,
+    Error as SecurityLoadError, LoadOptions, Object, Permissions,
+};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+
+const ENCRYPT_KEY: &[u8] = b"/Encrypt";
+const CRYPT_FILTER: &[u8] = b"StdCF";
+const FILE_KEY_LENGTH: usize = 32;
+const OWNER_PASSWORD_LENGTH: usize = 32;
+
+// AES-256 (revision 6) is part of PDF 2.0, declared as Adobe extension level 8 for PDF 1.7
+const NATIVE_AES_256_VERSION: &str = "2.0";
+const ENCRYPTED_PDF_VERSION: &str = "1.7";
+const ADOBE_EXTENSION_LEVEL: i64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Print,
+    Copy,
+    Modify,
+    Fill,
+}
+
+/// Export encryption, PDFs are encrypted with AES-256 when a password or a permission list is
+/// given while `zip_password` encrypts the ZIP entries with AES-256.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EncryptionOptions {
+    pub user_password: Option<String>,
+    /// A random owner password is used when missing so permissions can't be lifted
+    pub owner_password: Option<String>,
+    /// Allowed operations, everything is allowed when missing
+    pub permissions: Option<Vec<Permission>>,
+    pub zip_password: Option<String>,
+}
+
+impl EncryptionOptions {
+    pub fn encrypts_pdf(&self) -> bool {
+        self.user_password.is_some() || self.owner_password.is_some() || self.permissions.is_some()
+    }
+
+    fn get_permissions(&self) -> Permissions {
+        match self.permissions {
+            Some(ref permissions) => permissions.iter().fold(
+                Permissions::COPYABLE_FOR_ACCESSIBILITY,
+                |flags, permission| {
+                    flags
+                        | match permission {
+                            Permission::Print => {
+                                Permissions::PRINTABLE | Permissions::PRINTABLE_IN_HIGH_QUALITY
+                            }
+                            Permission::Copy => Permissions::COPYABLE,
+                            Permission::Modify => {
+                                Permissions::MODIFIABLE
+                                    | Permissions::ANNOTABLE
+                                    | Permissions::ASSEMBLABLE
+                            }
+                            Permission::Fill => Permissions::FILLABLE,
+                        }
+                },
+            ),
+            None => Permissions::all(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SecurityError {
+    PasswordRequired,
+    InvalidPassword,
+    Unsupported(String),
+    Malformed(String),
+    Encryption(String),
+}
+
+impl fmt::Display for SecurityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PasswordRequired => {
+                write!(f, "The PDF is password protected, a password is required")
+            }
+            Self::InvalidPassword => {
+                write!(f, "The password isn't valid for this PDF")
+            }
+            Self::Unsupported(message) => {
+                write!(f, "The PDF encryption isn't supported: {}", message)
+            }
+            Self::Malformed(message) => {
+                write!(f, "The encrypted PDF couldn't be read: {}", message)
+            }
+            Self::Encryption(message) => {
+                write!(f, "The PDF couldn't be encrypted: {}", message)
+            }
+        }
+    }
+}
+
+/// Returns the template without encryption so it can be loaded by the filler. Documents using the
+/// standard security handler (RC4, AES-128 or AES-256) are decrypted with the user or the owner
+/// password, documents protected by an owner password only are decrypted without one.
+pub fn decrypt_template(buffer: Vec<u8>, password: Option<&str>) -> Result<Vec<u8>, SecurityError> {
+    // Cheap check to avoid parsing twice documents that aren't encrypted at all
+    if !buffer
+        .windows(ENCRYPT_KEY.len())
+        .any(|window| window == ENCRYPT_KEY)
+    {
+        return Ok(buffer);
+    }
+
+    let options = match password {
+        Some(password) => LoadOptions::with_password(password),
+        None => LoadOptions::default(),
+    };
+
+    let mut document = match SecuredDocument::load_mem_with_options(&buffer, options) {
+        Ok(document) => document,
+        Err(SecurityLoadError::InvalidPassword) => {
+            return Err(SecurityError::InvalidPassword);
+        }
+        Err(SecurityLoadError::UnsupportedSecurityHandler(filter)) => {
+            return Err(SecurityError::Unsupported(format!(
+                "security handler {}",
+                String::from_utf8_lossy(&filter)
+            )));
+        }
+        Err(SecurityLoadError::Decryption(e)) => {
+            return Err(SecurityError::Unsupported(e.to_string()));
+        }
+        Err(e) => {
+            return Err(SecurityError::Malformed(e.to_string()));
+        }
+    };
+
+    if !document.was_encrypted() {
+        return if document.is_encrypted() {
+            // Authentication failed without a password, objects are left encrypted
+            Err(if password.is_some() {
+                SecurityError::InvalidPassword
+            } else {
+                SecurityError::PasswordRequired
+            })
+        } else {
+            Ok(buffer)
+        };
+    }
+
+    document.encryption_state = None;
+
+    let mut decrypted = Vec::new();
+    document
+        .save_to(&mut decrypted)
+        .map_err(|e| SecurityError::Malformed(e.to_string()))?;
+
+    Ok(decrypted)
+}
+
+/// Encrypts the PDF with AES-256 using the passwords and the permissions of the options.
+pub fn encrypt_document(
+    buffer: Vec<u8>,
+    options: &EncryptionOptions,
+) -> Result<Vec<u8>, SecurityError> {
+    let mut document =
+        SecuredDocument::load_mem(&buffer).map_err(|e| SecurityError::Encryption(e.to_string()))?;
+
+    let owner_password = match options.owner_password {
+        Some(ref owner_password) => owner_password.clone(),
+        None => random_password(),
+    };
+    let file_encryption_key = rand::random::<[u8; FILE_KEY_LENGTH]>();
+
+    let crypt_filter: Arc<dyn CryptFilter> = Arc::new(Aes256CryptFilter);
+    let state = EncryptionState::try_from(EncryptionVersion::V5 {
+        encrypt_metadata: true,
+        crypt_filters: BTreeMap::from([(CRYPT_FILTER.to_vec(), crypt_filter)]),
+        file_encryption_key: &file_encryption_key,
+        stream_filter: CRYPT_FILTER.to_vec(),
+        string_filter: CRYPT_FILTER.to_vec(),
+        owner_password: &owner_password,
+        user_password: options.user_password.as_deref().unwrap_or(""),
+        permissions: options.get_permissions(),
+    })
+    .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+
+    set_extension_level(&mut document).map_err(|e| SecurityError::Encryption(e.to_string()))?;
+
+    document
+        .encrypt(&state)
+        .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+
+    let mut encrypted = Vec::new();
+    document
+        .save_to(&mut encrypted)
+        .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+
+    Ok(encrypted)
+}
+
+fn set_extension_level(document: &mut SecuredDocument) -> Result<(), SecurityLoadError> {
+    if document.version.as_str() >= NATIVE_AES_256_VERSION {
+        return Ok(());
+    }
+
+    document.version = ENCRYPTED_PDF_VERSION.into();
+
+    let mut extension = Dictionary::new();
+    extension.set(
+        "BaseVersion",
+        Object::Name(ENCRYPTED_PDF_VERSION.as_bytes().to_vec()),
+    );
+    extension.set("ExtensionLevel", ADOBE_EXTENSION_LEVEL);
+
+    let mut extensions = Dictionary::new();
+    extensions.set("ADBE", extension);
+
+    document.catalog_mut()?.set("Extensions", extensions);
+
+    Ok(())
+}
+
+fn random_password() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(OWNER_PASSWORD_LENGTH)
+        .map(char::from)
+        .collect()
+}
@@ -0,0 +1,496 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Cursor, Read};
+use std::str;
+
+use serde_json::Value;
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::mongo::models::mapping::MappingProfile;
+use crate::services::filler::compiler::PDFillerMap;
+
+pub const CSV_CONTENT_TYPE: &str = "text/csv";
+pub const XLSX_CONTENT_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+const BYTE_ORDER_MARK: char = '\u{feff}';
+const DEFAULT_SEPARATOR: &str = ";";
+const WORKBOOK_PATH: &str = "xl/workbook.xml";
+const WORKBOOK_RELATIONSHIPS_PATH: &str = "xl/_rels/workbook.xml.rels";
+const SHARED_STRINGS_PATH: &str = "xl/sharedStrings.xml";
+const WORKBOOK_DIRECTORY: &str = "xl/";
+const TRUE_VALUE: &str = "true";
+const FALSE_VALUE: &str = "false";
+/// Columns of a worksheet, the last one is XFD
+const MAX_COLUMNS: usize = 16384;
+
+#[derive(Debug)]
+pub enum SheetError {
+    Utf8(str::Utf8Error),
+    Csv(String),
+    Zip(ZipError),
+    Xml(xml::reader::Error),
+    Xlsx(&'static str),
+    SheetNotFound(String),
+}
+
+impl Display for SheetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SheetError::Utf8(e) => write!(f, "Error decoding the CSV file: {}", e),
+            SheetError::Csv(message) => write!(f, "Not a valid CSV file: {}", message),
+            SheetError::Zip(e) => write!(f, "Not a valid XLSX file: {}", e),
+            SheetError::Xml(e) => write!(f, "Error reading the XLSX file: {}", e),
+            SheetError::Xlsx(message) => write!(f, "Not a valid XLSX file: {}", message),
+            SheetError::SheetNotFound(name) => {
+                write!(f, "The workbook has no sheet named \"{}\"", name)
+            }
+        }
+    }
+}
+
+impl From<str::Utf8Error> for SheetError {
+    fn from(e: str::Utf8Error) -> Self {
+        SheetError::Utf8(e)
+    }
+}
+
+impl From<ZipError> for SheetError {
+    fn from(e: ZipError) -> Self {
+        SheetError::Zip(e)
+    }
+}
+
+impl From<xml::reader::Error> for SheetError {
+    fn from(e: xml::reader::Error) -> Self {
+        SheetError::Xml(e)
+    }
+}
+
+/// Whether the body is a table read by `read_rows`.
+pub fn is_sheet(content_type: Option<&str>) -> bool {
+    matches!(
+        content_type,
+        Some(CSV_CONTENT_TYPE) | Some(XLSX_CONTENT_TYPE)
+    )
+}
+
+/// Data maps of the rows of a CSV file or of an XLSX sheet, the first one when no `sheet` is named.
+/// The header row names the fields, renamed by the mapping profile if any.
+pub fn read_rows(
+    content_type: Option<&str>,
+    body: &[u8],
+    sheet: Option<&str>,
+    profile: Option<&MappingProfile>,
+) -> Result<Vec<PDFillerMap>, SheetError> {
+    let records = match content_type {
+        Some(XLSX_CONTENT_TYPE) => read_xlsx(body, sheet)?,
+        _ => read_csv(str::from_utf8(body)?.trim_start_matches(BYTE_ORDER_MARK))?,
+    };
+
+    Ok(get_maps(records, profile))
+}
+
+/// CSV records can't be wider than the header, the values were likely not quoted.
+fn read_csv(body: &str) -> Result<Vec<Vec<Value>>, SheetError> {
+    let records = parse_csv(body)?;
+    let columns = records.first().map(Vec::len).unwrap_or(0);
+    if let Some((index, record)) = records
+        .iter()
+        .enumerate()
+        .find(|(_, record)| record.len() > columns)
+    {
+        return Err(SheetError::Csv(format!(
+            "row {} has {} columns while the header has {}",
+            index,
+            record.len(),
+            columns
+        )));
+    }
+
+    Ok(records
+        .into_iter()
+        .map(|record| record.into_iter().map(Value::String).collect())
+        .collect())
+}
+
+fn get_maps(records: Vec<Vec<Value>>, profile: Option<&MappingProfile>) -> Vec<PDFillerMap> {
+    let mut records = records.into_iter();
+    let header = match records.next() {
+        Some(header) => header
+            .into_iter()
+            .map(|name| match name {
+                Value::String(name) => name.trim().to_string(),
+                name => name.to_string(),
+            })
+            .collect::<Vec<_>>(),
+        None => return Vec::new(),
+    };
+
+    // Field name and list separator of every column
+    let separator = profile
+        .and_then(|profile| profile.separator.as_deref())
+        .unwrap_or(DEFAULT_SEPARATOR);
+    let columns = header
+        .iter()
+        .map(|name| {
+            let field = profile
+                .and_then(|profile| profile.columns.get(name))
+                .unwrap_or(name)
+                .clone();
+            let list = profile
+                .map(|profile| profile.lists.contains(name))
+                .unwrap_or(false);
+
+            (field, if list { Some(separator) } else { None })
+        })
+        .collect::<Vec<_>>();
+
+    // Cells without a header are skipped
+    records
+        .map(|record| {
+            columns
+                .iter()
+                .zip(record)
+                .filter(|((field, _), _)| !field.is_empty())
+                .map(|((field, separator), value)| (field.clone(), infer_value(value, *separator)))
+                .collect()
+        })
+        .collect()
+}
+
+/// Cells are text, `true` and `false` are booleans and JSON arrays fill list boxes as do the
+/// cells of list columns.
+fn infer_value(value: Value, separator: Option<&str>) -> Value {
+    let text = match value {
+        Value::String(text) => text,
+        value => return value,
+    };
+
+    if let Some(separator) = separator {
+        return Value::Array(
+            text.split(separator)
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(|value| Value::String(value.to_string()))
+                .collect(),
+        );
+    }
+
+    let trimmed = text.trim();
+    if trimmed.eq_ignore_ascii_case(TRUE_VALUE) {
+        Value::Bool(true)
+    } else if trimmed.eq_ignore_ascii_case(FALSE_VALUE) {
+        Value::Bool(false)
+    } else if trimmed.starts_with('[') && trimmed.ends_with(']') {
+        match serde_json::from_str::<Value>(trimmed) {
+            Ok(values @ Value::Array(_)) => values,
+            _ => Value::String(text),
+        }
+    } else {
+        Value::String(text)
+    }
+}
+
+/// Records as in RFC 4180, quoted values may hold separators, quotes written twice and line breaks.
+fn parse_csv(body: &str) -> Result<Vec<Vec<String>>, SheetError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut quote_line = 1;
+
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    value.push('"');
+                }
+                '"' => quoted = false,
+                c => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    value.push(c);
+                }
+            }
+
+            continue;
+        }
+
+        match c {
+            '"' if value.is_empty() => {
+                quoted = true;
+                quote_line = line;
+            }
+            ',' => record.push(std::mem::take(&mut value)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                line += 1;
+                record.push(std::mem::take(&mut value));
+                // Blank lines aren't records
+                if record.len() > 1 || !record[0].is_empty() {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            }
+            c => value.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(SheetError::Csv(format!(
+            "the quoted value at line {} isn't closed",
+            quote_line
+        )));
+    }
+
+    record.push(value);
+    if record.len() > 1 || !record[0].is_empty() {
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Rows of a worksheet with the text of the cells, booleans for boolean cells. Dates are numbers
+/// formatted by the styles, they're read as the stored number.
+fn read_xlsx(body: &[u8], sheet: Option<&str>) -> Result<Vec<Vec<Value>>, SheetError> {
+    let mut archive = ZipArchive::new(Cursor::new(body))?;
+
+    let path = get_sheet_path(&mut archive, sheet)?;
+    let shared_strings = match archive.by_name(SHARED_STRINGS_PATH) {
+        Ok(file) => read_shared_strings(file)?,
+        Err(ZipError::FileNotFound) => Vec::new(),
+        Err(e) => return Err(SheetError::Zip(e)),
+    };
+
+    let file = archive.by_name(&path)?;
+    read_sheet(file, &shared_strings)
+}
+
+/// Worksheets are listed by the workbook and found through its relationships.
+fn get_sheet_path<R: Read + std::io::Seek>(
+    archive: &mut ZipArchive<R>,
+    sheet: Option<&str>,
+) -> Result<String, SheetError> {
+    let mut relationship = None;
+    for event in get_reader(archive.by_name(WORKBOOK_PATH)?) {
+        if let XmlEvent::StartElement {
+            name, attributes, ..
+        } = event?
+        {
+            if name.local_name != "sheet" {
+                continue;
+            }
+
+            let matches = match sheet {
+                Some(sheet) => get_attribute(&attributes, "name", None) == Some(sheet),
+                None => true,
+            };
+            if matches {
+                relationship = get_attribute(&attributes, "id", Some("r")).map(str::to_string);
+                break;
+            }
+        }
+    }
+    let relationship = match (relationship, sheet) {
+        (Some(relationship), _) => relationship,
+        (None, Some(sheet)) => return Err(SheetError::SheetNotFound(sheet.to_string())),
+        (None, None) => return Err(SheetError::Xlsx("the workbook has no sheets")),
+    };
+
+    for event in get_reader(archive.by_name(WORKBOOK_RELATIONSHIPS_PATH)?) {
+        if let XmlEvent::StartElement {
+            name, attributes, ..
+        } = event?
+        {
+            if name.local_name == "Relationship"
+                && get_attribute(&attributes, "Id", None) == Some(relationship.as_str())
+            {
+                return match get_attribute(&attributes, "Target", None) {
+                    Some(target) if target.starts_with('/') => Ok(target[1..].to_string()),
+                    Some(target) => Ok(format!("{}{}", WORKBOOK_DIRECTORY, target)),
+                    None => Err(SheetError::Xlsx("the sheet has no target")),
+                };
+            }
+        }
+    }
+
+    Err(SheetError::Xlsx(
+        "the sheet isn't in the workbook relationships",
+    ))
+}
+
+/// Text of every shared string, rich text runs are joined and phonetic runs skipped.
+fn read_shared_strings<R: Read>(file: R) -> Result<Vec<String>, SheetError> {
+    let mut strings = Vec::new();
+    let mut text = String::new();
+    let mut in_text = false;
+    let mut in_phonetic = false;
+
+    for event in get_reader(file) {
+        match event? {
+            XmlEvent::StartElement { name, .. } => match name.local_name.as_str() {
+                "si" => text.clear(),
+                "t" => in_text = true,
+                "rPh" => in_phonetic = true,
+                _ => {}
+            },
+            XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                "si" => strings.push(std::mem::take(&mut text)),
+                "t" => in_text = false,
+                "rPh" => in_phonetic = false,
+                _ => {}
+            },
+            XmlEvent::Characters(value) | XmlEvent::CData(value) if in_text && !in_phonetic => {
+                text.push_str(&value)
+            }
+            _ => {}
+        }
+    }
+
+    Ok(strings)
+}
+
+fn read_sheet<R: Read>(file: R, shared_strings: &[String]) -> Result<Vec<Vec<Value>>, SheetError> {
+    let mut rows = Vec::new();
+    let mut row: Vec<Value> = Vec::new();
+    // Type and column of the cell being read with its text
+    let mut cell: Option<(String, usize)> = None;
+    let mut text = String::new();
+    let mut in_text = false;
+
+    for event in get_reader(file) {
+        match event? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => match name.local_name.as_str() {
+                "row" => row.clear(),
+                "c" => {
+                    // Empty cells may be left out, the reference gives the column
+                    let column = match get_attribute(&attributes, "r", None) {
+                        Some(reference) => get_column(reference)
+                            .ok_or(SheetError::Xlsx("a cell reference is not valid"))?,
+                        None => row.len(),
+                    };
+                    let kind = get_attribute(&attributes, "t", None).unwrap_or("n");
+
+                    cell = Some((kind.to_string(), column));
+                    text.clear();
+                }
+                "v" | "t" => in_text = cell.is_some(),
+                _ => {}
+            },
+            XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                "v" | "t" => in_text = false,
+                "c" => {
+                    if let Some((kind, column)) = cell.take() {
+                        let value =
+                            get_cell_value(&kind, std::mem::take(&mut text), shared_strings);
+                        if row.len() <= column {
+                            row.resize(column + 1, Value::String(String::new()));
+                        }
+                        row[column] = value;
+                    }
+                }
+                // Blank rows aren't records
+                "row" if row.iter().any(|value| value.as_str() != Some("")) => {
+                    rows.push(std::mem::take(&mut row));
+                }
+                _ => {}
+            },
+            XmlEvent::Characters(value) | XmlEvent::CData(value) if in_text => {
+                text.push_str(&value)
+            }
+            _ => {}
+        }
+    }
+
+    Ok(rows)
+}
+
+fn get_cell_value(kind: &str, text: String, shared_strings: &[String]) -> Value {
+    match kind {
+        "s" => Value::String(
+            text.trim()
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| shared_strings.get(index))
+                .cloned()
+                .unwrap_or_default(),
+        ),
+        "b" => Value::Bool(text.trim() == "1"),
+        // Formula errors like #DIV/0! have no value to fill
+        "e" => Value::String(String::new()),
+        _ => Value::String(text),
+    }
+}
+
+/// Zero based column of a cell reference like `AB12`.
+/// Zero based column of a cell reference, references past XFD are refused as rows are sized by
+/// them.
+fn get_column(reference: &str) -> Option<usize> {
+    let letters = reference
+        .chars()
+        .take_while(char::is_ascii_alphabetic)
+        .collect::<String>();
+    if letters.is_empty() {
+        return None;
+    }
+
+    letters
+        .chars()
+        .try_fold(0usize, |column, letter| {
+            column
+                .checked_mul(26)?
+                .checked_add((letter.to_ascii_uppercase() as u8 - b'A') as usize + 1)
+        })
+        .filter(|column| *column <= MAX_COLUMNS)
+        .map(|column| column - 1)
+}
+
+fn get_attribute<'a>(
+    attributes: &'a [OwnedAttribute],
+    name: &str,
+    prefix: Option<&str>,
+) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|attribute| {
+            attribute.name.local_name == name && attribute.name.prefix.as_deref() == prefix
+        })
+        .map(|attribute| attribute.value.as_str())
+}
+
+fn get_reader<R: Read>(file: R) -> EventReader<R> {
+    EventReader::new_with_config(
+        file,
+        ParserConfig::new()
+            .whitespace_to_characters(true)
+            .coalesce_characters(true),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_column;
+
+    #[test]
+    fn cell_columns() {
+        assert_eq!(get_column("A1"), Some(0));
+        assert_eq!(get_column("z10"), Some(25));
+        assert_eq!(get_column("AA3"), Some(26));
+        assert_eq!(get_column("XFD1"), Some(16383));
+        assert_eq!(get_column("XFE1"), None);
+        assert_eq!(get_column("ZZZZZZ1"), None);
+        assert_eq!(get_column("ZZZZZZZZZZZZZZZZ1"), None);
+        assert_eq!(get_column("1"), None);
+    }
+}
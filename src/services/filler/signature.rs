@@ -0,0 +1,1313 @@
+use std::fmt;
+
+use chrono::Utc;
+use lopdf_security::{
+    Dictionary, Document as SecuredDocument, IncrementalDocument, Object, ObjectId, Stream,
+    StringFormat,
+};
+use openssl::hash::{self, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer as KeySigner;
+use openssl::x509::{X509Ref, X509};
+use serde::Deserialize;
+use yasna::models::ObjectIdentifier;
+use yasna::tags::TAG_INTEGER;
+use yasna::{ASN1Error, ASN1ErrorKind, ASN1Result, DERWriter, Tag};
+
+use crate::client;
+use crate::data::Data;
+use crate::services::filler::metadata;
+
+// Room reserved for the CMS container, enough for a chain of a few certificates and a timestamp
+const SIGNATURE_SIZE: usize = 16384;
+const BYTE_RANGE_PLACEHOLDER: i64 = 9_999_999_999;
+const BYTE_RANGE_KEY: &[u8] = b"/ByteRange";
+
+const SIGNATURE_FIELD_PREFIX: &str = "Signature";
+const SIGNATURE_FONT: &str = "Helvetica";
+const SIGNATURE_FONT_RESOURCE: &str = "PFSig";
+const SIGNATURE_MAX_FONT_SIZE: f64 = 10.0;
+const SIGNATURE_PADDING: f64 = 2.0;
+const SIGNATURE_FLAGS: i64 = 3; // SignaturesExist | AppendOnly
+const ANNOTATION_PRINT_LOCKED: i64 = 132;
+
+const TIMESTAMP_CONTENT_TYPE: &str = "application/timestamp-query";
+
+const OID_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 1];
+pub const OID_SIGNED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 2];
+pub const OID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+const OID_CONTENT_TYPE: &[u64] = &[1, 2, 840, 113549, 1, 9, 3];
+pub const OID_MESSAGE_DIGEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 4];
+const OID_SIGNING_CERTIFICATE_V2: &[u64] = &[1, 2, 840, 113549, 1, 9, 16, 2, 47];
+pub const OID_TIMESTAMP_TOKEN: &[u64] = &[1, 2, 840, 113549, 1, 9, 16, 2, 14];
+const OID_TIMESTAMP_INFO: &[u64] = &[1, 2, 840, 113549, 1, 9, 16, 1, 4];
+const OID_RSA_ENCRYPTION: &[u64] = &[1, 2, 840, 113549, 1, 1, 1];
+const OID_ECDSA_SHA256: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+
+const TAG_CONTEXT_0: u8 = 0xA0;
+const TAG_CONTEXT_1: u8 = 0xA1;
+
+/// Visible signature widget, `rect` is expressed in page coordinates as `[x1, y1, x2, y2]`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignatureAppearance {
+    /// Page number starting from 1, the first page by default
+    pub page: Option<usize>,
+    pub rect: [f64; 4],
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SignatureOptions {
+    /// Signature field to sign, the first unsigned one or a new field when missing
+    pub field: Option<String>,
+    /// Password of the certificate uploaded for the token
+    pub password: Option<String>,
+    /// Creates a visible signature when the field doesn't exist, invisible otherwise
+    pub appearance: Option<SignatureAppearance>,
+    pub reason: Option<String>,
+    pub location: Option<String>,
+    pub contact_info: Option<String>,
+    /// Adds an RFC 3161 timestamp from the configured TSA
+    pub timestamp: Option<bool>,
+}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Certificate(String),
+    Field(String),
+    Pdf(String),
+    Cms(String),
+    Timestamp(String),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Certificate(message) => {
+                write!(f, "Signing certificate not available: {}", message)
+            }
+            Self::Field(message) => {
+                write!(f, "Signature field not available: {}", message)
+            }
+            Self::Pdf(message) => {
+                write!(f, "The PDF couldn't be signed: {}", message)
+            }
+            Self::Cms(message) => {
+                write!(f, "The signature couldn't be created: {}", message)
+            }
+            Self::Timestamp(message) => {
+                write!(f, "The timestamp couldn't be obtained: {}", message)
+            }
+        }
+    }
+}
+
+impl From<lopdf_security::Error> for SignatureError {
+    fn from(e: lopdf_security::Error) -> Self {
+        SignatureError::Pdf(e.to_string())
+    }
+}
+
+impl From<openssl::error::ErrorStack> for SignatureError {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        SignatureError::Cms(e.to_string())
+    }
+}
+
+/// Private key and certificate chain loaded from a PKCS#12 file.
+pub struct Signer {
+    key: PKey<Private>,
+    certificate: X509,
+    chain: Vec<X509>,
+    tsa_url: Option<String>,
+}
+
+impl Signer {
+    pub fn from_pkcs12(
+        der: &[u8],
+        password: &str,
+        tsa_url: Option<String>,
+    ) -> Result<Self, SignatureError> {
+        let pkcs12 = Pkcs12::from_der(der)
+            .and_then(|pkcs12| pkcs12.parse2(password))
+            .map_err(|e| SignatureError::Certificate(format!("invalid PKCS#12 file, {}", e)))?;
+
+        match (pkcs12.pkey, pkcs12.cert) {
+            (Some(key), Some(certificate)) => Ok(Self {
+                key,
+                certificate,
+                chain: pkcs12
+                    .ca
+                    .map(|ca| ca.into_iter().collect())
+                    .unwrap_or_default(),
+                tsa_url,
+            }),
+            _ => Err(SignatureError::Certificate(
+                "the PKCS#12 file must contain a private key and its certificate".into(),
+            )),
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        certificate_subject(&self.certificate)
+    }
+}
+
+/// Returns the common name of the certificate subject.
+pub fn certificate_subject(certificate: &X509Ref) -> String {
+    certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().to_string().ok())
+        .unwrap_or_default()
+}
+
+/// Loads the certificate uploaded for the token or, when missing, the configured one.
+pub async fn get_signer(
+    data: &Data,
+    token: &str,
+    options: &SignatureOptions,
+) -> Result<Signer, SignatureError> {
+    let tsa_url = data
+        .signature
+        .as_ref()
+        .and_then(|signature| signature.tsa_url.clone())
+        .filter(|tsa_url| !tsa_url.is_empty());
+
+    if options.timestamp.unwrap_or(false) && tsa_url.is_none() {
+        return Err(SignatureError::Timestamp(
+            "no Time Stamping Authority configured".into(),
+        ));
+    }
+
+    if let Some(certificate) = data.get_certificate_by_token(token).await {
+        let password = options.password.as_deref().ok_or_else(|| {
+            SignatureError::Certificate("the certificate password is required".into())
+        })?;
+
+        let der = data
+            .file
+            .load(&certificate.file)
+            .await
+            .map_err(|e| SignatureError::Certificate(e.to_string()))?;
+
+        Signer::from_pkcs12(&der, password, tsa_url)
+    } else {
+        match data.signature.as_ref().and_then(|signature| {
+            signature
+                .certificate
+                .as_ref()
+                .filter(|certificate| !certificate.is_empty())
+                .map(|certificate| (certificate, signature.password.as_deref().unwrap_or("")))
+        }) {
+            Some((certificate, password)) => {
+                let der = crystalsoft_utils::read_file_buf(certificate)
+                    .map_err(|e| SignatureError::Certificate(format!("{:#?}", e)))?;
+
+                Signer::from_pkcs12(&der, password, tsa_url)
+            }
+            None => Err(SignatureError::Certificate(
+                "no certificate uploaded for this token or configured".into(),
+            )),
+        }
+    }
+}
+
+pub struct SignatureField {
+    pub id: ObjectId,
+    pub name: String,
+    pub signed: bool,
+}
+
+/// Signs the document with a PAdES-B-B signature (PAdES-B-T when timestamped), the signature is
+/// appended as an incremental update so the previous revision is left untouched.
+pub async fn sign_document(
+    buffer: Vec<u8>,
+    signer: &Signer,
+    options: &SignatureOptions,
+) -> Result<Vec<u8>, SignatureError> {
+    let previous = SecuredDocument::load_mem(&buffer)?;
+    if previous.is_encrypted() || previous.was_encrypted() {
+        return Err(SignatureError::Pdf("encrypted PDFs can't be signed".into()));
+    }
+
+    let previous_length = buffer.len();
+    let mut document = IncrementalDocument::create_from(buffer, previous);
+
+    let fields = get_signature_fields(document.get_prev_documents());
+    let field = match options.field {
+        Some(ref name) => fields.into_iter().find(|field| &field.name == name),
+        None => fields.into_iter().find(|field| !field.signed),
+    };
+
+    let signature_id = document
+        .new_document
+        .add_object(signature_dictionary(signer, options));
+
+    match field {
+        Some(SignatureField {
+            signed: true, name, ..
+        }) => {
+            return Err(SignatureError::Field(format!(
+                "\"{}\" is already signed",
+                name
+            )));
+        }
+        Some(field) => sign_field(&mut document, field.id, signature_id, signer, options)?,
+        None => add_signature_field(&mut document, signature_id, signer, options)?,
+    }
+
+    get_acroform(&mut document)?.set("SigFlags", SIGNATURE_FLAGS);
+
+    let mut signed = Vec::new();
+    document
+        .save_to(&mut signed)
+        .map_err(|e| SignatureError::Pdf(e.to_string()))?;
+
+    let (contents_start, contents_end) = set_byte_range(&mut signed, previous_length)?;
+
+    let signed_content = [&signed[..contents_start], &signed[contents_end..]].concat();
+    let cms = create_cms(signer, &signed_content, options.timestamp.unwrap_or(false)).await?;
+
+    let contents = cms
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<String>();
+    if contents.len() > SIGNATURE_SIZE * 2 {
+        return Err(SignatureError::Cms(format!(
+            "the signature is {} bytes, only {} are available",
+            cms.len(),
+            SIGNATURE_SIZE
+        )));
+    }
+    signed[contents_start + 1..contents_start + 1 + contents.len()]
+        .copy_from_slice(contents.as_bytes());
+
+    Ok(signed)
+}
+
+fn signature_dictionary(signer: &Signer, options: &SignatureOptions) -> Dictionary {
+    let mut dictionary = Dictionary::new();
+    dictionary.set("Type", Object::Name(b"Sig".to_vec()));
+    dictionary.set("Filter", Object::Name(b"Adobe.PPKLite".to_vec()));
+    dictionary.set("SubFilter", Object::Name(b"ETSI.CAdES.detached".to_vec()));
+    dictionary.set(
+        "ByteRange",
+        vec![
+            Object::Integer(0),
+            Object::Integer(BYTE_RANGE_PLACEHOLDER),
+            Object::Integer(BYTE_RANGE_PLACEHOLDER),
+            Object::Integer(BYTE_RANGE_PLACEHOLDER),
+        ],
+    );
+    dictionary.set(
+        "Contents",
+        Object::String(vec![0; SIGNATURE_SIZE], StringFormat::Hexadecimal),
+    );
+    dictionary.set(
+        "M",
+        Object::String(
+            Utc::now()
+                .format(metadata::PDF_DATE_FORMAT)
+                .to_string()
+                .into_bytes(),
+            StringFormat::Literal,
+        ),
+    );
+    dictionary.set("Name", text_string(&signer.subject()));
+
+    let values = [
+        ("Reason", options.reason.as_ref()),
+        ("Location", options.location.as_ref()),
+        ("ContactInfo", options.contact_info.as_ref()),
+    ];
+    for (key, value) in values.iter() {
+        if let Some(value) = value {
+            dictionary.set(*key, text_string(value));
+        }
+    }
+
+    dictionary
+}
+
+/// Returns the terminal signature fields with their fully qualified names.
+pub fn get_signature_fields(document: &SecuredDocument) -> Vec<SignatureField> {
+    fn collect(
+        document: &SecuredDocument,
+        kids: &[Object],
+        parent: Option<(&str, bool)>,
+        fields: &mut Vec<SignatureField>,
+    ) {
+        for kid in kids {
+            let (id, field) = match kid
+                .as_reference()
+                .and_then(|id| document.get_dictionary(id).map(|field| (id, field)))
+            {
+                Ok(field) => field,
+                Err(_) => continue,
+            };
+
+            let partial_name = field
+                .get(b"T")
+                .and_then(Object::as_str)
+                .map(metadata::decode_text_string)
+                .ok();
+            let name = match (parent.map(|(name, _)| name), partial_name.as_ref()) {
+                (Some(parent), Some(name)) if !parent.is_empty() => format!("{}.{}", parent, name),
+                (_, Some(name)) => name.clone(),
+                (Some(parent), None) => parent.to_owned(),
+                (None, None) => String::new(),
+            };
+            let is_signature = field
+                .get(b"FT")
+                .and_then(Object::as_name)
+                .map(|field_type| field_type == b"Sig")
+                .unwrap_or_else(|_| {
+                    parent
+                        .map(|(_, is_signature)| is_signature)
+                        .unwrap_or(false)
+                });
+
+            let children = field
+                .get(b"Kids")
+                .and_then(Object::as_array)
+                .map(|kids| {
+                    kids.iter()
+                        .filter(|kid| {
+                            kid.as_reference()
+                                .and_then(|id| document.get_dictionary(id))
+                                .map(|kid| kid.has(b"T"))
+                                .unwrap_or(false)
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+
+            if !children.is_empty() {
+                collect(document, &children, Some((&name, is_signature)), fields);
+            } else if is_signature {
+                fields.push(SignatureField {
+                    id,
+                    name,
+                    signed: field.has(b"V"),
+                });
+            }
+        }
+    }
+
+    let mut fields = Vec::new();
+    let root = document
+        .catalog()
+        .and_then(|catalog| catalog.get(b"AcroForm"))
+        .and_then(|acroform| document.dereference(acroform))
+        .and_then(|(_, acroform)| acroform.as_dict())
+        .and_then(|acroform| acroform.get(b"Fields"))
+        .and_then(|fields| document.dereference(fields))
+        .and_then(|(_, fields)| fields.as_array());
+    if let Ok(root) = root {
+        collect(document, root, None, &mut fields);
+    }
+
+    fields
+}
+
+fn sign_field(
+    document: &mut IncrementalDocument,
+    field_id: ObjectId,
+    signature_id: ObjectId,
+    signer: &Signer,
+    options: &SignatureOptions,
+) -> Result<(), SignatureError> {
+    document.opt_clone_object_to_new_document(field_id)?;
+
+    let rect = get_dictionary_mut(&mut document.new_document, field_id)?
+        .get(b"Rect")
+        .and_then(Object::as_array)
+        .map(|rect| rect.iter().filter_map(as_number).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let appearance = if rect.len() == 4 {
+        Some(add_appearance(
+            &mut document.new_document,
+            (rect[2] - rect[0]).abs(),
+            (rect[3] - rect[1]).abs(),
+            signer,
+            options,
+        ))
+    } else {
+        None
+    };
+
+    let field = get_dictionary_mut(&mut document.new_document, field_id)?;
+    field.set("V", signature_id);
+    if let Some(appearance_id) = appearance {
+        let mut appearances = Dictionary::new();
+        appearances.set("N", appearance_id);
+        field.set("AP", appearances);
+    }
+
+    Ok(())
+}
+
+fn add_signature_field(
+    document: &mut IncrementalDocument,
+    signature_id: ObjectId,
+    signer: &Signer,
+    options: &SignatureOptions,
+) -> Result<(), SignatureError> {
+    let pages = document.get_prev_documents().get_pages();
+    let page_number = options
+        .appearance
+        .as_ref()
+        .and_then(|appearance| appearance.page)
+        .unwrap_or(1);
+    let page_id = *pages
+        .get(&(page_number as u32))
+        .ok_or_else(|| SignatureError::Field(format!("page {} doesn't exist", page_number)))?;
+
+    let rect = options
+        .appearance
+        .as_ref()
+        .map(|appearance| appearance.rect)
+        .unwrap_or([0.0; 4]);
+    let appearance_id = add_appearance(
+        &mut document.new_document,
+        (rect[2] - rect[0]).abs(),
+        (rect[3] - rect[1]).abs(),
+        signer,
+        options,
+    );
+
+    let existing_fields = get_signature_fields(document.get_prev_documents()).len();
+
+    let mut appearances = Dictionary::new();
+    appearances.set("N", appearance_id);
+
+    let mut field = Dictionary::new();
+    field.set("Type", Object::Name(b"Annot".to_vec()));
+    field.set("Subtype", Object::Name(b"Widget".to_vec()));
+    field.set("FT", Object::Name(b"Sig".to_vec()));
+    field.set(
+        "T",
+        text_string(
+            &options
+                .field
+                .clone()
+                .unwrap_or_else(|| format!("{}{}", SIGNATURE_FIELD_PREFIX, existing_fields + 1)),
+        ),
+    );
+    field.set("F", ANNOTATION_PRINT_LOCKED);
+    field.set(
+        "Rect",
+        rect.iter()
+            .map(|value| Object::Real(*value as f32))
+            .collect::<Vec<_>>(),
+    );
+    field.set("P", page_id);
+    field.set("V", signature_id);
+    field.set("AP", appearances);
+    let field_id = document.new_document.add_object(field);
+
+    // Annotations and fields may be direct arrays or references to arrays
+    let annotations_id = match document
+        .get_prev_documents()
+        .get_dictionary(page_id)?
+        .get(b"Annots")
+    {
+        Ok(Object::Reference(annotations_id)) => Some(*annotations_id),
+        _ => None,
+    };
+    match annotations_id {
+        Some(annotations_id) => {
+            document.opt_clone_object_to_new_document(annotations_id)?;
+            push_reference(&mut document.new_document, annotations_id, None, field_id)?;
+        }
+        None => {
+            document.opt_clone_object_to_new_document(page_id)?;
+            push_reference(
+                &mut document.new_document,
+                page_id,
+                Some(b"Annots"),
+                field_id,
+            )?;
+        }
+    }
+
+    let fields_id = get_acroform(document)?
+        .get(b"Fields")
+        .and_then(Object::as_reference)
+        .ok();
+    match fields_id {
+        Some(fields_id) => {
+            document.opt_clone_object_to_new_document(fields_id)?;
+            push_reference(&mut document.new_document, fields_id, None, field_id)?;
+        }
+        None => {
+            let acroform = get_acroform(document)?;
+            let mut fields = acroform
+                .get(b"Fields")
+                .and_then(Object::as_array)
+                .cloned()
+                .unwrap_or_default();
+            fields.push(Object::Reference(field_id));
+            acroform.set("Fields", fields);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the AcroForm of the new revision, the object holding it is cloned from the previous
+/// revision and the form is created when missing.
+fn get_acroform(document: &mut IncrementalDocument) -> Result<&mut Dictionary, SignatureError> {
+    let catalog_id = document
+        .get_prev_documents()
+        .trailer
+        .get(b"Root")
+        .and_then(Object::as_reference)?;
+    document.opt_clone_object_to_new_document(catalog_id)?;
+
+    let acroform = get_dictionary_mut(&mut document.new_document, catalog_id)?
+        .get(b"AcroForm")
+        .ok()
+        .cloned();
+    match acroform {
+        Some(Object::Reference(acroform_id)) => {
+            document.opt_clone_object_to_new_document(acroform_id)?;
+
+            get_dictionary_mut(&mut document.new_document, acroform_id)
+        }
+        Some(Object::Dictionary(_)) => {
+            Ok(get_dictionary_mut(&mut document.new_document, catalog_id)?
+                .get_mut(b"AcroForm")?
+                .as_dict_mut()?)
+        }
+        _ => {
+            let mut acroform = Dictionary::new();
+            acroform.set("Fields", Vec::<Object>::new());
+
+            let catalog = get_dictionary_mut(&mut document.new_document, catalog_id)?;
+            catalog.set("AcroForm", acroform);
+
+            Ok(catalog.get_mut(b"AcroForm")?.as_dict_mut()?)
+        }
+    }
+}
+
+fn push_reference(
+    document: &mut SecuredDocument,
+    object_id: ObjectId,
+    key: Option<&[u8]>,
+    reference: ObjectId,
+) -> Result<(), SignatureError> {
+    match key {
+        Some(key) => {
+            let dictionary = get_dictionary_mut(document, object_id)?;
+            let mut array = dictionary
+                .get(key)
+                .and_then(Object::as_array)
+                .cloned()
+                .unwrap_or_default();
+            array.push(Object::Reference(reference));
+            dictionary.set(key, array);
+        }
+        None => {
+            document
+                .get_object_mut(object_id)?
+                .as_array_mut()?
+                .push(Object::Reference(reference));
+        }
+    }
+
+    Ok(())
+}
+
+fn get_dictionary_mut(
+    document: &mut SecuredDocument,
+    object_id: ObjectId,
+) -> Result<&mut Dictionary, SignatureError> {
+    Ok(document.get_object_mut(object_id)?.as_dict_mut()?)
+}
+
+fn add_appearance(
+    document: &mut SecuredDocument,
+    width: f64,
+    height: f64,
+    signer: &Signer,
+    options: &SignatureOptions,
+) -> ObjectId {
+    let mut lines = vec![
+        format!("Digitally signed by {}", signer.subject()),
+        format!("Date: {}", Utc::now().format("%Y-%m-%d %H:%M:%S UTC")),
+    ];
+    if let Some(ref reason) = options.reason {
+        lines.push(format!("Reason: {}", reason));
+    }
+    if let Some(ref location) = options.location {
+        lines.push(format!("Location: {}", location));
+    }
+
+    let font_size = (height - SIGNATURE_PADDING * 2.0)
+        .max(0.0)
+        .min(SIGNATURE_MAX_FONT_SIZE * 1.2 * lines.len() as f64)
+        / (1.2 * lines.len() as f64);
+
+    let mut content = Vec::new();
+    if width > 0.0 && height > 0.0 {
+        content.extend_from_slice(
+            format!(
+                "q BT /{} {:.2} Tf 0 g {:.2} TL {:.2} {:.2} Td",
+                SIGNATURE_FONT_RESOURCE,
+                font_size,
+                font_size * 1.2,
+                SIGNATURE_PADDING,
+                height - SIGNATURE_PADDING - font_size
+            )
+            .as_bytes(),
+        );
+        for line in lines {
+            content.extend_from_slice(b" (");
+            content.extend(encode_literal(&line));
+            content.extend_from_slice(b") Tj T*");
+        }
+        content.extend_from_slice(b" ET Q");
+    }
+
+    let mut font = Dictionary::new();
+    font.set("Type", Object::Name(b"Font".to_vec()));
+    font.set("Subtype", Object::Name(b"Type1".to_vec()));
+    font.set("BaseFont", Object::Name(SIGNATURE_FONT.as_bytes().to_vec()));
+    font.set("Encoding", Object::Name(b"WinAnsiEncoding".to_vec()));
+    let font_id = document.add_object(font);
+
+    let mut fonts = Dictionary::new();
+    fonts.set(SIGNATURE_FONT_RESOURCE, font_id);
+    let mut resources = Dictionary::new();
+    resources.set("Font", fonts);
+
+    let mut dictionary = Dictionary::new();
+    dictionary.set("Type", Object::Name(b"XObject".to_vec()));
+    dictionary.set("Subtype", Object::Name(b"Form".to_vec()));
+    dictionary.set(
+        "BBox",
+        vec![
+            Object::Integer(0),
+            Object::Integer(0),
+            Object::Real(width as f32),
+            Object::Real(height as f32),
+        ],
+    );
+    dictionary.set("Resources", resources);
+
+    document.add_object(Stream::new(dictionary, content))
+}
+
+/// Replaces the ByteRange placeholder written in the new revision, returns the position of the
+/// Contents hexadecimal string, delimiters included.
+fn set_byte_range(buffer: &mut [u8], offset: usize) -> Result<(usize, usize), SignatureError> {
+    let placeholder = [b"<".to_vec(), vec![b'0'; SIGNATURE_SIZE * 2], b">".to_vec()].concat();
+    let contents_start = find(buffer, &placeholder, offset)
+        .ok_or_else(|| SignatureError::Pdf("signature placeholder not found".into()))?;
+    let contents_end = contents_start + placeholder.len();
+
+    let byte_range_start = find(buffer, BYTE_RANGE_KEY, offset)
+        .and_then(|start| find(buffer, b"[", start))
+        .ok_or_else(|| SignatureError::Pdf("byte range placeholder not found".into()))?;
+    let byte_range_end = find(buffer, b"]", byte_range_start)
+        .ok_or_else(|| SignatureError::Pdf("byte range placeholder not found".into()))?;
+
+    let byte_range = format!(
+        "0 {} {} {}",
+        contents_start,
+        contents_end,
+        buffer.len() - contents_end
+    );
+    let available = byte_range_end - byte_range_start - 1;
+    if byte_range.len() > available {
+        return Err(SignatureError::Pdf("byte range doesn't fit".into()));
+    }
+    buffer[byte_range_start + 1..byte_range_end]
+        .copy_from_slice(format!("{:width$}", byte_range, width = available).as_bytes());
+
+    Ok((contents_start, contents_end))
+}
+
+fn find(buffer: &[u8], needle: &[u8], offset: usize) -> Option<usize> {
+    buffer
+        .get(offset..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + offset)
+}
+
+async fn create_cms(
+    signer: &Signer,
+    content: &[u8],
+    timestamp: bool,
+) -> Result<Vec<u8>, SignatureError> {
+    let digest = hash::hash(MessageDigest::sha256(), content)?;
+    let certificate = signer.certificate.to_der()?;
+    let certificate_hash = hash::hash(MessageDigest::sha256(), &certificate)?;
+
+    // Signed attributes are signed as a SET and stored with an implicit [0] tag
+    let mut signed_attributes = yasna::construct_der(|writer| {
+        writer.write_set_of(|writer| {
+            write_attribute(writer.next(), OID_CONTENT_TYPE, |writer| {
+                writer.write_oid(&ObjectIdentifier::from_slice(OID_DATA))
+            });
+            write_attribute(writer.next(), OID_MESSAGE_DIGEST, |writer| {
+                writer.write_bytes(&digest)
+            });
+            write_attribute(writer.next(), OID_SIGNING_CERTIFICATE_V2, |writer| {
+                writer.write_sequence(|writer| {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_sequence(|writer| {
+                            writer.next().write_bytes(&certificate_hash);
+                        });
+                    });
+                })
+            });
+        })
+    });
+
+    let mut key_signer = KeySigner::new(MessageDigest::sha256(), &signer.key)?;
+    key_signer.update(&signed_attributes)?;
+    let signature = key_signer.sign_to_vec()?;
+    signed_attributes[0] = TAG_CONTEXT_0;
+
+    let signature_algorithm = match signer.key.id() {
+        Id::RSA => OID_RSA_ENCRYPTION,
+        Id::EC => OID_ECDSA_SHA256,
+        _ => {
+            return Err(SignatureError::Certificate(
+                "only RSA and ECDSA keys are supported".into(),
+            ));
+        }
+    };
+
+    let unsigned_attributes = if timestamp {
+        let token = get_timestamp_token(signer, &signature).await?;
+        let mut unsigned_attributes = yasna::construct_der(|writer| {
+            writer.write_set_of(|writer| {
+                write_attribute(writer.next(), OID_TIMESTAMP_TOKEN, |writer| {
+                    writer.write_der(&token)
+                });
+            })
+        });
+        unsigned_attributes[0] = TAG_CONTEXT_1;
+
+        Some(unsigned_attributes)
+    } else {
+        None
+    };
+
+    let issuer = signer.certificate.issuer_name().to_der()?;
+    let serial = signer.certificate.serial_number().to_bn()?.to_vec();
+    let certificates = std::iter::once(Ok(certificate))
+        .chain(signer.chain.iter().map(|certificate| certificate.to_der()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer
+                .next()
+                .write_oid(&ObjectIdentifier::from_slice(OID_SIGNED_DATA));
+            writer.next().write_tagged(Tag::context(0), |writer| {
+                writer.write_sequence(|writer| {
+                    writer.next().write_u8(1);
+                    writer.next().write_set(|writer| {
+                        write_algorithm(writer.next(), OID_SHA256, false);
+                    });
+                    writer.next().write_sequence(|writer| {
+                        writer
+                            .next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_DATA));
+                    });
+                    writer
+                        .next()
+                        .write_tagged_implicit(Tag::context(0), |writer| {
+                            writer.write_set_of(|writer| {
+                                for certificate in certificates.iter() {
+                                    writer.next().write_der(certificate);
+                                }
+                            })
+                        });
+                    writer.next().write_set(|writer| {
+                        writer.next().write_sequence(|writer| {
+                            writer.next().write_u8(1);
+                            writer.next().write_sequence(|writer| {
+                                writer.next().write_der(&issuer);
+                                writer.next().write_bigint_bytes(&serial, true);
+                            });
+                            write_algorithm(writer.next(), OID_SHA256, false);
+                            writer.next().write_der(&signed_attributes);
+                            write_algorithm(
+                                writer.next(),
+                                signature_algorithm,
+                                signature_algorithm == OID_RSA_ENCRYPTION,
+                            );
+                            writer.next().write_bytes(&signature);
+                            if let Some(ref unsigned_attributes) = unsigned_attributes {
+                                writer.next().write_der(unsigned_attributes);
+                            }
+                        });
+                    });
+                });
+            });
+        })
+    }))
+}
+
+async fn get_timestamp_token(signer: &Signer, signature: &[u8]) -> Result<Vec<u8>, SignatureError> {
+    let tsa_url = signer
+        .tsa_url
+        .as_ref()
+        .ok_or_else(|| SignatureError::Timestamp("no Time Stamping Authority configured".into()))?;
+
+    let imprint = hash::hash(MessageDigest::sha256(), signature)?;
+    let nonce = rand::random::<u64>() >> 1;
+    let request = yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer.next().write_u8(1);
+            writer.next().write_sequence(|writer| {
+                write_algorithm(writer.next(), OID_SHA256, true);
+                writer.next().write_bytes(&imprint);
+            });
+            writer.next().write_u64(nonce);
+            writer.next().write_bool(true);
+        })
+    });
+
+    let response = client::post_bytes(tsa_url, TIMESTAMP_CONTENT_TYPE, request)
+        .await
+        .ok_or_else(|| SignatureError::Timestamp(format!("no response from {}", tsa_url)))?;
+
+    let (status, token) = yasna::parse_der(&response, |reader| {
+        reader.read_sequence(|reader| {
+            let status = reader.next().read_sequence(|reader| {
+                let status = reader.next().read_u32()?;
+                reader.read_optional(|reader| reader.read_der())?;
+                reader.read_optional(|reader| reader.read_der())?;
+
+                Ok(status)
+            })?;
+            let token = reader.read_optional(|reader| reader.read_der())?;
+
+            Ok((status, token))
+        })
+    })
+    .map_err(|e| SignatureError::Timestamp(format!("invalid response, {}", e)))?;
+
+    let token = match token {
+        // 0 granted, 1 granted with modifications
+        Some(token) if status <= 1 => token,
+        _ => {
+            return Err(SignatureError::Timestamp(format!(
+                "request rejected with status {}",
+                status
+            )));
+        }
+    };
+
+    // The token must be the answer to this request, not a replayed or mixed up one
+    let info = parse_timestamp_info(&token)
+        .map_err(|e| SignatureError::Timestamp(format!("invalid token, {}", e)))?;
+    if info.nonce != Some(nonce) {
+        return Err(SignatureError::Timestamp(
+            "the token nonce doesn't match the request".into(),
+        ));
+    }
+    if info.imprint_algorithm != ObjectIdentifier::from_slice(OID_SHA256)
+        || info.imprint != imprint.as_ref()
+    {
+        return Err(SignatureError::Timestamp(
+            "the token message imprint doesn't match the signature".into(),
+        ));
+    }
+
+    Ok(token)
+}
+
+struct TimestampInfo {
+    imprint_algorithm: ObjectIdentifier,
+    imprint: Vec<u8>,
+    nonce: Option<u64>,
+}
+
+/// Reads the TSTInfo encapsulated in the SignedData of the timestamp token.
+fn parse_timestamp_info(token: &[u8]) -> ASN1Result<TimestampInfo> {
+    let content = yasna::parse_ber(token, |reader| {
+        reader.read_sequence(|reader| {
+            if reader.next().read_oid()? != ObjectIdentifier::from_slice(OID_SIGNED_DATA) {
+                return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+            }
+
+            reader.next().read_tagged(Tag::context(0), |reader| {
+                reader.read_sequence(|reader| {
+                    reader.next().read_u8()?; // version
+                    reader.next().read_der()?; // digestAlgorithms
+                    let content = reader.next().read_sequence(|reader| {
+                        if reader.next().read_oid()?
+                            != ObjectIdentifier::from_slice(OID_TIMESTAMP_INFO)
+                        {
+                            return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+                        }
+
+                        reader
+                            .next()
+                            .read_tagged(Tag::context(0), |reader| reader.read_bytes())
+                    })?;
+                    // Certificates, CRLs and signer infos
+                    while reader.read_optional(|reader| reader.read_der())?.is_some() {}
+
+                    Ok(content)
+                })
+            })
+        })
+    })?;
+
+    yasna::parse_der(&content, |reader| {
+        reader.read_sequence(|reader| {
+            reader.next().read_u8()?; // version
+            reader.next().read_oid()?; // policy
+            let (imprint_algorithm, imprint) = reader.next().read_sequence(|reader| {
+                let algorithm = reader.next().read_sequence(|reader| {
+                    let oid = reader.next().read_oid()?;
+                    reader.read_optional(|reader| reader.read_null())?;
+
+                    Ok(oid)
+                })?;
+
+                Ok((algorithm, reader.next().read_bytes()?))
+            })?;
+            reader.next().read_bigint_bytes()?; // serialNumber
+            reader.next().read_der()?; // genTime
+
+            // Accuracy, ordering, nonce, TSA name and extensions are optional, the nonce is the
+            // only integer among them
+            let mut nonce = None;
+            while let Some(value) = reader.read_optional(|reader| {
+                if reader.lookahead_tag()? == TAG_INTEGER {
+                    reader.read_u64().map(Some)
+                } else {
+                    reader.read_der().map(|_| None)
+                }
+            })? {
+                nonce = nonce.or(value);
+            }
+
+            Ok(TimestampInfo {
+                imprint_algorithm,
+                imprint,
+                nonce,
+            })
+        })
+    })
+}
+
+fn write_attribute<F: FnOnce(DERWriter)>(writer: DERWriter, oid: &[u64], value: F) {
+    writer.write_sequence(|writer| {
+        writer.next().write_oid(&ObjectIdentifier::from_slice(oid));
+        writer.next().write_set(|writer| value(writer.next()));
+    });
+}
+
+fn write_algorithm(writer: DERWriter, oid: &[u64], null_parameters: bool) {
+    writer.write_sequence(|writer| {
+        writer.next().write_oid(&ObjectIdentifier::from_slice(oid));
+        if null_parameters {
+            writer.next().write_null();
+        }
+    });
+}
+
+fn as_number(object: &Object) -> Option<f64> {
+    match object {
+        Object::Integer(value) => Some(*value as f64),
+        Object::Real(value) => Some(*value as f64),
+        _ => None,
+    }
+}
+
+fn text_string(value: &str) -> Object {
+    match metadata::encode_text_string(value) {
+        lopdf::Object::String(bytes, lopdf::StringFormat::Hexadecimal) => {
+            Object::String(bytes, StringFormat::Hexadecimal)
+        }
+        _ => Object::String(value.as_bytes().to_vec(), StringFormat::Literal),
+    }
+}
+
+/// Encodes the text for a literal string shown with a WinAnsiEncoding font.
+fn encode_literal(value: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for c in value.chars() {
+        let byte = if (c as u32) < 256 { c as u8 } else { b'?' };
+        if matches!(byte, b'(' | b')' | b'\\') {
+            bytes.push(b'\\');
+        }
+        bytes.push(byte);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use lopdf_security::{Dictionary, Document as SecuredDocument, Object};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509Builder, X509NameBuilder, X509};
+    use yasna::models::ObjectIdentifier;
+    use yasna::Tag;
+
+    use super::{
+        sign_document, write_algorithm, SignatureError, SignatureOptions, Signer, OID_SHA256,
+        OID_SIGNED_DATA, OID_TIMESTAMP_INFO,
+    };
+    use crate::services::filler::verification;
+
+    const PASSWORD: &str = "secret";
+
+    fn create_certificate() -> (Vec<u8>, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "PDFiller Test").unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = builder.build();
+
+        let pkcs12 = Pkcs12::builder()
+            .name("PDFiller Test")
+            .pkey(&key)
+            .cert(&certificate)
+            .build2(PASSWORD)
+            .unwrap();
+
+        (pkcs12.to_der().unwrap(), certificate)
+    }
+
+    fn create_pdf() -> Vec<u8> {
+        let mut document = SecuredDocument::with_version("1.7");
+        let pages_id = document.new_object_id();
+
+        let mut page = Dictionary::new();
+        page.set("Type", Object::Name(b"Page".to_vec()));
+        page.set("Parent", pages_id);
+        page.set("MediaBox", vec![0.into(), 0.into(), 595.into(), 842.into()]);
+        let page_id = document.add_object(page);
+
+        let mut pages = Dictionary::new();
+        pages.set("Type", Object::Name(b"Pages".to_vec()));
+        pages.set("Kids", vec![page_id.into()]);
+        pages.set("Count", 1);
+        document.objects.insert(pages_id, Object::Dictionary(pages));
+
+        let mut catalog = Dictionary::new();
+        catalog.set("Type", Object::Name(b"Catalog".to_vec()));
+        catalog.set("Pages", pages_id);
+        let catalog_id = document.add_object(catalog);
+        document.trailer.set("Root", catalog_id);
+
+        let mut buffer = Vec::new();
+        document.save_to(&mut buffer).unwrap();
+
+        buffer
+    }
+
+    /// Time Stamping Authority answering a single request, `tamper` changes the nonce of the
+    /// token. The token isn't signed, only its content is checked when signing.
+    fn start_tsa(tamper: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            let body_start = loop {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                if let Some(position) = request.windows(4).position(|window| window == b"\r\n\r\n")
+                {
+                    break position + 4;
+                }
+            };
+            let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+            let length = headers
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|length| length.trim().parse::<usize>().ok())
+                .unwrap();
+            while request.len() < body_start + length {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            let (imprint, nonce) = yasna::parse_der(&request[body_start..], |reader| {
+                reader.read_sequence(|reader| {
+                    reader.next().read_u8()?;
+                    let imprint = reader.next().read_sequence(|reader| {
+                        reader.next().read_der()?;
+                        reader.next().read_bytes()
+                    })?;
+                    let nonce = reader.next().read_u64()?;
+                    reader.next().read_bool()?;
+
+                    Ok((imprint, nonce))
+                })
+            })
+            .unwrap();
+            let nonce = if tamper { nonce ^ 1 } else { nonce };
+
+            let info = yasna::construct_der(|writer| {
+                writer.write_sequence(|writer| {
+                    writer.next().write_u8(1);
+                    writer
+                        .next()
+                        .write_oid(&ObjectIdentifier::from_slice(&[1, 2, 3, 4]));
+                    writer.next().write_sequence(|writer| {
+                        write_algorithm(writer.next(), OID_SHA256, true);
+                        writer.next().write_bytes(&imprint);
+                    });
+                    writer.next().write_u8(1);
+                    writer.next().write_der(b"\x18\x0f20260101000000Z");
+                    writer.next().write_u64(nonce);
+                })
+            });
+            let response = yasna::construct_der(|writer| {
+                writer.write_sequence(|writer| {
+                    writer
+                        .next()
+                        .write_sequence(|writer| writer.next().write_u8(0));
+                    writer.next().write_sequence(|writer| {
+                        writer
+                            .next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_SIGNED_DATA));
+                        writer.next().write_tagged(Tag::context(0), |writer| {
+                            writer.write_sequence(|writer| {
+                                writer.next().write_u8(3);
+                                writer.next().write_set(|writer| {
+                                    write_algorithm(writer.next(), OID_SHA256, false);
+                                });
+                                writer.next().write_sequence(|writer| {
+                                    writer.next().write_oid(&ObjectIdentifier::from_slice(
+                                        OID_TIMESTAMP_INFO,
+                                    ));
+                                    writer.next().write_tagged(Tag::context(0), |writer| {
+                                        writer.write_bytes(&info)
+                                    });
+                                });
+                                writer.next().write_set(|_| {});
+                            });
+                        });
+                    });
+                })
+            });
+
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/timestamp-reply\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                response.len()
+            );
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&response).unwrap();
+        });
+
+        url
+    }
+
+    #[actix_rt::test]
+    async fn sign_and_verify() {
+        let (pkcs12, certificate) = create_certificate();
+        let signer = Signer::from_pkcs12(&pkcs12, PASSWORD, Some(start_tsa(false))).unwrap();
+        let options = SignatureOptions {
+            reason: Some("Test".into()),
+            timestamp: Some(true),
+            ..Default::default()
+        };
+
+        let signed = sign_document(create_pdf(), &signer, &options)
+            .await
+            .unwrap();
+
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(certificate).unwrap();
+        let report = verification::verify_document(&signed, None, &store.build()).unwrap();
+
+        assert!(report.valid, "{:#?}", report);
+        assert!(!report.modified);
+        assert_eq!(report.signatures.len(), 1);
+        let signature = &report.signatures[0];
+        assert!(signature.timestamped);
+        assert!(signature.covers_whole_document);
+        assert_eq!(signature.signer.as_deref(), Some("PDFiller Test"));
+        assert_eq!(signature.reason.as_deref(), Some("Test"));
+    }
+
+    #[actix_rt::test]
+    async fn refuse_tampered_timestamp() {
+        let (pkcs12, _) = create_certificate();
+        let signer = Signer::from_pkcs12(&pkcs12, PASSWORD, Some(start_tsa(true))).unwrap();
+        let options = SignatureOptions {
+            timestamp: Some(true),
+            ..Default::default()
+        };
+
+        match sign_document(create_pdf(), &signer, &options).await {
+            Err(SignatureError::Timestamp(message)) => assert!(message.contains("nonce")),
+            result => panic!("the tampered token was accepted: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn detect_changed_bytes() {
+        let (pkcs12, certificate) = create_certificate();
+        let signer = Signer::from_pkcs12(&pkcs12, PASSWORD, None).unwrap();
+
+        let mut signed = sign_document(create_pdf(), &signer, &SignatureOptions::default())
+            .await
+            .unwrap();
+        let position = signed
+            .windows(3)
+            .position(|window| window == b"595")
+            .unwrap();
+        signed[position + 2] = b'6';
+
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(certificate).unwrap();
+        let report = verification::verify_document(&signed, None, &store.build()).unwrap();
+
+        assert!(!report.valid);
+        assert!(!report.signatures[0].digest_valid);
+        assert!(report.signatures[0].certificate_trusted);
+    }
+}
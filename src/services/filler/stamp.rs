@@ -0,0 +1,393 @@
+use std::collections::BTreeMap;
+
+use log::warn;
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document as PdfDocument, Object, ObjectId, Stream, StringFormat};
+use serde::Deserialize;
+
+use crate::services::filler::processor::DocumentPages;
+use crate::utils;
+
+const FONT_RESOURCE_PREFIX: &str = "PFStamp";
+const FONT_ENCODING: &str = "WinAnsiEncoding";
+const DEFAULT_FONT: &str = "Helvetica";
+const DEFAULT_FONT_SIZE: f64 = 9.0;
+const DEFAULT_MARGIN: f64 = 24.0;
+const DEFAULT_BATES_DIGITS: usize = 6;
+const DEFAULT_BATES_START: u64 = 1;
+
+const STANDARD_FONTS: [&str; 14] = [
+    "Times-Roman",
+    "Times-Bold",
+    "Times-Italic",
+    "Times-BoldItalic",
+    "Helvetica",
+    "Helvetica-Bold",
+    "Helvetica-Oblique",
+    "Helvetica-BoldOblique",
+    "Courier",
+    "Courier-Bold",
+    "Courier-Oblique",
+    "Courier-BoldOblique",
+    "Symbol",
+    "ZapfDingbats",
+];
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StampPosition {
+    TopLeft,
+    TopCenter,
+    TopRight,
+    BottomLeft,
+    BottomCenter,
+    BottomRight,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StampStyle {
+    pub font: Option<String>,
+    pub font_size: Option<f64>,
+    pub margin: Option<f64>,
+}
+
+/// A text line stamped on every page, the template accepts the following placeholders:
+/// `{page}`, `{total}`, `{doc_title}`, `{doc_date}`, `{doc_page}`, `{doc_total}` and `{bates}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StampText {
+    pub template: String,
+    pub position: Option<StampPosition>,
+    #[serde(flatten)]
+    pub style: StampStyle,
+}
+
+/// Sequential Bates numbering across the whole bundle, e.g. `ACME000001`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BatesOptions {
+    pub prefix: Option<String>,
+    pub digits: Option<usize>,
+    pub start: Option<u64>,
+    pub position: Option<StampPosition>,
+    #[serde(flatten)]
+    pub style: StampStyle,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StampOptions {
+    pub header: Option<StampText>,
+    pub footer: Option<StampText>,
+    pub bates: Option<BatesOptions>,
+}
+
+impl StampOptions {
+    pub fn is_empty(&self) -> bool {
+        self.header.is_none() && self.footer.is_none() && self.bates.is_none()
+    }
+}
+
+impl BatesOptions {
+    fn number(&self, index: usize) -> String {
+        format!(
+            "{}{:0width$}",
+            self.prefix.as_deref().unwrap_or(""),
+            self.start.unwrap_or(DEFAULT_BATES_START) + index as u64,
+            width = self.digits.unwrap_or(DEFAULT_BATES_DIGITS)
+        )
+    }
+}
+
+struct PageContext<'a> {
+    page: usize,
+    total: usize,
+    document: &'a DocumentPages,
+    document_page: usize,
+    bates: String,
+}
+
+impl<'a> PageContext<'a> {
+    fn render<S: AsRef<str>>(&self, template: S) -> String {
+        template
+            .as_ref()
+            .replace("{page}", &self.page.to_string())
+            .replace("{total}", &self.total.to_string())
+            .replace("{doc_title}", &self.document.title)
+            .replace(
+                "{doc_date}",
+                &self.document.date.format("%Y-%m-%d").to_string(),
+            )
+            .replace("{doc_page}", &self.document_page.to_string())
+            .replace("{doc_total}", &self.document.pages.len().to_string())
+            .replace("{bates}", &self.bates)
+    }
+}
+
+/// Stamps headers, footers and Bates numbers on every page of the document, `page_map` describes
+/// which source document each page (in reading order) belongs to.
+pub fn stamp_pages(
+    document: &mut PdfDocument,
+    page_map: &[DocumentPages],
+    options: &StampOptions,
+) -> Result<(), lopdf::Error> {
+    if options.is_empty() {
+        return Ok(());
+    }
+
+    let pages = document.get_pages().into_values().collect::<Vec<_>>();
+
+    let owners = page_map
+        .iter()
+        .flat_map(|document_pages| {
+            (1..=document_pages.pages.len()).map(move |page| (document_pages, page))
+        })
+        .collect::<Vec<_>>();
+
+    let mut fonts = BTreeMap::new();
+
+    for (index, page_id) in pages.iter().enumerate() {
+        let (owner, document_page) = match owners.get(index) {
+            Some(owner) => *owner,
+            None => {
+                warn!(
+                    "Page {} has no source document, stamping skipped",
+                    index + 1
+                );
+
+                continue;
+            }
+        };
+
+        let context = PageContext {
+            page: index + 1,
+            total: pages.len(),
+            document: owner,
+            document_page,
+            bates: options
+                .bates
+                .as_ref()
+                .map(|bates| bates.number(index))
+                .unwrap_or_default(),
+        };
+
+        let page_box = utils::get_page_box(document, *page_id);
+
+        let mut lines = Vec::new();
+        if let Some(ref header) = options.header {
+            lines.push((
+                context.render(&header.template),
+                header.position.unwrap_or(StampPosition::TopCenter),
+                &header.style,
+            ));
+        }
+        if let Some(ref footer) = options.footer {
+            lines.push((
+                context.render(&footer.template),
+                footer.position.unwrap_or(StampPosition::BottomCenter),
+                &footer.style,
+            ));
+        }
+        if let Some(ref bates) = options.bates {
+            lines.push((
+                context.bates.clone(),
+                bates.position.unwrap_or(StampPosition::BottomRight),
+                &bates.style,
+            ));
+        }
+
+        let mut operations = Vec::new();
+        for (text, position, style) in lines {
+            let font = get_font_name(style);
+            let font_count = fonts.len();
+            let resource_name = fonts
+                .entry(font)
+                .or_insert_with(|| format!("{}{}", FONT_RESOURCE_PREFIX, font_count + 1))
+                .clone();
+
+            operations.extend(text_operations(
+                &text,
+                font,
+                &resource_name,
+                position,
+                style,
+                page_box,
+            ));
+        }
+
+        add_page_content(document, *page_id, operations)?;
+    }
+
+    let font_resources = fonts
+        .into_iter()
+        .map(|(font, resource_name)| {
+            let mut dictionary = Dictionary::new();
+            dictionary.set("Type", Object::Name(b"Font".to_vec()));
+            dictionary.set("Subtype", Object::Name(b"Type1".to_vec()));
+            dictionary.set("BaseFont", Object::Name(font.as_bytes().to_vec()));
+            dictionary.set("Encoding", Object::Name(FONT_ENCODING.as_bytes().to_vec()));
+
+            (resource_name, document.add_object(dictionary))
+        })
+        .collect::<Vec<_>>();
+
+    for page_id in pages {
+        add_page_fonts(document, page_id, &font_resources)?;
+    }
+
+    Ok(())
+}
+
+fn get_font_name(style: &StampStyle) -> &'static str {
+    match style.font.as_deref() {
+        Some(font) => STANDARD_FONTS
+            .iter()
+            .find(|standard_font| standard_font.eq_ignore_ascii_case(font))
+            .copied()
+            .unwrap_or_else(|| {
+                warn!(
+                    "Font \"{}\" is not a standard font, using {}",
+                    font, DEFAULT_FONT
+                );
+
+                DEFAULT_FONT
+            }),
+        None => DEFAULT_FONT,
+    }
+}
+
+/// Standard fonts metrics aren't available, the average glyph width is a good approximation.
+pub fn get_text_width(text: &str, font: &str, font_size: f64) -> f64 {
+    let glyph_width = if font.starts_with("Courier") {
+        0.6
+    } else {
+        0.5
+    };
+
+    text.chars().count() as f64 * font_size * glyph_width
+}
+
+fn text_operations(
+    text: &str,
+    font: &str,
+    resource_name: &str,
+    position: StampPosition,
+    style: &StampStyle,
+    page_box: (f64, f64, f64, f64),
+) -> Vec<Operation> {
+    let font_size = style.font_size.unwrap_or(DEFAULT_FONT_SIZE);
+    let margin = style.margin.unwrap_or(DEFAULT_MARGIN);
+
+    let text_width = get_text_width(text, font, font_size);
+
+    let (x1, y1, x2, y2) = page_box;
+    let x = match position {
+        StampPosition::TopLeft | StampPosition::BottomLeft => x1 + margin,
+        StampPosition::TopCenter | StampPosition::BottomCenter => (x1 + x2 - text_width) / 2.0,
+        StampPosition::TopRight | StampPosition::BottomRight => x2 - margin - text_width,
+    };
+    let y = match position {
+        StampPosition::TopLeft | StampPosition::TopCenter | StampPosition::TopRight => {
+            y2 - margin - font_size
+        }
+        _ => y1 + margin,
+    };
+
+    vec![
+        Operation::new("BT", vec![]),
+        Operation::new("g", vec![0.into()]),
+        Operation::new(
+            "Tf",
+            vec![
+                Object::Name(resource_name.as_bytes().to_vec()),
+                font_size.into(),
+            ],
+        ),
+        Operation::new("Td", vec![x.into(), y.into()]),
+        Operation::new(
+            "Tj",
+            vec![Object::String(
+                PdfDocument::encode_text(Some(FONT_ENCODING), text),
+                StringFormat::Literal,
+            )],
+        ),
+        Operation::new("ET", vec![]),
+    ]
+}
+
+/// Appends the operations to the page, existing content is wrapped in a saved graphics state so
+/// transformations left over by the original content don't affect the new operations.
+pub fn add_page_content(
+    document: &mut PdfDocument,
+    page_id: ObjectId,
+    operations: Vec<Operation>,
+) -> Result<(), lopdf::Error> {
+    let content = Content { operations }.encode()?;
+
+    let mut contents = match document.get_dictionary(page_id)?.get(b"Contents") {
+        Ok(Object::Array(contents)) => contents.clone(),
+        Ok(contents) => vec![contents.clone()],
+        Err(_) => vec![],
+    };
+
+    let save_id = document.add_object(Stream::new(Dictionary::new(), b"q\n".to_vec()));
+    let content_id = document.add_object(Stream::new(
+        Dictionary::new(),
+        [b"Q\n".to_vec(), content].concat(),
+    ));
+
+    contents.insert(0, Object::Reference(save_id));
+    contents.push(Object::Reference(content_id));
+
+    document
+        .get_object_mut(page_id)?
+        .as_dict_mut()?
+        .set("Contents", contents);
+
+    Ok(())
+}
+
+/// Registers the fonts in the page resources, inherited resources are copied into the page first.
+pub fn add_page_fonts(
+    document: &mut PdfDocument,
+    page_id: ObjectId,
+    fonts: &[(String, ObjectId)],
+) -> Result<(), lopdf::Error> {
+    if fonts.is_empty() {
+        return Ok(());
+    }
+
+    if !document.get_dictionary(page_id)?.has(b"Resources") {
+        let resources = utils::get_inherited_attribute(document, page_id, b"Resources")
+            .and_then(|object| object.as_dict().ok())
+            .cloned()
+            .unwrap_or_default();
+
+        document
+            .get_object_mut(page_id)?
+            .as_dict_mut()?
+            .set("Resources", resources);
+    }
+
+    let font_dictionary_id = document
+        .get_or_create_resources(page_id)?
+        .as_dict()?
+        .get(b"Font")
+        .and_then(Object::as_reference)
+        .ok();
+
+    let font_dictionary = match font_dictionary_id {
+        Some(font_dictionary_id) => document.get_object_mut(font_dictionary_id)?.as_dict_mut()?,
+        None => {
+            let resources = document.get_or_create_resources(page_id)?.as_dict_mut()?;
+            if !resources.has(b"Font") {
+                resources.set("Font", Dictionary::new());
+            }
+
+            resources.get_mut(b"Font")?.as_dict_mut()?
+        }
+    };
+
+    for (resource_name, font_id) in fonts {
+        font_dictionary.set(resource_name.as_str(), Object::Reference(*font_id));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use std::fmt::{Display, Formatter};
+
+use lopdf::{Dictionary, Document as PdfDocument, Object};
+
+use crate::config::UploadConfig;
+
+pub const PDF_MAGIC: &[u8] = b"%PDF-";
+/// Readers accept the header anywhere in the first KiB of the file
+const HEADER_WINDOW: usize = 1024;
+
+#[derive(Debug)]
+pub enum UploadError {
+    NotPdf,
+    Invalid(String),
+    TooLarge { max: usize },
+    TooManyPages { pages: usize, max: usize },
+    JavaScript,
+    LaunchAction,
+    EmbeddedFiles,
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::NotPdf => write!(f, "The file is not a PDF"),
+            UploadError::Invalid(e) => write!(f, "The PDF can't be read: {}", e),
+            UploadError::TooLarge { max } => {
+                write!(f, "The file is larger than the {} bytes accepted", max)
+            }
+            UploadError::TooManyPages { pages, max } => write!(
+                f,
+                "The PDF has {} pages, at most {} are accepted",
+                pages, max
+            ),
+            UploadError::JavaScript => write!(f, "PDFs running JavaScript aren't accepted"),
+            UploadError::LaunchAction => write!(f, "PDFs launching applications aren't accepted"),
+            UploadError::EmbeddedFiles => write!(f, "PDFs with embedded files aren't accepted"),
+        }
+    }
+}
+
+/// Active content found in a document.
+#[derive(Default)]
+struct ActiveContent {
+    javascript: bool,
+    launch: bool,
+    embedded_files: bool,
+}
+
+/// Checks the size and the header of the uploaded bytes, before anything is parsed.
+pub fn check_upload(buffer: &[u8], config: &UploadConfig) -> Result<(), UploadError> {
+    if let Some(max) = config.max_size {
+        if buffer.len() > max {
+            return Err(UploadError::TooLarge { max });
+        }
+    }
+
+    if !has_pdf_header(buffer) {
+        return Err(UploadError::NotPdf);
+    }
+
+    Ok(())
+}
+
+/// Whether the PDF header is in the first KiB, where readers look for it.
+pub fn has_pdf_header(buffer: &[u8]) -> bool {
+    buffer[..buffer.len().min(HEADER_WINDOW)]
+        .windows(PDF_MAGIC.len())
+        .any(|window| window == PDF_MAGIC)
+}
+
+/// Parses the decrypted template, checking its pages and the active content rejected by the
+/// configuration.
+pub fn check_document(buffer: &[u8], config: &UploadConfig) -> Result<(), UploadError> {
+    let document =
+        PdfDocument::load_mem(buffer).map_err(|e| UploadError::Invalid(format!("{:?}", e)))?;
+
+    let pages = document.get_pages().len();
+    if pages == 0 {
+        return Err(UploadError::Invalid("the document has no pages".into()));
+    }
+    if let Some(max) = config.max_pages {
+        if pages > max {
+            return Err(UploadError::TooManyPages { pages, max });
+        }
+    }
+
+    let reject_javascript = config.reject_javascript.unwrap_or(false);
+    let reject_launch = config.reject_launch_actions.unwrap_or(false);
+    let reject_embedded_files = config.reject_embedded_files.unwrap_or(false);
+    if !reject_javascript && !reject_launch && !reject_embedded_files {
+        return Ok(());
+    }
+
+    let mut content = ActiveContent::default();
+    for object in document.objects.values() {
+        scan_object(object, &mut content);
+    }
+
+    if reject_javascript && content.javascript {
+        Err(UploadError::JavaScript)
+    } else if reject_launch && content.launch {
+        Err(UploadError::LaunchAction)
+    } else if reject_embedded_files && content.embedded_files {
+        Err(UploadError::EmbeddedFiles)
+    } else {
+        Ok(())
+    }
+}
+
+/// Actions and attachments may be inline in other dictionaries, nested objects are scanned too.
+fn scan_object(object: &Object, content: &mut ActiveContent) {
+    match object {
+        Object::Dictionary(dictionary) => scan_dictionary(dictionary, content),
+        Object::Stream(stream) => scan_dictionary(&stream.dict, content),
+        Object::Array(array) => {
+            for object in array {
+                scan_object(object, content);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn scan_dictionary(dictionary: &Dictionary, content: &mut ActiveContent) {
+    let is_name = |key: &[u8], value: &[u8]| matches!(dictionary.get(key).and_then(Object::as_name), Ok(name) if name == value);
+
+    // Actions, and the JavaScript and EmbeddedFiles name trees of the catalog
+    if is_name(b"S", b"JavaScript") || dictionary.has(b"JS") || dictionary.has(b"JavaScript") {
+        content.javascript = true;
+    }
+    if is_name(b"S", b"Launch") {
+        content.launch = true;
+    }
+    if is_name(b"Type", b"EmbeddedFile")
+        || is_name(b"Subtype", b"FileAttachment")
+        || dictionary.has(b"EmbeddedFiles")
+        || dictionary.has(b"EF")
+    {
+        content.embedded_files = true;
+    }
+
+    for (_, object) in dictionary.iter() {
+        scan_object(object, content);
+    }
+}
@@ -0,0 +1,663 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use lopdf_security::{
+    Dictionary, Document as SecuredDocument, Error as SecurityLoadError, LoadOptions, Object,
+};
+use openssl::cms::{CMSOptions, CmsContentInfo};
+use openssl::error::ErrorStack;
+use openssl::hash::{self, MessageDigest};
+use openssl::stack::Stack;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use openssl::x509::{X509StoreContext, X509};
+use serde::Serialize;
+use yasna::models::ObjectIdentifier;
+use yasna::tags::TAG_SEQUENCE;
+use yasna::{ASN1Error, ASN1ErrorKind, ASN1Result, BERReader, Tag};
+
+use crate::config::SignatureConfig;
+use crate::services::filler::metadata;
+use crate::services::filler::security::SecurityError;
+use crate::services::filler::signature::{self, SignatureField};
+
+const EOF_MARKER: &[u8] = b"%%EOF";
+
+const SUB_FILTER_SHA1: &str = "adbe.pkcs7.sha1";
+const SUPPORTED_SUB_FILTERS: [&str; 3] = [
+    "adbe.pkcs7.detached",
+    "ETSI.CAdES.detached",
+    SUB_FILTER_SHA1,
+];
+
+const OID_SHA1: &[u64] = &[1, 3, 14, 3, 2, 26];
+const OID_SHA384: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 2];
+const OID_SHA512: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 3];
+
+#[derive(Debug, Serialize)]
+pub struct VerificationReport {
+    /// Every signature is valid, trusted and the document wasn't modified after signing
+    pub valid: bool,
+    pub modified: bool,
+    pub signatures: Vec<SignatureReport>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SignatureReport {
+    pub field: String,
+    pub signed: bool,
+    pub signer: Option<String>,
+    pub signing_time: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+    pub location: Option<String>,
+    pub contact_info: Option<String>,
+    pub sub_filter: Option<String>,
+    pub timestamped: bool,
+    pub byte_range_valid: bool,
+    pub digest_valid: bool,
+    pub signature_valid: bool,
+    pub certificate_trusted: bool,
+    /// The signed byte range ends with the file
+    pub covers_whole_document: bool,
+    /// Revisions other than later signatures were appended after this signature
+    pub modified_after_signing: bool,
+    pub issues: Vec<String>,
+}
+
+impl SignatureReport {
+    fn is_valid(&self) -> bool {
+        self.signed
+            && self.byte_range_valid
+            && self.digest_valid
+            && self.signature_valid
+            && self.certificate_trusted
+            && !self.modified_after_signing
+    }
+}
+
+#[derive(Debug)]
+pub enum VerificationError {
+    Security(SecurityError),
+    Pdf(String),
+    TrustStore(String),
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Security(e) => {
+                write!(f, "{}", e)
+            }
+            Self::Pdf(message) => {
+                write!(f, "The PDF couldn't be read: {}", message)
+            }
+            Self::TrustStore(message) => {
+                write!(f, "The trust store couldn't be loaded: {}", message)
+            }
+        }
+    }
+}
+
+impl From<ErrorStack> for VerificationError {
+    fn from(e: ErrorStack) -> Self {
+        VerificationError::TrustStore(e.to_string())
+    }
+}
+
+enum SignerIdentifier {
+    IssuerAndSerialNumber(Vec<u8>, Vec<u8>),
+    KeyIdentifier(Vec<u8>),
+}
+
+impl SignerIdentifier {
+    fn matches(&self, certificate: &X509) -> bool {
+        match self {
+            Self::IssuerAndSerialNumber(issuer, serial) => {
+                certificate
+                    .issuer_name()
+                    .to_der()
+                    .map(|name| &name == issuer)
+                    .unwrap_or(false)
+                    && certificate
+                        .serial_number()
+                        .to_bn()
+                        .map(|number| number.to_vec().as_slice() == trim_leading_zeros(serial))
+                        .unwrap_or(false)
+            }
+            Self::KeyIdentifier(key_identifier) => certificate
+                .subject_key_id()
+                .map(|id| id.as_slice() == key_identifier.as_slice())
+                .unwrap_or(false),
+        }
+    }
+}
+
+struct SignerInfo {
+    identifier: SignerIdentifier,
+    digest_algorithm: ObjectIdentifier,
+    message_digest: Option<Vec<u8>>,
+    timestamped: bool,
+}
+
+struct SignedData {
+    certificates: Vec<Vec<u8>>,
+    signer: SignerInfo,
+}
+
+/// Loads the trusted certificates from the configured PEM file or directory, the system ones
+/// when missing.
+pub fn get_trust_store(config: Option<&SignatureConfig>) -> Result<X509Store, VerificationError> {
+    let mut builder = X509StoreBuilder::new()?;
+
+    match config
+        .and_then(|config| config.trust_store.as_ref())
+        .filter(|trust_store| !trust_store.is_empty())
+    {
+        Some(trust_store) => {
+            let path = Path::new(trust_store);
+            let files = if path.is_dir() {
+                fs::read_dir(path)
+                    .map_err(|e| VerificationError::TrustStore(format!("{:#?}", e)))?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.is_file())
+                    .collect()
+            } else {
+                vec![path.to_path_buf()]
+            };
+
+            for file in files {
+                let pem = fs::read(&file)
+                    .map_err(|e| VerificationError::TrustStore(format!("{:#?}", e)))?;
+                for certificate in X509::stack_from_pem(&pem).map_err(|e| {
+                    VerificationError::TrustStore(format!("{}, {}", file.display(), e))
+                })? {
+                    builder.add_cert(certificate)?;
+                }
+            }
+        }
+        None => builder.set_default_paths()?,
+    }
+
+    Ok(builder.build())
+}
+
+/// Verifies every signature of the document: the byte range, the digest of the signed bytes, the
+/// CMS signature and the signer certificate chain against the trust store. A document is reported
+/// as modified when an incremental update that doesn't end with a signature follows a signature.
+pub fn verify_document(
+    buffer: &[u8],
+    password: Option<&str>,
+    store: &X509Store,
+) -> Result<VerificationReport, VerificationError> {
+    let options = match password {
+        Some(password) => LoadOptions::with_password(password),
+        None => LoadOptions::default(),
+    };
+    let document = match SecuredDocument::load_mem_with_options(buffer, options) {
+        Ok(document) => document,
+        Err(SecurityLoadError::InvalidPassword) => {
+            return Err(VerificationError::Security(SecurityError::InvalidPassword));
+        }
+        Err(e) => {
+            return Err(VerificationError::Pdf(e.to_string()));
+        }
+    };
+    if document.is_encrypted() && !document.was_encrypted() {
+        return Err(VerificationError::Security(if password.is_some() {
+            SecurityError::InvalidPassword
+        } else {
+            SecurityError::PasswordRequired
+        }));
+    }
+
+    let (mut signatures, ends): (Vec<_>, Vec<_>) = signature::get_signature_fields(&document)
+        .into_iter()
+        .map(|field| verify_field(&document, buffer, &field, store))
+        .unzip();
+
+    let revisions = get_revisions(buffer);
+    let signature_ends = ends.iter().flatten().copied().collect::<Vec<_>>();
+    let is_signature_revision = |(start, end): &(usize, usize)| {
+        signature_ends
+            .iter()
+            .any(|signature_end| *signature_end >= start + EOF_MARKER.len() && signature_end <= end)
+    };
+
+    for (report, end) in signatures.iter_mut().zip(ends.iter()) {
+        if let Some(end) = *end {
+            let trailing = &buffer[end..];
+            report.covers_whole_document = trailing.iter().all(u8::is_ascii_whitespace);
+
+            let later_revisions = revisions
+                .iter()
+                .filter(|(start, _)| *start >= end)
+                .collect::<Vec<_>>();
+            let last_revision_end = revisions.last().map(|(_, end)| *end).unwrap_or(0).max(end);
+
+            report.modified_after_signing = later_revisions
+                .iter()
+                .any(|revision| !is_signature_revision(revision))
+                || !buffer[last_revision_end..]
+                    .iter()
+                    .all(u8::is_ascii_whitespace);
+
+            if !report.covers_whole_document && !report.modified_after_signing {
+                report
+                    .issues
+                    .push("The document was signed again after this signature".into());
+            }
+        }
+    }
+
+    Ok(VerificationReport {
+        valid: !signatures.is_empty() && signatures.iter().all(SignatureReport::is_valid),
+        modified: signatures
+            .iter()
+            .any(|signature| signature.modified_after_signing),
+        signatures,
+    })
+}
+
+fn verify_field(
+    document: &SecuredDocument,
+    buffer: &[u8],
+    field: &SignatureField,
+    store: &X509Store,
+) -> (SignatureReport, Option<usize>) {
+    let mut report = SignatureReport {
+        field: field.name.clone(),
+        signed: field.signed,
+        ..Default::default()
+    };
+    if !field.signed {
+        return (report, None);
+    }
+
+    let dictionary = match document
+        .get_dictionary(field.id)
+        .and_then(|field| field.get(b"V"))
+        .and_then(|value| document.dereference(value))
+        .and_then(|(_, value)| value.as_dict())
+    {
+        Ok(dictionary) => dictionary,
+        Err(e) => {
+            report
+                .issues
+                .push(format!("The signature dictionary couldn't be read: {}", e));
+
+            return (report, None);
+        }
+    };
+
+    report.sub_filter = dictionary
+        .get(b"SubFilter")
+        .and_then(Object::as_name)
+        .map(|sub_filter| String::from_utf8_lossy(sub_filter).into_owned())
+        .ok();
+    report.signing_time =
+        get_text(dictionary, b"M").and_then(|date| metadata::parse_pdf_date(&date));
+    report.reason = get_text(dictionary, b"Reason");
+    report.location = get_text(dictionary, b"Location");
+    report.contact_info = get_text(dictionary, b"ContactInfo");
+
+    let byte_range = dictionary
+        .get(b"ByteRange")
+        .and_then(Object::as_array)
+        .map(|byte_range| {
+            byte_range
+                .iter()
+                .filter_map(|value| value.as_i64().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let (content, contents, end) = match get_signed_content(buffer, &byte_range) {
+        Ok(signed_content) => signed_content,
+        Err(issue) => {
+            report.issues.push(issue);
+
+            return (report, None);
+        }
+    };
+    report.byte_range_valid = true;
+
+    let sub_filter = report.sub_filter.clone().unwrap_or_default();
+    if !SUPPORTED_SUB_FILTERS.contains(&sub_filter.as_str()) {
+        report.issues.push(format!(
+            "The signature format \"{}\" isn't supported",
+            sub_filter
+        ));
+
+        return (report, Some(end));
+    }
+
+    // Signatures are padded with zeros, the DER encoding is read back without them
+    let (mut cms, signed_data) = match CmsContentInfo::from_der(&contents)
+        .and_then(|cms| cms.to_der().map(|der| (cms, parse_signed_data(&der))))
+    {
+        Ok((cms, Ok(signed_data))) => (cms, signed_data),
+        Ok((_, Err(e))) => {
+            report
+                .issues
+                .push(format!("The signature isn't a valid CMS signature: {}", e));
+
+            return (report, Some(end));
+        }
+        Err(e) => {
+            report
+                .issues
+                .push(format!("The signature isn't a valid CMS signature: {}", e));
+
+            return (report, Some(end));
+        }
+    };
+    report.timestamped = signed_data.signer.timestamped;
+
+    let certificates = signed_data
+        .certificates
+        .iter()
+        .filter_map(|certificate| X509::from_der(certificate).ok())
+        .collect::<Vec<_>>();
+    let signer_certificate = certificates
+        .iter()
+        .find(|certificate| signed_data.signer.identifier.matches(certificate));
+    report.signer =
+        signer_certificate.map(|certificate| signature::certificate_subject(certificate));
+
+    let flags = CMSOptions::NO_SIGNER_CERT_VERIFY | CMSOptions::BINARY;
+    if sub_filter == SUB_FILTER_SHA1 {
+        // The digest of the byte range is the signed content
+        let mut encapsulated = Vec::new();
+        match cms.verify(None, None, None, Some(&mut encapsulated), flags) {
+            Ok(_) => report.signature_valid = true,
+            Err(e) => report
+                .issues
+                .push(format!("The CMS signature isn't valid: {}", e)),
+        }
+        report.digest_valid = hash::hash(MessageDigest::sha1(), &content)
+            .map(|digest| digest.as_ref() == encapsulated.as_slice())
+            .unwrap_or(false);
+    } else {
+        match get_message_digest(&signed_data.signer.digest_algorithm) {
+            Some(digest_algorithm) => {
+                report.digest_valid = match (
+                    hash::hash(digest_algorithm, &content),
+                    signed_data.signer.message_digest.as_ref(),
+                ) {
+                    (Ok(digest), Some(message_digest)) => {
+                        digest.as_ref() == message_digest.as_slice()
+                    }
+                    _ => false,
+                };
+            }
+            None => report.issues.push(format!(
+                "The digest algorithm {} isn't supported",
+                signed_data.signer.digest_algorithm
+            )),
+        }
+
+        match cms.verify(None, None, Some(&content), None, flags) {
+            Ok(_) => report.signature_valid = true,
+            Err(e) => report
+                .issues
+                .push(format!("The CMS signature isn't valid: {}", e)),
+        }
+    }
+    if !report.digest_valid {
+        report
+            .issues
+            .push("The signed bytes don't match the signature digest".into());
+    }
+
+    match signer_certificate {
+        Some(signer_certificate) => {
+            match verify_certificate(signer_certificate, &certificates, store) {
+                Ok(None) => report.certificate_trusted = true,
+                Ok(Some(error)) => report
+                    .issues
+                    .push(format!("The signer certificate isn't trusted: {}", error)),
+                Err(e) => report.issues.push(format!(
+                    "The signer certificate couldn't be verified: {}",
+                    e
+                )),
+            }
+        }
+        None => report
+            .issues
+            .push("The signer certificate isn't included in the signature".into()),
+    }
+
+    (report, Some(end))
+}
+
+/// Returns the signed bytes, the signature and the end of the signed revision.
+fn get_signed_content(
+    buffer: &[u8],
+    byte_range: &[i64],
+) -> Result<(Vec<u8>, Vec<u8>, usize), String> {
+    let byte_range = byte_range
+        .iter()
+        .filter(|value| **value >= 0)
+        .map(|value| *value as usize)
+        .collect::<Vec<_>>();
+
+    match byte_range.as_slice() {
+        [0, first_length, second_start, second_length]
+            if first_length < second_start
+                && second_start + second_length <= buffer.len()
+                && second_start - first_length >= 2 =>
+        {
+            let gap = &buffer[*first_length..*second_start];
+            if gap[0] != b'<' || gap[gap.len() - 1] != b'>' {
+                return Err("The byte range excludes more than the signature".into());
+            }
+
+            let hex = gap[1..gap.len() - 1]
+                .iter()
+                .filter(|byte| !byte.is_ascii_whitespace())
+                .copied()
+                .collect::<Vec<_>>();
+            let contents = hex
+                .chunks(2)
+                .map(|pair| {
+                    std::str::from_utf8(pair)
+                        .ok()
+                        .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| "The signature isn't a valid hexadecimal string".to_string())?;
+
+            Ok((
+                [
+                    &buffer[..*first_length],
+                    &buffer[*second_start..second_start + second_length],
+                ]
+                .concat(),
+                contents,
+                second_start + second_length,
+            ))
+        }
+        _ => Err("The byte range isn't valid".into()),
+    }
+}
+
+/// Returns the `%%EOF` positions with the end of the revision, line endings included.
+fn get_revisions(buffer: &[u8]) -> Vec<(usize, usize)> {
+    buffer
+        .windows(EOF_MARKER.len())
+        .enumerate()
+        .filter(|(_, window)| *window == EOF_MARKER)
+        .map(|(start, _)| {
+            let end = start
+                + EOF_MARKER.len()
+                + buffer[start + EOF_MARKER.len()..]
+                    .iter()
+                    .take(2)
+                    .take_while(|byte| **byte == b'\r' || **byte == b'\n')
+                    .count();
+
+            (start, end)
+        })
+        .collect()
+}
+
+fn verify_certificate(
+    certificate: &X509,
+    certificates: &[X509],
+    store: &X509Store,
+) -> Result<Option<String>, ErrorStack> {
+    let mut chain = Stack::new()?;
+    for intermediate in certificates {
+        chain.push(intermediate.clone())?;
+    }
+
+    let mut context = X509StoreContext::new()?;
+    context.init(store, certificate, &chain, |context| {
+        Ok(if context.verify_cert()? {
+            None
+        } else {
+            Some(context.error().error_string().to_owned())
+        })
+    })
+}
+
+fn parse_signed_data(der: &[u8]) -> ASN1Result<SignedData> {
+    yasna::parse_ber(der, |reader| {
+        reader.read_sequence(|reader| {
+            if reader.next().read_oid()? != ObjectIdentifier::from_slice(signature::OID_SIGNED_DATA)
+            {
+                return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+            }
+
+            reader.next().read_tagged(Tag::context(0), |reader| {
+                reader.read_sequence(|reader| {
+                    reader.next().read_u8()?; // version
+                    reader.next().read_der()?; // digestAlgorithms
+                    reader.next().read_der()?; // encapContentInfo
+                    let certificates = reader
+                        .read_optional(|reader| {
+                            reader.read_tagged_implicit(Tag::context(0), |reader| {
+                                reader.collect_set_of(|reader| reader.read_der())
+                            })
+                        })?
+                        .unwrap_or_default();
+                    reader.read_optional(|reader| {
+                        reader.read_tagged_implicit(Tag::context(1), |reader| {
+                            reader.read_set_of(|reader| reader.read_der().map(|_| ()))
+                        })
+                    })?;
+
+                    // PDF signatures have a single signer
+                    let signer = reader
+                        .next()
+                        .collect_set_of(parse_signer_info)?
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| ASN1Error::new(ASN1ErrorKind::Invalid))?;
+
+                    Ok(SignedData {
+                        certificates,
+                        signer,
+                    })
+                })
+            })
+        })
+    })
+}
+
+fn parse_signer_info(reader: BERReader) -> ASN1Result<SignerInfo> {
+    reader.read_sequence(|reader| {
+        reader.next().read_u8()?; // version
+
+        let identifier = {
+            let reader = reader.next();
+            if reader.lookahead_tag()? == TAG_SEQUENCE {
+                reader.read_sequence(|reader| {
+                    let issuer = reader.next().read_der()?;
+                    let (serial, _) = reader.next().read_bigint_bytes()?;
+
+                    Ok(SignerIdentifier::IssuerAndSerialNumber(issuer, serial))
+                })?
+            } else {
+                SignerIdentifier::KeyIdentifier(
+                    reader.read_tagged_implicit(Tag::context(0), |reader| reader.read_bytes())?,
+                )
+            }
+        };
+
+        let digest_algorithm = reader.next().read_sequence(|reader| {
+            let oid = reader.next().read_oid()?;
+            reader.read_optional(|reader| reader.read_der())?;
+
+            Ok(oid)
+        })?;
+        let signed_attributes = reader
+            .read_optional(|reader| {
+                reader.read_tagged_implicit(Tag::context(0), |reader| {
+                    reader.collect_set_of(parse_attribute)
+                })
+            })?
+            .unwrap_or_default();
+        reader.next().read_der()?; // signatureAlgorithm
+        reader.next().read_bytes()?; // signature
+        let unsigned_attributes = reader
+            .read_optional(|reader| {
+                reader.read_tagged_implicit(Tag::context(1), |reader| {
+                    reader.collect_set_of(parse_attribute)
+                })
+            })?
+            .unwrap_or_default();
+
+        let message_digest_oid = ObjectIdentifier::from_slice(signature::OID_MESSAGE_DIGEST);
+        let timestamp_token_oid = ObjectIdentifier::from_slice(signature::OID_TIMESTAMP_TOKEN);
+
+        Ok(SignerInfo {
+            identifier,
+            digest_algorithm,
+            message_digest: signed_attributes
+                .into_iter()
+                .find(|(oid, _)| oid == &message_digest_oid)
+                .and_then(|(_, values)| {
+                    values.first().and_then(|value| {
+                        yasna::parse_der(value, |reader| reader.read_bytes()).ok()
+                    })
+                }),
+            timestamped: unsigned_attributes
+                .iter()
+                .any(|(oid, _)| oid == &timestamp_token_oid),
+        })
+    })
+}
+
+fn parse_attribute(reader: BERReader) -> ASN1Result<(ObjectIdentifier, Vec<Vec<u8>>)> {
+    reader.read_sequence(|reader| {
+        Ok((
+            reader.next().read_oid()?,
+            reader.next().collect_set_of(|reader| reader.read_der())?,
+        ))
+    })
+}
+
+fn get_message_digest(oid: &ObjectIdentifier) -> Option<MessageDigest> {
+    match oid.components().as_slice() {
+        OID_SHA1 => Some(MessageDigest::sha1()),
+        signature::OID_SHA256 => Some(MessageDigest::sha256()),
+        OID_SHA384 => Some(MessageDigest::sha384()),
+        OID_SHA512 => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+fn get_text(dictionary: &Dictionary, key: &[u8]) -> Option<String> {
+    dictionary
+        .get(key)
+        .and_then(Object::as_str)
+        .map(metadata::decode_text_string)
+        .ok()
+}
+
+fn trim_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let zeros = bytes.iter().take_while(|byte| **byte == 0).count();
+
+    &bytes[zeros..]
+}
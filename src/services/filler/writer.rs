@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use lopdf::{Dictionary, Object, ObjectId, Stream, StringFormat};
+
+/// Writes a PDF object by object so the output can be sent while the following documents are
+/// still being loaded, lopdf only saves whole documents. The syntax matches `Document::save_to`.
+#[derive(Default)]
+pub struct PdfStreamWriter {
+    written: usize,
+    xref: BTreeMap<u32, (usize, u16)>,
+}
+
+impl PdfStreamWriter {
+    pub fn write_header(&mut self, buffer: &mut Vec<u8>, version: &str) {
+        self.track(buffer, |buffer| {
+            buffer.extend_from_slice(format!("%PDF-{}\n", version).as_bytes());
+        });
+    }
+
+    pub fn write_object(&mut self, buffer: &mut Vec<u8>, id: ObjectId, object: &Object) {
+        self.xref.insert(id.0, (self.written, id.1));
+
+        self.track(buffer, |buffer| {
+            buffer.extend_from_slice(
+                format!(
+                    "{} {} obj{}",
+                    id.0,
+                    id.1,
+                    if need_separator(object) { " " } else { "" }
+                )
+                .as_bytes(),
+            );
+            write_object(buffer, object);
+            buffer.extend_from_slice(
+                format!(
+                    "{}endobj\n",
+                    if need_end_separator(object) { " " } else { "" }
+                )
+                .as_bytes(),
+            );
+        });
+    }
+
+    /// Writes the cross-reference table and the trailer, `Size` is set from the written objects.
+    pub fn write_trailer(&mut self, buffer: &mut Vec<u8>, mut trailer: Dictionary) {
+        let xref_start = self.written;
+        let xref = mem::take(&mut self.xref);
+        let size = xref.keys().next_back().map(|id| id + 1).unwrap_or(1);
+
+        self.track(buffer, |buffer| {
+            buffer.extend_from_slice(format!("xref\n0 {}\n", size).as_bytes());
+            buffer.extend_from_slice(format!("{:>010} {:>05} f \n", 0, 65535).as_bytes());
+            for id in 1..size {
+                let entry = match xref.get(&id) {
+                    Some((offset, generation)) => {
+                        format!("{:>010} {:>05} n \n", offset, generation)
+                    }
+                    None => format!("{:>010} {:>05} f \n", 0, 65535),
+                };
+                buffer.extend_from_slice(entry.as_bytes());
+            }
+
+            trailer.set("Size", i64::from(size));
+            buffer.extend_from_slice(b"trailer\n");
+            write_dictionary(buffer, &trailer);
+            buffer.extend_from_slice(format!("\nstartxref\n{}\n%%EOF", xref_start).as_bytes());
+        });
+    }
+
+    /// Offsets count every byte written so far, buffers can be handed out between calls.
+    fn track<F: FnOnce(&mut Vec<u8>)>(&mut self, buffer: &mut Vec<u8>, write: F) {
+        let start = buffer.len();
+        write(buffer);
+        self.written += buffer.len() - start;
+    }
+}
+
+fn need_separator(object: &Object) -> bool {
+    matches!(
+        object,
+        Object::Null
+            | Object::Boolean(_)
+            | Object::Integer(_)
+            | Object::Real(_)
+            | Object::Reference(_)
+    )
+}
+
+fn need_end_separator(object: &Object) -> bool {
+    matches!(
+        object,
+        Object::Null
+            | Object::Boolean(_)
+            | Object::Integer(_)
+            | Object::Real(_)
+            | Object::Name(_)
+            | Object::Reference(_)
+            | Object::Stream(_)
+    )
+}
+
+fn write_object(buffer: &mut Vec<u8>, object: &Object) {
+    match object {
+        Object::Null => buffer.extend_from_slice(b"null"),
+        Object::Boolean(value) => {
+            buffer.extend_from_slice(if *value { b"true" } else { b"false" });
+        }
+        Object::Integer(value) => buffer.extend_from_slice(value.to_string().as_bytes()),
+        Object::Real(value) => buffer.extend_from_slice(format!("{:.02?}", value).as_bytes()),
+        Object::Name(name) => write_name(buffer, name),
+        Object::String(text, format) => write_string(buffer, text, format),
+        Object::Array(array) => {
+            buffer.push(b'[');
+            for (index, object) in array.iter().enumerate() {
+                if index > 0 && need_separator(object) {
+                    buffer.push(b' ');
+                }
+                write_object(buffer, object);
+            }
+            buffer.push(b']');
+        }
+        Object::Dictionary(dictionary) => write_dictionary(buffer, dictionary),
+        Object::Stream(stream) => write_stream(buffer, stream),
+        Object::Reference(id) => {
+            buffer.extend_from_slice(format!("{} {} R", id.0, id.1).as_bytes())
+        }
+    }
+}
+
+fn write_name(buffer: &mut Vec<u8>, name: &[u8]) {
+    buffer.push(b'/');
+    for &byte in name {
+        // White-space, delimiters and bytes outside of the printable range are encoded as #XX
+        if b" \t\n\r\x0C()<>[]{}/%#".contains(&byte) || !(33..=126).contains(&byte) {
+            buffer.extend_from_slice(format!("#{:02X}", byte).as_bytes());
+        } else {
+            buffer.push(byte);
+        }
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, text: &[u8], format: &StringFormat) {
+    match format {
+        StringFormat::Literal => {
+            // Backslashes, carriage returns and unbalanced parentheses are escaped
+            let mut escaped = vec![false; text.len()];
+            let mut parentheses = Vec::new();
+            for (index, &byte) in text.iter().enumerate() {
+                match byte {
+                    b'(' => parentheses.push(index),
+                    // Closing parentheses without an opening one are escaped
+                    b')' if parentheses.pop().is_none() => escaped[index] = true,
+                    b'\\' | b'\r' => escaped[index] = true,
+                    _ => {}
+                }
+            }
+            for index in parentheses {
+                escaped[index] = true;
+            }
+
+            buffer.push(b'(');
+            for (&byte, escaped) in text.iter().zip(escaped) {
+                if escaped {
+                    buffer.push(b'\\');
+                    buffer.push(if byte == b'\r' { b'r' } else { byte });
+                } else {
+                    buffer.push(byte);
+                }
+            }
+            buffer.push(b')');
+        }
+        StringFormat::Hexadecimal => {
+            buffer.push(b'<');
+            for byte in text {
+                buffer.extend_from_slice(format!("{:02X}", byte).as_bytes());
+            }
+            buffer.push(b'>');
+        }
+    }
+}
+
+fn write_dictionary(buffer: &mut Vec<u8>, dictionary: &Dictionary) {
+    buffer.extend_from_slice(b"<<");
+    for (key, value) in dictionary {
+        write_name(buffer, key);
+        if need_separator(value) {
+            buffer.push(b' ');
+        }
+        write_object(buffer, value);
+    }
+    buffer.extend_from_slice(b">>");
+}
+
+fn write_stream(buffer: &mut Vec<u8>, stream: &Stream) {
+    write_dictionary(buffer, &stream.dict);
+    buffer.extend_from_slice(b"stream\n");
+    buffer.extend_from_slice(&stream.content);
+    buffer.extend_from_slice(b"endstream");
+}
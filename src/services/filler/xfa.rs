@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use lopdf::{Dictionary, Document as PdfDocument, Object, ObjectId};
+use serde::Deserialize;
+use serde_json::Value;
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
+use xml::writer::{EmitterConfig, EventWriter};
+
+use crate::services::filler::compiler::PDFillerMap;
+
+const DATASETS_PACKET: &[u8] = b"datasets";
+const DATASETS_ELEMENT: &str = "datasets";
+const DATA_ELEMENT: &str = "data";
+const CHECKED_VALUE: &str = "1";
+const UNCHECKED_VALUE: &str = "0";
+
+/// XFA forms come as hybrid forms, with an AcroForm fallback of the same fields, or as dynamic
+/// forms having only the XFA template.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XfaForm {
+    Hybrid,
+    Dynamic,
+}
+
+impl XfaForm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            XfaForm::Hybrid => "hybrid",
+            XfaForm::Dynamic => "dynamic",
+        }
+    }
+}
+
+/// Handling of XFA forms when compiling: `remove` drops the XFA so viewers show the filled
+/// AcroForm, `fill` writes the same values to the XFA datasets.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XfaMode {
+    Remove,
+    Fill,
+}
+
+#[derive(Debug)]
+pub enum XfaError {
+    Pdf(lopdf::Error),
+    Xml(xml::reader::Error),
+    Write(xml::writer::Error),
+    DynamicForm,
+    NoDatasets,
+}
+
+impl Display for XfaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            XfaError::Pdf(e) => write!(f, "Error reading the XFA form: {}", e),
+            XfaError::Xml(e) => write!(f, "Error reading the XFA datasets: {}", e),
+            XfaError::Write(e) => write!(f, "Error writing the XFA datasets: {}", e),
+            XfaError::DynamicForm => write!(
+                f,
+                "The document is an XFA-only form which can't be filled as AcroForm, use the \"fill\" XFA option to fill its datasets"
+            ),
+            XfaError::NoDatasets => write!(f, "The XFA form has no datasets to fill"),
+        }
+    }
+}
+
+impl From<lopdf::Error> for XfaError {
+    fn from(e: lopdf::Error) -> Self {
+        XfaError::Pdf(e)
+    }
+}
+
+impl From<xml::reader::Error> for XfaError {
+    fn from(e: xml::reader::Error) -> Self {
+        XfaError::Xml(e)
+    }
+}
+
+impl From<xml::writer::Error> for XfaError {
+    fn from(e: xml::writer::Error) -> Self {
+        XfaError::Write(e)
+    }
+}
+
+/// Kind of XFA form of the document, dynamic forms have no AcroForm fields or ask viewers to
+/// render the XFA template.
+pub fn detect(document: &PdfDocument) -> Option<XfaForm> {
+    let catalog = document.catalog().ok()?;
+    let acro_form = get_acro_form(document, catalog)?;
+    acro_form.get(b"XFA").ok()?;
+
+    let needs_rendering = matches!(catalog.get(b"NeedsRendering"), Ok(Object::Boolean(true)));
+    let has_fields = acro_form
+        .get(b"Fields")
+        .and_then(|fields| dereference(document, fields))
+        .and_then(Object::as_array)
+        .map(|fields| !fields.is_empty())
+        .unwrap_or(false);
+
+    if needs_rendering || !has_fields {
+        Some(XfaForm::Dynamic)
+    } else {
+        Some(XfaForm::Hybrid)
+    }
+}
+
+/// Detects XFA forms in an uploaded file, files which can't be read aren't forms.
+pub fn detect_buffer(buffer: &[u8]) -> Option<XfaForm> {
+    PdfDocument::load_mem(buffer)
+        .ok()
+        .and_then(|document| detect(&document))
+}
+
+/// Handles the XFA form of a filled document, returning the warnings for the caller to report.
+pub fn apply(
+    document: &mut PdfDocument,
+    map: &PDFillerMap,
+    mode: Option<XfaMode>,
+) -> Result<Vec<String>, XfaError> {
+    let form = match detect(document) {
+        Some(form) => form,
+        None => return Ok(Vec::new()),
+    };
+
+    match (form, mode) {
+        (XfaForm::Dynamic, None) | (XfaForm::Dynamic, Some(XfaMode::Remove)) => {
+            Err(XfaError::DynamicForm)
+        }
+        (XfaForm::Hybrid, None) => Ok(vec![
+            "The document is a hybrid XFA form, viewers supporting XFA won't show the filled values unless the XFA is removed or filled with the \"xfa\" option".into(),
+        ]),
+        (XfaForm::Hybrid, Some(XfaMode::Remove)) => {
+            remove_xfa(document)?;
+
+            Ok(Vec::new())
+        }
+        (_, Some(XfaMode::Fill)) => {
+            let filled = fill_datasets(document, map)?;
+            let mut unmatched = map
+                .keys()
+                .filter(|key| !filled.contains(*key))
+                .cloned()
+                .collect::<Vec<_>>();
+            unmatched.sort();
+
+            Ok(unmatched
+                .into_iter()
+                .map(|key| format!("Field \"{}\" not found in the XFA datasets", key))
+                .collect())
+        }
+    }
+}
+
+/// Drops the XFA so viewers fall back to the AcroForm fields.
+pub fn remove_xfa(document: &mut PdfDocument) -> Result<(), XfaError> {
+    let catalog_id = document.trailer.get(b"Root")?.as_reference()?;
+    let catalog = document.get_object_mut(catalog_id)?.as_dict_mut()?;
+    catalog.remove(b"NeedsRendering");
+
+    let acro_form_id = match catalog.get_mut(b"AcroForm")? {
+        Object::Reference(id) => *id,
+        Object::Dictionary(acro_form) => {
+            acro_form.remove(b"XFA");
+
+            return Ok(());
+        }
+        _ => return Err(XfaError::Pdf(lopdf::Error::Type)),
+    };
+
+    document
+        .get_object_mut(acro_form_id)?
+        .as_dict_mut()?
+        .remove(b"XFA");
+
+    Ok(())
+}
+
+/// Writes the values to the fields of the XFA datasets, returning the filled keys. Data elements
+/// are matched by name, keys ending with an index like `Name[1]` fill only that occurrence.
+pub fn fill_datasets(
+    document: &mut PdfDocument,
+    map: &PDFillerMap,
+) -> Result<HashSet<String>, XfaError> {
+    let stream_id = get_datasets_id(document)?;
+    let stream = document.get_object_mut(stream_id)?.as_stream_mut()?;
+    let content = if stream.dict.get(b"Filter").is_ok() {
+        stream.decompressed_content()?
+    } else {
+        stream.content.clone()
+    };
+
+    let mut filled = HashSet::new();
+    let datasets = fill_xml(&content, map, &mut filled)?;
+    stream.set_plain_content(datasets);
+
+    Ok(filled)
+}
+
+fn get_acro_form<'a>(document: &'a PdfDocument, catalog: &'a Dictionary) -> Option<&'a Dictionary> {
+    catalog
+        .get(b"AcroForm")
+        .and_then(|acro_form| dereference(document, acro_form))
+        .and_then(Object::as_dict)
+        .ok()
+}
+
+fn dereference<'a>(document: &'a PdfDocument, object: &'a Object) -> lopdf::Result<&'a Object> {
+    match object {
+        Object::Reference(id) => document.get_object(*id),
+        object => Ok(object),
+    }
+}
+
+/// The XFA is either a single stream holding the whole XDP document or an array of packet names
+/// and streams.
+fn get_datasets_id(document: &PdfDocument) -> Result<ObjectId, XfaError> {
+    let acro_form =
+        get_acro_form(document, document.catalog()?).ok_or(XfaError::Pdf(lopdf::Error::Type))?;
+
+    match acro_form.get(b"XFA")? {
+        Object::Reference(id) => match document.get_object(*id)? {
+            Object::Array(packets) => get_packet_id(packets),
+            _ => Ok(*id),
+        },
+        Object::Array(packets) => get_packet_id(packets),
+        _ => Err(XfaError::NoDatasets),
+    }
+}
+
+fn get_packet_id(packets: &[Object]) -> Result<ObjectId, XfaError> {
+    packets
+        .chunks(2)
+        .find_map(|packet| match packet {
+            [Object::String(name, _), Object::Reference(id)] if name == DATASETS_PACKET => {
+                Some(*id)
+            }
+            _ => None,
+        })
+        .ok_or(XfaError::NoDatasets)
+}
+
+fn fill_xml(
+    content: &[u8],
+    map: &PDFillerMap,
+    filled: &mut HashSet<String>,
+) -> Result<Vec<u8>, XfaError> {
+    let reader = EventReader::new_with_config(
+        content,
+        ParserConfig::new()
+            .ignore_comments(false)
+            .coalesce_characters(true),
+    );
+    let mut writer = EventWriter::new_with_config(
+        Vec::new(),
+        EmitterConfig::new()
+            .write_document_declaration(false)
+            .perform_indent(false)
+            .normalize_empty_elements(false)
+            .keep_element_names_stack(false),
+    );
+
+    // Packets of an XDP array are fragments, the declaration is kept only when already there
+    let declaration = content
+        .iter()
+        .skip_while(|byte| byte.is_ascii_whitespace())
+        .take(5)
+        .eq(b"<?xml".iter());
+
+    // Element names of the open elements with the occurrences of their children names
+    let mut elements: Vec<(String, HashMap<String, usize>)> = Vec::new();
+    let mut data_depth = None;
+    // Key and events of the innermost matched element, replaced when it has no child elements
+    let mut pending: Option<(String, Vec<XmlEvent>)> = None;
+
+    for event in reader {
+        let event = event?;
+        match event {
+            XmlEvent::StartDocument { .. } if !declaration => continue,
+            XmlEvent::StartElement { ref name, .. } => {
+                if let Some((_, events)) = pending.take() {
+                    for event in events.iter() {
+                        write_event(&mut writer, event)?;
+                    }
+                }
+
+                let index = match elements.last_mut() {
+                    Some((_, children)) => {
+                        let count = children.entry(name.local_name.clone()).or_insert(0);
+                        *count += 1;
+
+                        *count - 1
+                    }
+                    None => 0,
+                };
+
+                if data_depth.is_some() {
+                    let indexed = format!("{}[{}]", name.local_name, index);
+                    if map.contains_key(&indexed) {
+                        pending = Some((indexed, Vec::new()));
+                    } else if map.contains_key(&name.local_name) {
+                        pending = Some((name.local_name.clone(), Vec::new()));
+                    }
+                } else if name.local_name == DATA_ELEMENT
+                    && elements
+                        .last()
+                        .map(|(parent, _)| parent == DATASETS_ELEMENT)
+                        .unwrap_or(false)
+                {
+                    data_depth = Some(elements.len());
+                }
+
+                elements.push((name.local_name.clone(), HashMap::new()));
+                write_event(&mut writer, &event)?;
+            }
+            XmlEvent::EndElement { .. } => {
+                if let Some((key, _)) = pending.take() {
+                    let value = get_xfa_value(&map[&key]);
+                    writer.write(xml::writer::XmlEvent::Characters(&value))?;
+                    filled.insert(key);
+                }
+
+                elements.pop();
+                if data_depth == Some(elements.len()) {
+                    data_depth = None;
+                }
+
+                write_event(&mut writer, &event)?;
+            }
+            event => match pending {
+                Some((_, ref mut events)) => events.push(event),
+                None => write_event(&mut writer, &event)?,
+            },
+        }
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn write_event(writer: &mut EventWriter<Vec<u8>>, event: &XmlEvent) -> Result<(), XfaError> {
+    if let Some(event) = event.as_writer_event() {
+        writer.write(event)?;
+    }
+
+    Ok(())
+}
+
+/// XFA check boxes use `1` and `0` as their states and multiple selections are separated by new
+/// lines.
+fn get_xfa_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Bool(true) => CHECKED_VALUE.into(),
+        Value::Bool(false) => UNCHECKED_VALUE.into(),
+        Value::Array(values) => values
+            .iter()
+            .map(get_xfa_value)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
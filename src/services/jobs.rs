@@ -0,0 +1,318 @@
+use actix_web::rt::{self, Arbiter};
+use actix_web::{get, post, web, HttpResponse, Responder};
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use chrono::Utc;
+use log::{error, info};
+use serde_json::Value;
+
+use crate::config::JobsConfig;
+use crate::data::Data;
+use crate::mongo::models::document::Document;
+use crate::mongo::models::job::{Job, JobStatus};
+use crate::services::{
+    self,
+    filler::{self, compiler, CompileQuery},
+    webhook::{self, Callback},
+    WsError,
+};
+
+const DEFAULT_WORKERS: usize = 2;
+const DEFAULT_QUEUE_SIZE: usize = 100;
+
+/// Work of a queued job, checked when the job was submitted.
+struct CompileTask {
+    job: Job,
+    map: compiler::PDFillerMap,
+    options: compiler::ExportOptions,
+    documents: Vec<Document>,
+    accept: String,
+    callback: Option<Callback>,
+}
+
+/// Bounded queue of the compile jobs, run by workers on their own threads.
+#[derive(Clone)]
+pub struct JobQueue {
+    sender: Sender<CompileTask>,
+}
+
+impl JobQueue {
+    pub fn start(data: Data, config: Option<&JobsConfig>) -> Self {
+        let workers = config
+            .and_then(|config| config.workers)
+            .unwrap_or(DEFAULT_WORKERS)
+            .max(1);
+        let queue_size = config
+            .and_then(|config| config.queue_size)
+            .unwrap_or(DEFAULT_QUEUE_SIZE)
+            .max(1);
+
+        let (sender, receiver) = channel::bounded(queue_size);
+        for _ in 0..workers {
+            let data = data.clone();
+            let receiver = receiver.clone();
+
+            // Exports aren't Send, every worker runs them on its own arbiter
+            Arbiter::new().spawn_fn(move || {
+                rt::spawn(run_worker(data, receiver));
+            });
+        }
+
+        info!(
+            "Started {} compile job workers with a queue of {} jobs",
+            workers, queue_size
+        );
+
+        Self { sender }
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(post_compile_job);
+    cfg.service(get_job);
+    cfg.service(get_job_result);
+}
+
+#[post("/jobs/compile/{token}")]
+pub async fn post_compile_job(
+    data: web::Data<Data>,
+    queue: web::Data<JobQueue>,
+    token: web::Path<String>,
+    query: web::Query<CompileQuery>,
+    request: web::HttpRequest,
+    bytes: web::Bytes,
+) -> impl Responder {
+    let values =
+        match filler::get_request_values(&data, token.as_str(), &request, &query, &bytes).await {
+            Ok(values) => values,
+            Err(response) => return response,
+        };
+    if values.get("dry_run").and_then(Value::as_bool) == Some(true) {
+        return HttpResponse::BadRequest().json(WsError {
+            error: "Dry runs aren't queued, use the compile endpoint.".into(),
+        });
+    }
+
+    let options = match filler::get_export_options(&data, token.as_str(), &values).await {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    let map = match filler::get_data_map(&values) {
+        Ok(map) => map,
+        Err(response) => return response,
+    };
+    let accept = match services::get_accepted_header(&request, query.format.as_deref()) {
+        Some(accept) => accept,
+        None => {
+            return HttpResponse::NotAcceptable().json(WsError {
+                error: "Only PDF, Streams or PNG and JPEG images are accepted".into(),
+            });
+        }
+    };
+    let documents = match filler::get_template_documents(&data, token.as_str(), &values).await {
+        Ok(documents) => documents,
+        Err(response) => return response,
+    };
+
+    let callback = match webhook::get_callback(&data, token.as_str(), &values).await {
+        Ok(callback) => callback,
+        Err(response) => return response,
+    };
+
+    let mut job = Job::new(token.to_string(), options.get_filename(token.as_str()));
+    job.callback = callback.as_ref().map(|callback| callback.url.clone());
+    if let Err(e) = data.create_job(job.clone()).await {
+        return HttpResponse::InternalServerError().json(WsError {
+            error: format!("An error occurred: {:#?}", e),
+        });
+    }
+
+    // The job is stored first, workers only update existing jobs
+    match queue.sender.try_send(CompileTask {
+        job: job.clone(),
+        map,
+        options,
+        documents,
+        accept,
+        callback,
+    }) {
+        Ok(_) => HttpResponse::Accepted().json(job),
+        Err(TrySendError::Full(mut task)) => {
+            fail_job(&data, &mut task.job, "Too many jobs queued".into()).await;
+
+            HttpResponse::ServiceUnavailable().json(WsError {
+                error: "Too many jobs queued, retry later.".into(),
+            })
+        }
+        Err(TrySendError::Closed(mut task)) => {
+            fail_job(&data, &mut task.job, "The job queue is closed".into()).await;
+
+            HttpResponse::ServiceUnavailable().json(WsError {
+                error: "The job queue is closed.".into(),
+            })
+        }
+    }
+}
+
+#[get("/jobs/{token}/{id}")]
+pub async fn get_job(data: web::Data<Data>, path: web::Path<(String, String)>) -> impl Responder {
+    let (token, id) = path.into_inner();
+
+    match get_token_job(&data, &token, &id).await {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().json(WsError {
+            error: "Job not found!".into(),
+        }),
+    }
+}
+
+#[get("/jobs/{token}/{id}/result")]
+pub async fn get_job_result(
+    data: web::Data<Data>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (token, id) = path.into_inner();
+    let job = match get_token_job(&data, &token, &id).await {
+        Some(job) => job,
+        None => {
+            return HttpResponse::NotFound().json(WsError {
+                error: "Job not found!".into(),
+            });
+        }
+    };
+
+    match (job.status, job.file, job.content_type) {
+        (JobStatus::Done, Some(file), Some(content_type)) => match data.file.load(&file).await {
+            Ok(buffer) => {
+                let content = compiler::ExportedContent::from(buffer);
+
+                services::export_content(content_type, job.filename, Ok(content))
+            }
+            Err(e) => {
+                sentry::capture_error(&e);
+
+                HttpResponse::InternalServerError().json(WsError {
+                    error: format!("Error loading the job result: {}", e),
+                })
+            }
+        },
+        (JobStatus::Failed, _, _) => HttpResponse::Conflict().json(WsError {
+            error: format!(
+                "The job failed: {}",
+                job.error.as_deref().unwrap_or("unknown error")
+            ),
+        }),
+        (status, _, _) => HttpResponse::Conflict().json(WsError {
+            error: format!("The job is {}, retry later.", status.as_str()),
+        }),
+    }
+}
+
+/// Jobs of other tokens are reported as missing.
+async fn get_token_job(data: &Data, token: &str, id: &str) -> Option<Job> {
+    data.get_job(id).await.filter(|job| job.token == token)
+}
+
+async fn run_worker(data: Data, receiver: Receiver<CompileTask>) {
+    while let Ok(mut task) = receiver.recv().await {
+        let mut job = task.job.clone();
+        let callback = task.callback.take();
+        job.status = JobStatus::Running;
+        update_job(&data, &mut job).await;
+
+        match run_task(&data, task, &mut job).await {
+            Ok(_) => {
+                job.status = JobStatus::Done;
+                job.progress = 100;
+                update_job(&data, &mut job).await;
+            }
+            Err(message) => fail_job(&data, &mut job, message).await,
+        }
+
+        // Retries wait in the background, the worker moves to the next job
+        if let Some(callback) = callback {
+            rt::spawn(webhook::deliver(data.clone(), job, callback));
+        }
+    }
+}
+
+/// Compiles the documents one by one for the progress, the export counts as the last step.
+async fn run_task(data: &Data, task: CompileTask, job: &mut Job) -> Result<(), String> {
+    let compilation = filler::new_compilation(data, &job.token, &task.map, &task.documents);
+    let steps = task.documents.len() + 1;
+    for (index, document) in task.documents.iter().enumerate() {
+        match compiler::compile_document(
+            data.file.clone(),
+            &task.map,
+            document,
+            task.options.xfa,
+            compilation.id(),
+        )
+        .await
+        {
+            Ok(_) => {
+                job.progress = ((index + 1) * 100 / steps) as u8;
+                update_job(data, job).await;
+            }
+            Err(compiler::HandlerCompilerError::FillingError(e)) => {
+                return Err(format!("Error during document filling: {:#?}", e));
+            }
+            Err(compiler::HandlerCompilerError::Error(message)) => return Err(message),
+        }
+    }
+
+    if let Err(e) = data.create_compilation(compilation.clone()).await {
+        return Err(format!("Error storing the compilation: {:#?}", e));
+    }
+    job.compilation = Some(compilation.id().to_owned());
+
+    let content = match filler::export_documents(
+        data,
+        task.documents,
+        task.options,
+        &task.accept,
+        compilation.id(),
+    )
+    .await
+    {
+        Ok(content) => content,
+        Err(compiler::ExportCompilerError::GenericError(message)) => return Err(message),
+    };
+    let content_type = content
+        .content_type
+        .as_ref()
+        .map(|content_type| content_type.to_string())
+        .unwrap_or(task.accept);
+    let buffer = content.body.into_bytes().await?;
+
+    let file = data.file.generate_filepath(&format!(
+        "{}.{}",
+        job.id(),
+        services::get_extension(&content_type)
+    ));
+    data.file.save(&file, buffer).await.map_err(|e| {
+        sentry::capture_error(&e);
+
+        format!("Error saving the job result: {}", e)
+    })?;
+
+    job.file = Some(file);
+    job.content_type = Some(content_type);
+
+    Ok(())
+}
+
+async fn fail_job(data: &Data, job: &mut Job, message: String) {
+    error!("Compile job {} failed: {}", job.id(), message);
+
+    job.status = JobStatus::Failed;
+    job.error = Some(message);
+    update_job(data, job).await;
+}
+
+async fn update_job(data: &Data, job: &mut Job) {
+    job.updated = Utc::now();
+
+    if let Err(e) = data.update_job(job.clone()).await {
+        error!("Error updating the compile job {}: {:#?}", job.id(), e);
+    }
+}
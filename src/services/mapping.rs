@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use actix_web::{get, post, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+use crate::data::Data;
+use crate::mongo::models::mapping::MappingProfile;
+use crate::services::WsError;
+
+/// Columns of the CSV and XLSX data renamed to field names, `lists` columns are split on the
+/// `separator` into several values.
+#[derive(Deserialize)]
+pub struct MappingRequest {
+    name: String,
+    #[serde(default)]
+    columns: HashMap<String, String>,
+    #[serde(default)]
+    lists: Vec<String>,
+    separator: Option<String>,
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(post_mapping);
+    cfg.service(get_mappings);
+}
+
+#[post("/mapping/{token}")]
+pub async fn post_mapping(
+    data: web::Data<Data>,
+    token: web::Path<String>,
+    bytes: web::Bytes,
+) -> impl Responder {
+    let request = match serde_json::from_slice::<MappingRequest>(&bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: format!("Not a valid mapping profile: {:#?}", e),
+            });
+        }
+    };
+    if request.name.trim().is_empty() {
+        return HttpResponse::BadRequest().json(WsError {
+            error: "The mapping profile name can't be empty.".into(),
+        });
+    }
+    if request.separator.as_deref() == Some("") {
+        return HttpResponse::BadRequest().json(WsError {
+            error: "The list separator can't be empty.".into(),
+        });
+    }
+
+    // Headers are trimmed when the data is read
+    let columns = request
+        .columns
+        .into_iter()
+        .map(|(column, field)| (column.trim().to_string(), field))
+        .collect();
+    let lists = request
+        .lists
+        .into_iter()
+        .map(|column| column.trim().to_string())
+        .collect();
+
+    let profile = MappingProfile::new(
+        token.to_string(),
+        request.name,
+        columns,
+        lists,
+        request.separator,
+    );
+    match data.create_mapping_profile(profile.clone()).await {
+        Ok(_) => HttpResponse::Created().json(profile),
+        Err(e) => HttpResponse::InternalServerError().json(WsError {
+            error: format!("An error occurred: {:#?}", e),
+        }),
+    }
+}
+
+#[get("/mapping/{token}")]
+pub async fn get_mappings(data: web::Data<Data>, token: web::Path<String>) -> impl Responder {
+    match data.get_mapping_profiles(token.as_str()).await {
+        Some(profiles) if !profiles.is_empty() => HttpResponse::Ok().json(profiles),
+        _ => HttpResponse::NotFound().json(WsError {
+            error: "No mapping profiles found for this token!".into(),
+        }),
+    }
+}
@@ -1,13 +1,34 @@
+mod certificate;
+mod compilation;
 mod document;
+mod extraction;
 mod filler;
+pub mod jobs;
+mod mapping;
+mod pagination;
+mod storage;
+mod verification;
+mod webhook;
 
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter};
+
+use actix_multipart::{Field, MultipartError};
 use actix_web::dev::BodyEncoding;
 use actix_web::http::{header::ACCEPT, ContentEncoding};
 use actix_web::{web, HttpResponse};
+use futures_lite::stream::StreamExt;
 use serde::Serialize;
 
+use crate::config::BatchConfig;
 use crate::services::filler::compiler;
 
+pub use crate::services::filler::render::check_renderer;
+
+const CONFORMANCE_REPORT_HEADER: &str = "x-conformance-report";
+/// Bytes of a text field of a multipart form, like a password or an URI
+const MAX_FIELD_SIZE: usize = 64 * 1024;
+
 #[derive(Serialize)]
 struct WsMessage {
     message: String,
@@ -18,17 +39,38 @@ struct WsError {
     error: String,
 }
 
-pub fn config(cfg: &mut web::ServiceConfig) {
+pub fn config(cfg: &mut web::ServiceConfig, batch: Option<&BatchConfig>) {
+    certificate::config(cfg);
+    compilation::config(cfg);
     document::config(cfg);
-    filler::config(cfg);
+    extraction::config(cfg);
+    filler::config(cfg, batch);
+    jobs::config(cfg);
+    mapping::config(cfg);
+    verification::config(cfg);
+    webhook::config(cfg);
 }
 
-pub fn get_accepted_header(request: &web::HttpRequest) -> Option<String> {
+/// The exported content type, the `format` query parameter takes precedence over the header.
+pub fn get_accepted_header(request: &web::HttpRequest, format: Option<&str>) -> Option<String> {
+    if let Some(format) = format {
+        return match format.to_lowercase().as_str() {
+            "pdf" => Some(mime::APPLICATION_PDF),
+            "zip" => Some(mime::APPLICATION_OCTET_STREAM),
+            "png" => Some(mime::IMAGE_PNG),
+            "jpg" | "jpeg" => Some(mime::IMAGE_JPEG),
+            _ => None,
+        }
+        .map(|content_type| content_type.to_string());
+    }
+
     if let Some(accept) = request.headers().get(ACCEPT) {
         let accept = accept.to_str().unwrap_or("").to_lowercase();
 
         if accept.as_str() == mime::APPLICATION_PDF
             || accept.as_str() == mime::APPLICATION_OCTET_STREAM
+            || accept.as_str() == mime::IMAGE_PNG
+            || accept.as_str() == mime::IMAGE_JPEG
         {
             Some(accept)
         } else {
@@ -39,29 +81,167 @@ pub fn get_accepted_header(request: &web::HttpRequest) -> Option<String> {
     }
 }
 
+/// Extension of the exported file for its content type.
+fn get_extension<S: AsRef<str>>(content_type: S) -> &'static str {
+    let content_type = content_type.as_ref();
+    if content_type == mime::APPLICATION_PDF {
+        "pdf"
+    } else if content_type == mime::IMAGE_PNG {
+        "png"
+    } else if content_type == mime::IMAGE_JPEG {
+        "jpg"
+    } else {
+        "zip"
+    }
+}
+
 pub fn export_content<S: AsRef<str>>(
     accept: S,
-    export_result: compiler::ExportCompilerResult<Vec<u8>>,
+    filename: String,
+    export_result: compiler::ExportCompilerResult<compiler::ExportedContent>,
 ) -> HttpResponse {
     match export_result {
-        Ok(bytes) => HttpResponse::Ok()
-            .encoding(ContentEncoding::Identity)
-            .content_type(accept.as_ref())
-            .append_header(("accept-ranges", "bytes"))
-            .append_header((
-                "content-disposition",
-                format!(
-                    "attachment; filename=\"pdf.{}\"",
-                    if accept.as_ref() != mime::APPLICATION_PDF {
-                        "zip"
-                    } else {
-                        "pdf"
-                    }
-                ),
-            ))
-            .body(bytes),
+        Ok(content) => {
+            let content_type = match content.content_type {
+                Some(ref content_type) => content_type.as_ref(),
+                None => accept.as_ref(),
+            };
+            let filename = format!("{}.{}", filename, get_extension(content_type));
+
+            let mut response = HttpResponse::Ok();
+            if let Some(ref report) = content.report {
+                if let Ok(report) = serde_json::to_string(report) {
+                    // Header values must be visible ASCII, font names may contain anything
+                    response.append_header((
+                        CONFORMANCE_REPORT_HEADER,
+                        report
+                            .chars()
+                            .map(|c| {
+                                if c.is_ascii() && !c.is_ascii_control() {
+                                    c
+                                } else {
+                                    '?'
+                                }
+                            })
+                            .collect::<String>(),
+                    ));
+                }
+            }
+
+            response
+                .encoding(ContentEncoding::Identity)
+                .content_type(content_type)
+                .append_header(("content-disposition", get_content_disposition(&filename)));
+
+            match content.body {
+                compiler::ExportBody::Buffer(bytes) => response
+                    .append_header(("accept-ranges", "bytes"))
+                    .body(bytes),
+                compiler::ExportBody::Stream(receiver) => {
+                    response.streaming(receiver.map(|chunk| {
+                        chunk
+                            .map(web::Bytes::from)
+                            .map_err(actix_web::error::ErrorInternalServerError)
+                    }))
+                }
+            }
+        }
         Err(compiler::ExportCompilerError::GenericError(message)) => {
             HttpResponse::InternalServerError().json(WsError { error: message })
         }
     }
 }
+
+/// Quoted ASCII file name for older clients, with the UTF-8 name encoded as in RFC 5987.
+fn get_content_disposition(filename: &str) -> String {
+    let fallback = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let encoded = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect::<String>();
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Multipart(MultipartError),
+    TooLarge { max: usize },
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Multipart(e) => write!(f, "Multipart error: {}", e),
+            ReadError::TooLarge { max } => {
+                write!(f, "The field is larger than the {} bytes accepted", max)
+            }
+        }
+    }
+}
+
+impl StdError for ReadError {}
+
+/// Reads a text field, which can't grow past `MAX_FIELD_SIZE`.
+pub async fn read_field(field: &mut Field) -> Result<Vec<u8>, ReadError> {
+    read_chuncked_buffer(field, Some(MAX_FIELD_SIZE)).await
+}
+
+/// Fields too large are the client's fault, multipart errors are reported as ours.
+pub fn read_error_response(name: &str, e: ReadError) -> HttpResponse {
+    match e {
+        ReadError::TooLarge { max } => HttpResponse::PayloadTooLarge().json(WsError {
+            error: format!("The {} is larger than the {} bytes accepted.", name, max),
+        }),
+        ReadError::Multipart(_) => {
+            sentry::capture_error(&e);
+
+            HttpResponse::InternalServerError().json(WsError {
+                error: format!("An error occurred reading the {}: {:#?}", name, e),
+            })
+        }
+    }
+}
+
+/// Reads a multipart field, giving up as soon as it grows past `max_size`.
+pub async fn read_chuncked_buffer(
+    field: &mut Field,
+    max_size: Option<usize>,
+) -> Result<Vec<u8>, ReadError> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = field.next().await {
+        match chunk {
+            Ok(data) => {
+                if let Some(max) = max_size {
+                    if buf.len() + data.len() > max {
+                        return Err(ReadError::TooLarge { max });
+                    }
+                }
+                buf.extend(data);
+            }
+            Err(e) => {
+                return Err(ReadError::Multipart(e));
+            }
+        }
+    }
+
+    Ok(buf)
+}
@@ -0,0 +1,251 @@
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use bson::oid::ObjectId;
+use bson::{doc, Bson};
+use chrono::{DateTime, TimeZone, Utc};
+use mongodb::bson::Document as MongoDocument;
+use openssl::base64;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::mongo::models::document::Document;
+use crate::mongo::FindQuery;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+/// Listing of the documents, a page at a time. `cursor` comes from the `x-next-cursor` header of
+/// the previous page, `sort` is `date` or `token` and `order` is `asc` or `desc`. `from` and `to`
+/// bound the upload date as RFC 3339, `tag` lists tags separated by commas and `label` labels as
+/// `key:value` or `key`, documents having all of them are listed.
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    sort: Option<String>,
+    order: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    token_prefix: Option<String>,
+    tag: Option<String>,
+    label: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortField {
+    Date,
+    Token,
+}
+
+impl SortField {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortField::Date => "date",
+            SortField::Token => "token",
+        }
+    }
+
+    fn from_str(field: &str) -> Option<Self> {
+        match field {
+            "date" => Some(SortField::Date),
+            "token" => Some(SortField::Token),
+            _ => None,
+        }
+    }
+
+    fn value(&self, document: &Document) -> Value {
+        match self {
+            SortField::Date => Value::from(document.date.timestamp_millis()),
+            SortField::Token => Value::from(document.token.clone()),
+        }
+    }
+
+    fn to_bson(self, value: &Value) -> Option<Bson> {
+        match self {
+            SortField::Date => value
+                .as_i64()
+                .map(|millis| Bson::from(Utc.timestamp_millis(millis))),
+            SortField::Token => value.as_str().map(Bson::from),
+        }
+    }
+}
+
+/// Position after the last listed document, the sorted value with the id breaking ties.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    value: Value,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+
+        base64::encode_block(&json)
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_")
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let mut encoded = cursor.replace('-', "+").replace('_', "/");
+        while !encoded.len().is_multiple_of(4) {
+            encoded.push('=');
+        }
+
+        base64::decode_block(&encoded)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+    }
+}
+
+/// Sort and size of the page read.
+pub struct Page {
+    sort: SortField,
+    limit: usize,
+}
+
+/// The query reading a page of the documents, of the token when given. A document more than the
+/// limit is read to know whether another page follows.
+pub fn get_documents_query(
+    query: &ListQuery,
+    token: Option<&str>,
+) -> Result<(FindQuery, Page), String> {
+    let sort = match query.sort.as_deref() {
+        Some(field) => SortField::from_str(field)
+            .ok_or_else(|| format!("\"{}\" is not a sortable field, use date or token", field))?,
+        None => SortField::Date,
+    };
+    let direction = match query.order.as_deref() {
+        Some("asc") | None => 1,
+        Some("desc") => -1,
+        Some(order) => {
+            return Err(format!(
+                "\"{}\" is not a valid order, use asc or desc",
+                order
+            ));
+        }
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(format!("The limit must be between 1 and {}", MAX_LIMIT));
+    }
+
+    let mut filters = Vec::new();
+    if let Some(token) = token {
+        filters.push(doc! { "token": token });
+    }
+    if let Some(ref prefix) = query.token_prefix {
+        filters.push(doc! {
+            "token": { "$regex": format!("^{}", regex::escape(prefix)) },
+        });
+    }
+    if let Some(ref from) = query.from {
+        filters.push(doc! { "date": { "$gte": parse_date(from)? } });
+    }
+    if let Some(ref to) = query.to {
+        filters.push(doc! { "date": { "$lte": parse_date(to)? } });
+    }
+
+    let tags = split_list(query.tag.as_deref());
+    if !tags.is_empty() {
+        filters.push(doc! { "metadata.tags": { "$all": tags } });
+    }
+    for label in split_list(query.label.as_deref()) {
+        let label = match label.split_once(':') {
+            Some((key, value)) => doc! { "key": key.trim(), "value": value.trim() },
+            None => doc! { "key": label },
+        };
+        filters.push(doc! { "metadata.labels": { "$elemMatch": label } });
+    }
+
+    if let Some(ref cursor) = query.cursor {
+        filters.push(get_cursor_filter(cursor, sort, direction)?);
+    }
+
+    let filter = match filters.len() {
+        0 => None,
+        1 => filters.pop(),
+        _ => Some(doc! { "$and": filters }),
+    };
+
+    Ok((
+        FindQuery {
+            filter,
+            sort: Some(doc! { sort.as_str(): direction, "_id": direction }),
+            limit: Some(limit + 1),
+            ..FindQuery::default()
+        },
+        Page {
+            sort,
+            limit: limit as usize,
+        },
+    ))
+}
+
+/// The documents of the page, with the cursor of the next one in the `x-next-cursor` header.
+pub fn page_response(mut documents: Vec<Document>, page: &Page) -> HttpResponse {
+    let next_cursor = if documents.len() > page.limit {
+        documents.truncate(page.limit);
+
+        documents.last().and_then(|document| {
+            document.id().map(|id| Cursor {
+                sort: page.sort.as_str().to_string(),
+                value: page.sort.value(document),
+                id: id.to_owned(),
+            })
+        })
+    } else {
+        None
+    };
+
+    let mut response = HttpResponse::Ok().json(documents);
+    if let Some(cursor) = next_cursor {
+        if let Ok(cursor) = header::HeaderValue::from_str(&cursor.encode()) {
+            response
+                .headers_mut()
+                .insert(header::HeaderName::from_static(NEXT_CURSOR_HEADER), cursor);
+        }
+    }
+
+    response
+}
+
+fn get_cursor_filter(
+    cursor: &str,
+    sort: SortField,
+    direction: i32,
+) -> Result<MongoDocument, String> {
+    let cursor = Cursor::decode(cursor)
+        .filter(|cursor| cursor.sort == sort.as_str())
+        .ok_or_else(|| "Not a valid cursor for this sort".to_string())?;
+    let value = sort
+        .to_bson(&cursor.value)
+        .ok_or_else(|| "Not a valid cursor for this sort".to_string())?;
+    let id = ObjectId::with_string(&cursor.id).map_err(|_| "Not a valid cursor".to_string())?;
+
+    let operator = if direction > 0 { "$gt" } else { "$lt" };
+
+    Ok(doc! {
+        "$or": [
+            { sort.as_str(): { operator: value.clone() } },
+            { sort.as_str(): value, "_id": { operator: id } },
+        ],
+    })
+}
+
+fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| format!("\"{}\" is not a valid RFC 3339 date", date))
+}
+
+fn split_list(list: Option<&str>) -> Vec<&str> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect()
+}
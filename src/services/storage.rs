@@ -0,0 +1,79 @@
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter};
+
+use crate::data::Data;
+use crate::file::FileError;
+use crate::mongo::Error;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Data(Error),
+    File(FileError),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Data(e) => write!(f, "Error storing the blob: {}", e),
+            StorageError::File(e) => write!(f, "Error storing the file: {}", e),
+        }
+    }
+}
+
+impl StdError for StorageError {}
+
+impl From<Error> for StorageError {
+    fn from(e: Error) -> Self {
+        StorageError::Data(e)
+    }
+}
+
+impl From<FileError> for StorageError {
+    fn from(e: FileError) -> Self {
+        StorageError::File(e)
+    }
+}
+
+/// Stores the template content once, uploads of the same content reference the same file.
+pub async fn store_template(
+    data: &Data,
+    sha256: &str,
+    buffer: Vec<u8>,
+) -> Result<String, StorageError> {
+    // Saved before the blob points at it, the copy is dropped when the content is already stored
+    let file = data.file.generate_blob_filepath(sha256);
+    data.file.save(&file, buffer).await?;
+
+    match data.reference_blob(sha256, &file).await {
+        Ok(Some(blob)) if blob.file != file => {
+            data.file.delete(&file).await?;
+
+            Ok(blob.file)
+        }
+        Ok(_) => Ok(file),
+        Err(e) => {
+            let _ = data.file.delete(&file).await;
+
+            Err(e.into())
+        }
+    }
+}
+
+/// Releases the reference of a document to its file, deleting it with the last one.
+pub async fn release_template(data: &Data, file: &str) -> Result<(), StorageError> {
+    let blob = match data.get_blob_by_file(file).await {
+        Some(blob) => blob,
+        None => {
+            // Uploaded before deduplication, every document has its own file
+            return Ok(data.file.delete(file).await?);
+        }
+    };
+
+    if let Some(blob) = data.release_blob(blob.id()).await? {
+        if blob.references <= 0 && data.delete_unreferenced_blob(blob.id()).await? {
+            data.file.delete(&blob.file).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use actix_multipart::Multipart;
+use actix_web::{post, web, HttpResponse, Responder};
+use futures_lite::stream::StreamExt;
+
+use crate::data::Data;
+use crate::services::{
+    self,
+    filler::{security::SecurityError, verification},
+    WsError,
+};
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(post_verify);
+}
+
+#[post("/verify")]
+pub async fn post_verify(data: web::Data<Data>, mut payload: Multipart) -> impl Responder {
+    let max_size = data.upload.as_ref().and_then(|upload| upload.max_size);
+    let mut upload = None;
+    let mut password = None;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        if let Some(ref content_type) = field.content_disposition() {
+            let name = content_type.get_name().map(|name| name.to_owned());
+
+            match name.as_deref() {
+                Some("file") => match services::read_chuncked_buffer(&mut field, max_size).await {
+                    Ok(buf) => {
+                        upload = Some(buf);
+                    }
+                    Err(e) => return services::read_error_response("file", e),
+                },
+                Some("password") => match services::read_field(&mut field).await {
+                    Ok(buf) => match String::from_utf8(buf) {
+                        Ok(value) => {
+                            password = Some(value);
+                        }
+                        Err(e) => {
+                            return HttpResponse::BadRequest().json(WsError {
+                                error: format!("Not a valid password: {:#?}", e),
+                            });
+                        }
+                    },
+                    Err(e) => return services::read_error_response("password", e),
+                },
+                Some(_) => {}
+                None => {}
+            }
+        }
+    }
+
+    let buf = match upload {
+        Some(buf) => buf,
+        None => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: "File missing.".into(),
+            });
+        }
+    };
+
+    let store = match verification::get_trust_store(data.signature.as_ref()) {
+        Ok(store) => store,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(WsError {
+                error: format!("{}.", e),
+            });
+        }
+    };
+
+    match verification::verify_document(&buf, password.as_deref(), &store) {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(
+            e @ verification::VerificationError::Security(SecurityError::PasswordRequired)
+            | e @ verification::VerificationError::Security(SecurityError::InvalidPassword),
+        ) => HttpResponse::BadRequest().json(WsError {
+            error: format!("{}.", e),
+        }),
+        Err(e) => HttpResponse::UnprocessableEntity().json(WsError {
+            error: format!("{}.", e),
+        }),
+    }
+}
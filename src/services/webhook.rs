@@ -0,0 +1,364 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use actix_web::rt;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use async_std::net::ToSocketAddrs;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::client;
+use crate::data::Data;
+use crate::mongo::models::dead_letter::DeadLetter;
+use crate::mongo::models::job::{Job, JobStatus};
+use crate::mongo::models::webhook::Webhook;
+use crate::services::WsError;
+
+const SIGNATURE_HEADER: &str = "X-PDFiller-Signature";
+const EVENT_HEADER: &str = "X-PDFiller-Event";
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_RETRY_DELAY: u64 = 2;
+const MAX_RETRY_DELAY: u64 = 3600;
+
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    url: String,
+    secret: String,
+}
+
+/// Callback of a single request, signed with its own secret or the one of the token webhook and
+/// the configuration otherwise.
+#[derive(Deserialize)]
+pub struct CallbackOptions {
+    url: String,
+    secret: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Callback {
+    pub url: String,
+    secret: String,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    job: &'a Job,
+    date: DateTime<Utc>,
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(post_webhook);
+    cfg.service(get_webhook);
+}
+
+#[post("/webhook/{token}")]
+pub async fn post_webhook(
+    data: web::Data<Data>,
+    token: web::Path<String>,
+    bytes: web::Bytes,
+) -> impl Responder {
+    let request = match serde_json::from_slice::<WebhookRequest>(&bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(WsError {
+                error: format!("Not a valid webhook: {:#?}", e),
+            });
+        }
+    };
+    if let Err(message) = validate_url(&data, &request.url).await {
+        return HttpResponse::BadRequest().json(WsError { error: message });
+    }
+    if request.secret.is_empty() {
+        return HttpResponse::BadRequest().json(WsError {
+            error: "The webhook secret can't be empty.".into(),
+        });
+    }
+
+    let webhook = Webhook::new(token.to_string(), request.url, request.secret);
+    match data.create_webhook(webhook.clone()).await {
+        Ok(_) => HttpResponse::Created().json(webhook),
+        Err(e) => HttpResponse::InternalServerError().json(WsError {
+            error: format!("An error occurred: {:#?}", e),
+        }),
+    }
+}
+
+#[get("/webhook/{token}")]
+pub async fn get_webhook(data: web::Data<Data>, token: web::Path<String>) -> impl Responder {
+    match data.get_webhook_by_token(token.as_str()).await {
+        Some(webhook) => HttpResponse::Ok().json(webhook),
+        None => HttpResponse::NotFound().json(WsError {
+            error: "No webhook found for this token!".into(),
+        }),
+    }
+}
+
+/// The `callback` of the request, or the webhook registered for the token.
+pub async fn get_callback(
+    data: &Data,
+    token: &str,
+    values: &Value,
+) -> Result<Option<Callback>, HttpResponse> {
+    let options = match values.get("callback") {
+        Some(value) => match CallbackOptions::deserialize(value) {
+            Ok(options) => options,
+            Err(e) => {
+                return Err(HttpResponse::BadRequest().json(WsError {
+                    error: format!("Not valid callback options: {:#?}", e),
+                }));
+            }
+        },
+        None => {
+            return Ok(data
+                .get_webhook_by_token(token)
+                .await
+                .map(|webhook| Callback {
+                    url: webhook.url,
+                    secret: webhook.secret,
+                }));
+        }
+    };
+    validate_url(data, &options.url)
+        .await
+        .map_err(|message| HttpResponse::BadRequest().json(WsError { error: message }))?;
+
+    let secret = match options.secret.filter(|secret| !secret.is_empty()) {
+        Some(secret) => Some(secret),
+        None => match data.get_webhook_by_token(token).await {
+            Some(webhook) => Some(webhook.secret),
+            None => data
+                .webhooks
+                .as_ref()
+                .and_then(|config| config.secret.clone()),
+        },
+    };
+
+    match secret {
+        Some(secret) => Ok(Some(Callback {
+            url: options.url,
+            secret,
+        })),
+        None => Err(HttpResponse::BadRequest().json(WsError {
+            error: "No secret to sign the callback, set it in the request or register a webhook."
+                .into(),
+        })),
+    }
+}
+
+/// Posts the ended job to the callback, retrying with exponential backoff and storing a dead
+/// letter when every attempt fails.
+pub async fn deliver(data: Data, job: Job, callback: Callback) {
+    let event = match job.status {
+        JobStatus::Done => "job.done",
+        _ => "job.failed",
+    };
+    let payload = WebhookPayload {
+        event,
+        job: &job,
+        date: Utc::now(),
+    };
+
+    // The signed bytes are the ones posted
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(e) => {
+            error!(
+                "Error serializing the callback of job {}: {:#?}",
+                job.id(),
+                e
+            );
+
+            return;
+        }
+    };
+    let signature = match sign(&callback.secret, &body) {
+        Ok(signature) => signature,
+        Err(e) => {
+            error!("Error signing the callback of job {}: {:#?}", job.id(), e);
+
+            return;
+        }
+    };
+    let headers = [
+        (SIGNATURE_HEADER, format!("sha256={}", signature)),
+        (EVENT_HEADER, event.to_string()),
+    ];
+
+    let config = data.webhooks.as_ref();
+    let max_attempts = config
+        .and_then(|config| config.max_attempts)
+        .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+        .max(1);
+    let mut delay = config
+        .and_then(|config| config.retry_delay)
+        .unwrap_or(DEFAULT_RETRY_DELAY);
+
+    let mut last_error = String::new();
+    for attempt in 1..=max_attempts {
+        match client::post(
+            &callback.url,
+            mime::APPLICATION_JSON.as_ref(),
+            body.clone(),
+            &headers,
+        )
+        .await
+        {
+            Ok(_) => return,
+            Err(e) => {
+                warn!(
+                    "Callback of job {} to {} failed, attempt {} of {}: {}",
+                    job.id(),
+                    callback.url,
+                    attempt,
+                    max_attempts,
+                    e
+                );
+
+                last_error = e;
+            }
+        }
+
+        if attempt < max_attempts {
+            rt::time::sleep(Duration::from_secs(delay)).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    let dead_letter = DeadLetter::new(
+        job.id().to_owned(),
+        callback.url,
+        String::from_utf8_lossy(&body).into_owned(),
+        max_attempts,
+        last_error,
+    );
+    if let Err(e) = data.create_dead_letter(dead_letter).await {
+        error!(
+            "Error storing the undelivered callback of job {}: {:#?}",
+            job.id(),
+            e
+        );
+    }
+}
+
+/// Callbacks are posted from inside the network, they can't target loopback or private
+/// addresses unless the configuration allows it.
+async fn validate_url(data: &Data, url: &str) -> Result<(), String> {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => parsed,
+        _ => return Err(format!("\"{}\" is not a valid HTTP URL", url)),
+    };
+    let allow_private = data
+        .webhooks
+        .as_ref()
+        .and_then(|config| config.allow_private_hosts)
+        .unwrap_or(false);
+    if allow_private {
+        return Ok(());
+    }
+
+    // IPv6 hosts keep their brackets
+    let host = match parsed.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return Err(format!("\"{}\" is not a valid HTTP URL", url)),
+    };
+    let addresses = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => {
+            let port = parsed.port_or_known_default().unwrap_or(80);
+            match (host, port).to_socket_addrs().await {
+                Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+                Err(_) => return Err(format!("The host of \"{}\" can't be resolved", url)),
+            }
+        }
+    };
+
+    if addresses.is_empty() || !addresses.iter().all(is_public_address) {
+        return Err(format!(
+            "\"{}\" targets a loopback, private or link-local address",
+            url
+        ));
+    }
+
+    Ok(())
+}
+
+fn is_public_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            // 100.64.0.0/10 is shared by carrier-grade NATs
+            let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_address(&IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of the body.
+fn sign(secret: &str, body: &[u8]) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(body)?;
+
+    Ok(signer
+        .sign_to_vec()?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::is_public_address;
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:4700::1111"].iter() {
+            let address = ip.parse::<IpAddr>().unwrap();
+            assert!(is_public_address(&address), "{} should be public", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ]
+        .iter()
+        {
+            let address = ip.parse::<IpAddr>().unwrap();
+            assert!(!is_public_address(&address), "{} should be rejected", ip);
+        }
+    }
+}
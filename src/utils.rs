@@ -1,4 +1,6 @@
-use lopdf::Dictionary;
+use lopdf::{Dictionary, Document as PdfDocument, Object, ObjectId};
+
+const DEFAULT_PAGE_BOX: (f64, f64, f64, f64) = (0.0, 0.0, 595.0, 842.0);
 
 pub fn get_object_rect(field: &Dictionary) -> Result<(f64, f64, f64, f64), lopdf::Error> {
     let rect = field
@@ -18,3 +20,47 @@ pub fn get_object_rect(field: &Dictionary) -> Result<(f64, f64, f64, f64), lopdf
         Err(lopdf::Error::ObjectNotFound)
     }
 }
+
+/// Looks up a page attribute, following the `Parent` chain for inheritable ones.
+pub fn get_inherited_attribute<'a>(
+    document: &'a PdfDocument,
+    page_id: ObjectId,
+    key: &[u8],
+) -> Option<&'a Object> {
+    let mut node_id = Some(page_id);
+    while let Some(id) = node_id {
+        let dictionary = document.get_dictionary(id).ok()?;
+        if let Ok(object) = dictionary.get(key) {
+            return document.dereference(object).ok().map(|(_, object)| object);
+        }
+
+        node_id = dictionary
+            .get(b"Parent")
+            .and_then(Object::as_reference)
+            .ok();
+    }
+
+    None
+}
+
+pub fn get_page_box(document: &PdfDocument, page_id: ObjectId) -> (f64, f64, f64, f64) {
+    get_inherited_attribute(document, page_id, b"CropBox")
+        .or_else(|| get_inherited_attribute(document, page_id, b"MediaBox"))
+        .and_then(|object| object.as_array().ok())
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| value.as_f64().unwrap_or(value.as_i64().unwrap_or(0) as f64))
+                .collect::<Vec<_>>()
+        })
+        .filter(|values| values.len() == 4)
+        .map(|values| {
+            (
+                values[0].min(values[2]),
+                values[1].min(values[3]),
+                values[0].max(values[2]),
+                values[1].max(values[3]),
+            )
+        })
+        .unwrap_or(DEFAULT_PAGE_BOX)
+}